@@ -0,0 +1,364 @@
+use crate::export_conflicts::FileConflictPrompt;
+use crate::export_error::{ExportError, ExportErrorCode};
+use crate::export_jobs::ExportJob;
+use crate::image_helpers::{
+    self, BatchSettings, ExportOutcome, ExportPathClaims, ExportSettings, ExportStage,
+};
+use log::{info, warn};
+use serde::Serialize;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+use tauri::ipc::Channel;
+
+/// Memory an image is assumed to take up per pixel while it is exported. Covers a 16 bit RGBA
+/// working copy plus the resized or converted copy made from it.
+const ESTIMATED_BYTES_PER_PIXEL: u64 = 16;
+/// Used for images whose size can't be read without decoding them, like raw and heif files.
+/// Roughly a 24 megapixel image.
+const FALLBACK_MEMORY_ESTIMATE: u64 = 24_000_000 * ESTIMATED_BYTES_PER_PIXEL;
+
+/// Sent to the frontend as each image of a batch moves through the export
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "event")]
+pub enum ExportProgress {
+    /// Always the first message of an export. The id is used to cancel, pause or resume it
+    #[serde(rename_all = "camelCase")]
+    JobStarted { job_id: u32 },
+    #[serde(rename_all = "camelCase")]
+    Started {
+        image_index: usize,
+        image_path: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Decoded { image_index: usize },
+    /// Only sent when resizing is enabled
+    #[serde(rename_all = "camelCase")]
+    Resized { image_index: usize },
+    #[serde(rename_all = "camelCase")]
+    Encoded { image_index: usize },
+    #[serde(rename_all = "camelCase")]
+    Written {
+        image_index: usize,
+        export_path: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Skipped {
+        image_index: usize,
+        export_path: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Cancelled { image_index: usize },
+    #[serde(rename_all = "camelCase")]
+    Failed {
+        image_index: usize,
+        error_code: ExportErrorCode,
+        error_message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageExportStatus {
+    Ok,
+    Skipped,
+    Failed,
+    Cancelled,
+}
+
+/// The result of exporting one image of a batch
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageExportResult {
+    pub input_path: String,
+    /// Where the image was written, or the existing file it was skipped for
+    pub output_path: Option<String>,
+    pub status: ImageExportStatus,
+    /// The image was exported at its own size, which is smaller than the requested size,
+    /// because enlarging is turned off
+    pub left_smaller_than_target: bool,
+    pub error_code: Option<ExportErrorCode>,
+    pub message: Option<String>,
+    /// The full error of a failed image, including the errors that caused it
+    pub error: Option<ExportError>,
+    pub duration_ms: u64,
+    pub input_bytes: Option<u64>,
+    pub output_bytes: Option<u64>,
+}
+impl ImageExportResult {
+    fn new(input_path: &str, status: ImageExportStatus) -> Self {
+        ImageExportResult {
+            input_path: input_path.to_string(),
+            output_path: None,
+            status,
+            left_smaller_than_target: false,
+            error_code: None,
+            message: None,
+            error: None,
+            duration_ms: 0,
+            input_bytes: None,
+            output_bytes: None,
+        }
+    }
+}
+
+/// Limits the memory taken up by the images being exported at the same time
+struct MemoryBudget {
+    total: u64,
+    available: Mutex<u64>,
+    released: Condvar,
+}
+impl MemoryBudget {
+    fn new(budget_mb: u32) -> Self {
+        let total = if budget_mb == 0 {
+            u64::MAX
+        } else {
+            u64::from(budget_mb) * 1024 * 1024
+        };
+        MemoryBudget {
+            total,
+            available: Mutex::new(total),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `bytes` fit in the budget. Requests larger than the whole budget are capped
+    /// to it, so a huge image still gets exported, just on its own.
+    fn reserve(&self, bytes: u64) -> MemoryReservation<'_> {
+        let bytes = bytes.min(self.total);
+        let mut available = self.available.lock().unwrap();
+        while *available < bytes {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= bytes;
+        MemoryReservation {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+/// Gives its memory back to the budget when dropped
+struct MemoryReservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+impl Drop for MemoryReservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+fn estimated_memory_use(image_path: &Path) -> u64 {
+    match image::image_dimensions(image_path) {
+        Ok((width, height)) => u64::from(width) * u64::from(height) * ESTIMATED_BYTES_PER_PIXEL,
+        Err(_) => FALLBACK_MEMORY_ESTIMATE,
+    }
+}
+
+fn send_progress(on_progress: &Channel<ExportProgress>, progress: ExportProgress) {
+    if let Err(e) = on_progress.send(progress) {
+        warn!("Error sending export progress {e:?}");
+    }
+}
+
+/// Everything the workers of one export job share
+struct ExportBatch<'a> {
+    export_settings: &'a ExportSettings,
+    file_conflict_prompt: &'a FileConflictPrompt,
+    export_path_claims: ExportPathClaims,
+    job: &'a ExportJob,
+    on_progress: &'a Channel<ExportProgress>,
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    std::fs::metadata(path).map(|metadata| metadata.len()).ok()
+}
+
+fn export_one(batch: &ExportBatch, image_index: usize, image_path: &str) -> ImageExportResult {
+    let on_progress = batch.on_progress;
+    send_progress(
+        on_progress,
+        ExportProgress::Started {
+            image_index,
+            image_path: image_path.to_string(),
+        },
+    );
+
+    let started_at = Instant::now();
+    // Decoders and encoders can panic on files they don't expect. That should only fail this
+    // image, not take down the whole batch along with the results of the other images.
+    let export_result = panic::catch_unwind(AssertUnwindSafe(|| {
+        image_helpers::export_image(
+            image_path,
+            image_index,
+            batch.export_settings,
+            &|export_path: &Path| {
+                batch
+                    .file_conflict_prompt
+                    .ask(Path::new(image_path), export_path)
+            },
+            &batch.export_path_claims,
+            &|stage| {
+                let progress = match stage {
+                    ExportStage::Decoded => ExportProgress::Decoded { image_index },
+                    ExportStage::Resized => ExportProgress::Resized { image_index },
+                    ExportStage::Encoded => ExportProgress::Encoded { image_index },
+                };
+                send_progress(on_progress, progress);
+                batch.job.checkpoint()
+            },
+        )
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Exporting panicked".to_string());
+        Err(ExportError::Panicked(message))
+    });
+
+    let written = |export_path: PathBuf, left_smaller_than_target: bool| {
+        let mut result = ImageExportResult::new(image_path, ImageExportStatus::Ok);
+        result.output_bytes = file_size(&export_path);
+        if left_smaller_than_target {
+            result.left_smaller_than_target = true;
+            result.message =
+                Some("Left smaller than the requested size since enlarging is off".to_string());
+        }
+        let export_path = export_path.to_string_lossy().to_string();
+        result.output_path = Some(export_path.clone());
+        let progress = ExportProgress::Written {
+            image_index,
+            export_path,
+        };
+        (result, progress)
+    };
+    let (mut result, progress) = match export_result {
+        Ok(ExportOutcome::Exported(export_path)) => written(export_path, false),
+        Ok(ExportOutcome::ExportedSmallerThanTarget(export_path)) => written(export_path, true),
+        Ok(ExportOutcome::Skipped(export_path)) => {
+            let mut result = ImageExportResult::new(image_path, ImageExportStatus::Skipped);
+            let export_path = export_path.to_string_lossy().to_string();
+            result.message = Some(format!("Skipped since {export_path} already exists"));
+            result.output_path = Some(export_path.clone());
+            let progress = ExportProgress::Skipped {
+                image_index,
+                export_path,
+            };
+            (result, progress)
+        }
+        Ok(ExportOutcome::Cancelled) => (
+            ImageExportResult::new(image_path, ImageExportStatus::Cancelled),
+            ExportProgress::Cancelled { image_index },
+        ),
+        Err(e) => {
+            warn!("Error exporting {image_path} {e:?}");
+            let mut result = ImageExportResult::new(image_path, ImageExportStatus::Failed);
+            let error_code = e.code();
+            let error_message = e.to_string();
+            result.error_code = Some(error_code);
+            result.message = Some(error_message.clone());
+            result.error = Some(e);
+            let progress = ExportProgress::Failed {
+                image_index,
+                error_code,
+                error_message,
+            };
+            (result, progress)
+        }
+    };
+    result.duration_ms = started_at.elapsed().as_millis() as u64;
+    result.input_bytes = file_size(Path::new(image_path));
+    send_progress(on_progress, progress);
+    result
+}
+
+/// Export all images with as many workers as the batch settings allow. Returns the result of
+/// each image in the order of `image_paths`. Images which haven't started when the job is
+/// cancelled come back as `ImageExportStatus::Cancelled`.
+pub fn export_images(
+    image_paths: &[String],
+    export_settings: &ExportSettings,
+    file_conflict_prompt: &FileConflictPrompt,
+    job: &ExportJob,
+    on_progress: &Channel<ExportProgress>,
+) -> Vec<ImageExportResult> {
+    let BatchSettings {
+        max_parallel_exports,
+        memory_budget_mb,
+    } = export_settings.batch;
+    let worker_count = if max_parallel_exports == 0 {
+        thread::available_parallelism().map_or(1, |count| count.get())
+    } else {
+        max_parallel_exports as usize
+    };
+    let worker_count = worker_count.min(image_paths.len()).max(1);
+    info!(
+        "Exporting {} images on {worker_count} workers",
+        image_paths.len()
+    );
+
+    // The workers are plain threads rather than a rayon pool. Encoders like ravif use rayon
+    // internally, and a rayon worker blocked on the memory budget could otherwise steal another
+    // image's export and wait on memory held further down its own stack.
+    let memory_budget = MemoryBudget::new(memory_budget_mb);
+    let batch = ExportBatch {
+        export_settings,
+        file_conflict_prompt,
+        export_path_claims: ExportPathClaims::default(),
+        job,
+        on_progress,
+    };
+    let next_index = AtomicUsize::new(0);
+    let results = Mutex::new(
+        image_paths
+            .iter()
+            .map(|_| None)
+            .collect::<Vec<Option<ImageExportResult>>>(),
+    );
+    thread::scope(|scope| {
+        for _ in 0..worker_count {
+            scope.spawn(|| loop {
+                let image_index = next_index.fetch_add(1, Ordering::Relaxed);
+                let Some(image_path) = image_paths.get(image_index) else {
+                    break;
+                };
+                // Waiting on the budget can take a while, so check for cancellation on both sides
+                let export_result = if job.checkpoint().is_break() {
+                    None
+                } else {
+                    let _reservation =
+                        memory_budget.reserve(estimated_memory_use(Path::new(image_path)));
+                    job.checkpoint()
+                        .is_continue()
+                        .then(|| export_one(&batch, image_index, image_path))
+                };
+                let export_result = export_result.unwrap_or_else(|| {
+                    send_progress(on_progress, ExportProgress::Cancelled { image_index });
+                    ImageExportResult::new(image_path, ImageExportStatus::Cancelled)
+                });
+                results.lock().unwrap()[image_index] = Some(export_result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .zip(image_paths)
+        .map(|(result, image_path)| {
+            result.unwrap_or_else(|| {
+                let mut result = ImageExportResult::new(image_path, ImageExportStatus::Failed);
+                result.message = Some("Image was not exported".to_string());
+                result
+            })
+        })
+        .collect()
+}
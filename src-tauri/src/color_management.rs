@@ -0,0 +1,255 @@
+use crate::image_helpers::ColorSpace;
+use image::{DynamicImage, ImageReader};
+use lcms2::{
+    CIExyY, CIExyYTRIPLE, ColorSpaceSignature, InfoType, Intent, Locale, PixelFormat, Profile, Tag,
+    TagSignature, ToneCurve, Transform, MLU,
+};
+use libheif_rs::HeifContext;
+use log::warn;
+use std::path::Path;
+
+const D65: CIExyY = CIExyY {
+    x: 0.3127,
+    y: 0.3290,
+    Y: 1.0,
+};
+const D50: CIExyY = CIExyY {
+    x: 0.3457,
+    y: 0.3585,
+    Y: 1.0,
+};
+
+fn primaries((rx, ry): (f64, f64), (gx, gy): (f64, f64), (bx, by): (f64, f64)) -> CIExyYTRIPLE {
+    CIExyYTRIPLE {
+        Red: CIExyY {
+            x: rx,
+            y: ry,
+            Y: 1.0,
+        },
+        Green: CIExyY {
+            x: gx,
+            y: gy,
+            Y: 1.0,
+        },
+        Blue: CIExyY {
+            x: bx,
+            y: by,
+            Y: 1.0,
+        },
+    }
+}
+
+/// The sRGB transfer function, which Display P3 uses as well
+fn srgb_tone_curve() -> Result<ToneCurve, String> {
+    ToneCurve::new_parametric(4, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045])
+        .map_err(|e| format!("Error creating srgb tone curve {e}"))
+}
+
+fn rgb_profile(
+    description: &str,
+    white_point: &CIExyY,
+    primaries: &CIExyYTRIPLE,
+    tone_curve: &ToneCurve,
+) -> Result<Profile, String> {
+    let mut profile = Profile::new_rgb(
+        white_point,
+        primaries,
+        &[tone_curve, tone_curve, tone_curve],
+    )
+    .map_err(|e| format!("Error creating {description} color profile {e}"))?;
+    let mut profile_description = MLU::new(1);
+    profile_description.set_text(description, Locale::none());
+    profile.write_tag(
+        TagSignature::ProfileDescriptionTag,
+        Tag::MLU(&profile_description),
+    );
+    Ok(profile)
+}
+
+/// Build the icc profile for one of the output color spaces
+pub fn color_space_profile(color_space: ColorSpace) -> Result<Profile, String> {
+    match color_space {
+        ColorSpace::Srgb => Ok(Profile::new_srgb()),
+        ColorSpace::DisplayP3 => rgb_profile(
+            "Display P3",
+            &D65,
+            &primaries((0.680, 0.320), (0.265, 0.690), (0.150, 0.060)),
+            &srgb_tone_curve()?,
+        ),
+        ColorSpace::AdobeRgb => rgb_profile(
+            "Adobe RGB (1998)",
+            &D65,
+            &primaries((0.640, 0.330), (0.210, 0.710), (0.150, 0.060)),
+            &ToneCurve::new(563.0 / 256.0),
+        ),
+        ColorSpace::ProphotoRgb => {
+            // ROMM RGB gamma 1.8 with a linear segment near black
+            let tone_curve = ToneCurve::new_parametric(4, &[1.8, 1.0, 0.0, 1.0 / 16.0, 1.0 / 32.0])
+                .map_err(|e| format!("Error creating prophoto tone curve {e}"))?;
+            rgb_profile(
+                "ProPhoto RGB",
+                &D50,
+                &primaries((0.7347, 0.2653), (0.1596, 0.8404), (0.0366, 0.0001)),
+                &tone_curve,
+            )
+        }
+        ColorSpace::Rec2020 => {
+            let tone_curve = ToneCurve::new_parametric(
+                4,
+                &[1.0 / 0.45, 1.0 / 1.099, 0.099 / 1.099, 1.0 / 4.5, 0.081],
+            )
+            .map_err(|e| format!("Error creating rec2020 tone curve {e}"))?;
+            rgb_profile(
+                "Rec. 2020",
+                &D65,
+                &primaries((0.708, 0.292), (0.170, 0.797), (0.131, 0.046)),
+                &tone_curve,
+            )
+        }
+    }
+}
+
+/// Serialized icc profile of an output color space, for embedding in exported files
+pub fn color_space_icc_profile(color_space: ColorSpace) -> Result<Vec<u8>, String> {
+    color_space_profile(color_space)?
+        .icc()
+        .map_err(|e| format!("Error serializing {color_space:?} color profile {e}"))
+}
+
+pub fn read_heif_icc_profile(path: &Path) -> Option<Vec<u8>> {
+    let context = HeifContext::read_from_file(path.to_str()?).ok()?;
+    let handle = context.primary_image_handle().ok()?;
+    handle.color_profile_raw().map(|profile| profile.data)
+}
+
+/// Read the icc profile embedded in a file the image crate reads, like jpeg, png, tiff or webp.
+/// The format is told from the content. Returns `None` if there is no profile, in which case the
+/// pixels are assumed to be sRGB. HEIF profiles are read by `read_heif_icc_profile`.
+pub fn read_icc_profile(path: &Path) -> Option<Vec<u8>> {
+    let decoder = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .ok()?
+        .into_decoder();
+    match decoder {
+        Ok(mut decoder) => image::ImageDecoder::icc_profile(&mut decoder).unwrap_or_else(|e| {
+            warn!("Error reading icc profile from {path:?} {e}");
+            None
+        }),
+        Err(_) => None,
+    }
+}
+
+/// Whether pixels tagged with `icc_profile` are already in `color_space`, so they can be written
+/// without converting them. Untagged pixels are sRGB. Profiles are told apart by their
+/// description, since different apps write slightly different profiles for the same color
+/// space. sRGB profiles go by many names, but they all start with "sRGB".
+pub fn is_in_color_space(icc_profile: Option<&[u8]>, color_space: ColorSpace) -> bool {
+    let Some(icc_profile) = icc_profile else {
+        return color_space == ColorSpace::Srgb;
+    };
+    let description = |profile: &Profile| profile.info(InfoType::Description, Locale::none());
+    let Some(source_description) = Profile::new_icc(icc_profile)
+        .ok()
+        .and_then(|profile| description(&profile))
+    else {
+        return false;
+    };
+    if color_space == ColorSpace::Srgb {
+        return source_description.starts_with("sRGB");
+    }
+    color_space_profile(color_space)
+        .ok()
+        .and_then(|profile| description(&profile))
+        .is_some_and(|target_description| target_description == source_description)
+}
+
+fn source_profile(source_icc_profile: Option<&[u8]>) -> Profile {
+    let Some(icc_profile) = source_icc_profile else {
+        return Profile::new_srgb();
+    };
+    match Profile::new_icc(icc_profile) {
+        // We only convert rgb pixels, so gray or cmyk profiles can't be used as the source
+        Ok(profile) if profile.color_space() == ColorSpaceSignature::RgbData => profile,
+        Ok(profile) => {
+            warn!(
+                "Ignoring {:?} source color profile, assuming sRGB",
+                profile.color_space()
+            );
+            Profile::new_srgb()
+        }
+        Err(e) => {
+            warn!("Error reading source color profile, assuming sRGB {e}");
+            Profile::new_srgb()
+        }
+    }
+}
+
+fn transform_pixels(
+    pixels: &mut [u8],
+    pixel_format: PixelFormat,
+    source: &Profile,
+    target: &Profile,
+) -> Result<(), String> {
+    let transform: Transform<u8, u8> = Transform::new(
+        source,
+        pixel_format,
+        target,
+        pixel_format,
+        Intent::Perceptual,
+    )
+    .map_err(|e| format!("Error creating color transform {e}"))?;
+    // The alpha channel is left untouched when transforming in place
+    transform.transform_in_place(pixels);
+    Ok(())
+}
+
+/// Convert the pixels of an image from the source icc profile (sRGB if there is none) to one of
+/// the output color spaces. 16 bit and float images are converted with 16 bit precision.
+pub fn convert_to_color_space(
+    image: DynamicImage,
+    source_icc_profile: Option<&[u8]>,
+    color_space: ColorSpace,
+) -> Result<DynamicImage, String> {
+    if source_icc_profile.is_none() && color_space == ColorSpace::Srgb {
+        return Ok(image);
+    }
+
+    let source = source_profile(source_icc_profile);
+    let target = color_space_profile(color_space)?;
+    let has_alpha = image.color().has_alpha();
+    let is_high_bit_depth = image.color().bytes_per_pixel() / image.color().channel_count() > 1;
+
+    let converted_image = match (is_high_bit_depth, has_alpha) {
+        (false, false) => {
+            let mut buffer = image.into_rgb8();
+            transform_pixels(&mut buffer, PixelFormat::RGB_8, &source, &target)?;
+            DynamicImage::ImageRgb8(buffer)
+        }
+        (false, true) => {
+            let mut buffer = image.into_rgba8();
+            transform_pixels(&mut buffer, PixelFormat::RGBA_8, &source, &target)?;
+            DynamicImage::ImageRgba8(buffer)
+        }
+        (true, false) => {
+            let mut buffer = image.into_rgb16();
+            transform_pixels(
+                bytemuck::cast_slice_mut(&mut *buffer),
+                PixelFormat::RGB_16,
+                &source,
+                &target,
+            )?;
+            DynamicImage::ImageRgb16(buffer)
+        }
+        (true, true) => {
+            let mut buffer = image.into_rgba16();
+            transform_pixels(
+                bytemuck::cast_slice_mut(&mut *buffer),
+                PixelFormat::RGBA_16,
+                &source,
+                &target,
+            )?;
+            DynamicImage::ImageRgba16(buffer)
+        }
+    };
+    Ok(converted_image)
+}
@@ -0,0 +1,1056 @@
+use log::warn;
+
+/// Metadata blocks written into exported files
+#[derive(Debug, Default)]
+pub struct EmbeddedMetadata {
+    pub icc_profile: Option<Vec<u8>>,
+    /// Exif as a TIFF structure, without the `Exif\0\0` prefix used in jpeg files
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    /// IPTC-IIM datasets
+    pub iptc: Option<Vec<u8>>,
+}
+impl EmbeddedMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.icc_profile.is_none()
+            && self.exif.is_none()
+            && self.xmp.is_none()
+            && self.iptc.is_none()
+    }
+}
+
+const VP8X_ICC_FLAG: u8 = 0x20;
+const VP8X_ALPHA_FLAG: u8 = 0x10;
+const VP8X_EXIF_FLAG: u8 = 0x08;
+const VP8X_XMP_FLAG: u8 = 0x04;
+
+pub const JPEG_EXIF_PREFIX: &[u8] = b"Exif\0\0";
+pub const JPEG_XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+pub const JPEG_PHOTOSHOP_PREFIX: &[u8] = b"Photoshop 3.0\0";
+/// Extended xmp, for packets too big for a single segment
+const JPEG_XMP_EXTENSION_PREFIX: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+const JPEG_APP0: u8 = 0xE0;
+pub const JPEG_APP1: u8 = 0xE1;
+pub const JPEG_APP13: u8 = 0xED;
+/// The segment length is stored in 16 bits and includes the two length bytes
+const JPEG_MAX_SEGMENT_PAYLOAD: usize = 0xFFFF - 2;
+/// Photoshop image resource holding IPTC-IIM data
+const PHOTOSHOP_IPTC_RESOURCE_ID: u16 = 0x0404;
+
+pub const TIFF_XMP_TAG: u16 = 700;
+pub const TIFF_IPTC_TAG: u16 = 33723;
+const TIFF_EXIF_IFD_TAG: u16 = 34665;
+const TIFF_GPS_IFD_TAG: u16 = 34853;
+const TIFF_INTEROP_IFD_TAG: u16 = 40965;
+
+fn read_u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Split the chunks of a RIFF WEBP file into (fourcc, payload) pairs
+fn webp_chunks(webp: &[u8]) -> Result<Vec<([u8; 4], &[u8])>, String> {
+    if webp.len() < 12 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return Err("Not a webp file".to_string());
+    }
+
+    let mut chunks = vec![];
+    let mut offset = 12;
+    while offset + 8 <= webp.len() {
+        let fourcc: [u8; 4] = webp[offset..offset + 4].try_into().unwrap();
+        let size = read_u32_le(webp, offset + 4).unwrap() as usize;
+        let payload = webp
+            .get(offset + 8..offset + 8 + size)
+            .ok_or_else(|| format!("Truncated {} chunk in webp file", fourcc.escape_ascii()))?;
+        chunks.push((fourcc, payload));
+        // Chunks are padded to an even size
+        offset += 8 + size + (size & 1);
+    }
+    Ok(chunks)
+}
+
+fn push_webp_chunk(buffer: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    buffer.extend_from_slice(fourcc);
+    buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(payload);
+    if payload.len() & 1 == 1 {
+        buffer.push(0);
+    }
+}
+
+/// Rewrite a webp file using the extended format, which is needed to store metadata chunks.
+/// `width`, `height` and `has_alpha` describe the encoded image.
+pub fn embed_in_webp(
+    webp: &[u8],
+    (width, height): (u32, u32),
+    has_alpha: bool,
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, String> {
+    if metadata.is_empty() {
+        return Ok(webp.to_vec());
+    }
+
+    let chunks = webp_chunks(webp)?;
+    let mut vp8x = match chunks.iter().find(|(fourcc, _)| fourcc == b"VP8X") {
+        Some((_, payload)) if payload.len() >= 10 => payload[0..10].to_vec(),
+        _ => {
+            let mut vp8x = vec![0; 10];
+            if has_alpha {
+                vp8x[0] |= VP8X_ALPHA_FLAG;
+            }
+            // Canvas width and height minus one, as 24 bit little endian numbers
+            vp8x[4..7].copy_from_slice(&(width - 1).to_le_bytes()[0..3]);
+            vp8x[7..10].copy_from_slice(&(height - 1).to_le_bytes()[0..3]);
+            vp8x
+        }
+    };
+    if metadata.icc_profile.is_some() {
+        vp8x[0] |= VP8X_ICC_FLAG;
+    }
+    if metadata.exif.is_some() {
+        vp8x[0] |= VP8X_EXIF_FLAG;
+    }
+    if metadata.xmp.is_some() {
+        vp8x[0] |= VP8X_XMP_FLAG;
+    }
+
+    // The order of the chunks is fixed, VP8X first, then ICCP, then the image data, then EXIF
+    // and XMP. webp has no place for IPTC.
+    let mut body = Vec::with_capacity(webp.len());
+    body.extend_from_slice(b"WEBP");
+    push_webp_chunk(&mut body, b"VP8X", &vp8x);
+    if let Some(icc_profile) = &metadata.icc_profile {
+        push_webp_chunk(&mut body, b"ICCP", icc_profile);
+    }
+    for (fourcc, payload) in chunks.iter() {
+        if !matches!(fourcc, b"VP8X" | b"ICCP" | b"EXIF" | b"XMP ") {
+            push_webp_chunk(&mut body, fourcc, payload);
+        }
+    }
+    if let Some(exif) = &metadata.exif {
+        push_webp_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        push_webp_chunk(&mut body, b"XMP ", xmp);
+    }
+
+    let mut buffer = Vec::with_capacity(body.len() + 8);
+    buffer.extend_from_slice(b"RIFF");
+    buffer.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&body);
+    Ok(buffer)
+}
+
+/// Split the segments of a jpeg file before the image data into (marker, payload) pairs
+pub fn jpeg_segments(jpeg: &[u8]) -> Result<Vec<(u8, &[u8])>, String> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err("Not a jpeg file".to_string());
+    }
+
+    let mut segments = vec![];
+    let mut offset = 2;
+    while offset + 4 <= jpeg.len() {
+        if jpeg[offset] != 0xFF {
+            return Err(format!("Invalid jpeg marker at {offset}"));
+        }
+        let marker = jpeg[offset + 1];
+        match marker {
+            // Fill bytes before a marker
+            0xFF => {
+                offset += 1;
+                continue;
+            }
+            // Start of scan or end of image, there are no more metadata segments after this
+            0xDA | 0xD9 => break,
+            _ => {}
+        }
+        let length = u16::from_be_bytes([jpeg[offset + 2], jpeg[offset + 3]]) as usize;
+        let payload = jpeg
+            .get(offset + 4..offset + 2 + length)
+            .ok_or("Truncated jpeg segment")?;
+        segments.push((marker, payload));
+        offset += 2 + length;
+    }
+    Ok(segments)
+}
+
+fn push_jpeg_segment(buffer: &mut Vec<u8>, marker: u8, prefix: &[u8], payload: &[u8]) {
+    if prefix.len() + payload.len() > JPEG_MAX_SEGMENT_PAYLOAD {
+        warn!(
+            "Not writing {} metadata since it doesn't fit in a jpeg segment",
+            String::from_utf8_lossy(prefix).trim_end_matches('\0')
+        );
+        return;
+    }
+    buffer.extend_from_slice(&[0xFF, marker]);
+    buffer.extend_from_slice(&((prefix.len() + payload.len() + 2) as u16).to_be_bytes());
+    buffer.extend_from_slice(prefix);
+    buffer.extend_from_slice(payload);
+}
+
+/// Find the IPTC-IIM data in the Photoshop image resources of a jpeg APP13 segment
+pub fn iptc_from_photoshop_resources(resources: &[u8]) -> Option<Vec<u8>> {
+    let mut offset = 0;
+    while offset + 12 <= resources.len() && &resources[offset..offset + 4] == b"8BIM" {
+        let resource_id = u16::from_be_bytes([resources[offset + 4], resources[offset + 5]]);
+        // Pascal string name, padded to an even length including the length byte
+        let name_length = resources[offset + 6] as usize;
+        let data_start = offset + 6 + ((name_length + 2) & !1);
+        let size = read_u32_be(resources, data_start)? as usize;
+        let data = resources.get(data_start + 4..data_start + 4 + size)?;
+        if resource_id == PHOTOSHOP_IPTC_RESOURCE_ID {
+            return Some(data.to_vec());
+        }
+        offset = data_start + 4 + size + (size & 1);
+    }
+    None
+}
+
+fn photoshop_iptc_resource(iptc: &[u8]) -> Vec<u8> {
+    let mut resource = b"8BIM".to_vec();
+    resource.extend_from_slice(&PHOTOSHOP_IPTC_RESOURCE_ID.to_be_bytes());
+    // Empty name
+    resource.extend_from_slice(&[0, 0]);
+    resource.extend_from_slice(&(iptc.len() as u32).to_be_bytes());
+    resource.extend_from_slice(iptc);
+    if iptc.len() & 1 == 1 {
+        resource.push(0);
+    }
+    resource
+}
+
+/// Add exif, xmp and iptc segments to a jpeg file, right after the JFIF header
+pub fn embed_in_jpeg(jpeg: &[u8], metadata: &EmbeddedMetadata) -> Result<Vec<u8>, String> {
+    if metadata.exif.is_none() && metadata.xmp.is_none() && metadata.iptc.is_none() {
+        return Ok(jpeg.to_vec());
+    }
+
+    let segments = jpeg_segments(jpeg)?;
+    let mut insert_at = 2;
+    if let Some((JPEG_APP0, payload)) = segments.first() {
+        insert_at += 4 + payload.len();
+    }
+
+    let mut buffer = Vec::with_capacity(jpeg.len() + 1024);
+    buffer.extend_from_slice(&jpeg[..insert_at]);
+    if let Some(exif) = &metadata.exif {
+        push_jpeg_segment(&mut buffer, JPEG_APP1, JPEG_EXIF_PREFIX, exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        push_jpeg_segment(&mut buffer, JPEG_APP1, JPEG_XMP_PREFIX, xmp);
+    }
+    if let Some(iptc) = &metadata.iptc {
+        push_jpeg_segment(
+            &mut buffer,
+            JPEG_APP13,
+            JPEG_PHOTOSHOP_PREFIX,
+            &photoshop_iptc_resource(iptc),
+        );
+    }
+    buffer.extend_from_slice(&jpeg[insert_at..]);
+    Ok(buffer)
+}
+
+/// Remove the exif, xmp and iptc segments of a jpeg file. Everything else, like the icc profile
+/// and the image data, is kept as it is.
+pub fn strip_jpeg_metadata(jpeg: &[u8]) -> Result<Vec<u8>, String> {
+    let segments = jpeg_segments(jpeg)?;
+    // Payloads borrow from `jpeg`, so the image data starts right after the last one
+    let image_data_start = segments.last().map_or(2, |(_, payload)| {
+        payload.as_ptr() as usize - jpeg.as_ptr() as usize + payload.len()
+    });
+
+    let mut buffer = Vec::with_capacity(jpeg.len());
+    buffer.extend_from_slice(&jpeg[..2]);
+    for &(marker, payload) in &segments {
+        let is_metadata = match marker {
+            JPEG_APP1 => {
+                payload.starts_with(JPEG_EXIF_PREFIX)
+                    || payload.starts_with(JPEG_XMP_PREFIX)
+                    || payload.starts_with(JPEG_XMP_EXTENSION_PREFIX)
+            }
+            JPEG_APP13 => payload.starts_with(JPEG_PHOTOSHOP_PREFIX),
+            _ => false,
+        };
+        if !is_metadata {
+            push_jpeg_segment(&mut buffer, marker, &[], payload);
+        }
+    }
+    buffer.extend_from_slice(&jpeg[image_data_start..]);
+    Ok(buffer)
+}
+
+fn push_png_chunk(buffer: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let crc_start = buffer.len();
+    buffer.extend_from_slice(chunk_type);
+    buffer.extend_from_slice(data);
+    let crc = crc32fast::hash(&buffer[crc_start..]);
+    buffer.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Add exif and xmp chunks to a png file, before the image data. png has no place for IPTC.
+pub fn embed_in_png(png: &[u8], metadata: &EmbeddedMetadata) -> Result<Vec<u8>, String> {
+    if metadata.exif.is_none() && metadata.xmp.is_none() {
+        return Ok(png.to_vec());
+    }
+
+    // Find the first IDAT chunk, the eXIf chunk has to come before it
+    let mut offset = 8;
+    loop {
+        let length = read_u32_be(png, offset).ok_or("Png file has no image data")? as usize;
+        let chunk_type = png
+            .get(offset + 4..offset + 8)
+            .ok_or("Truncated png chunk")?;
+        if chunk_type == b"IDAT" {
+            break;
+        }
+        offset += 12 + length;
+    }
+
+    let mut buffer = Vec::with_capacity(png.len() + 1024);
+    buffer.extend_from_slice(&png[..offset]);
+    if let Some(exif) = &metadata.exif {
+        push_png_chunk(&mut buffer, b"eXIf", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        // Keyword, then uncompressed with empty language and translated keyword
+        let mut itxt = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+        itxt.extend_from_slice(xmp);
+        push_png_chunk(&mut buffer, b"iTXt", &itxt);
+    }
+    buffer.extend_from_slice(&png[offset..]);
+    Ok(buffer)
+}
+
+/// A TIFF directory entry with its value bytes, in the byte order of the file it came from
+#[derive(Debug, Clone)]
+struct IfdEntry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+fn tiff_field_type_size(field_type: u16) -> usize {
+    match field_type {
+        // SHORT, SSHORT
+        3 | 8 => 2,
+        // LONG, SLONG, FLOAT, IFD
+        4 | 9 | 11 | 13 => 4,
+        // RATIONAL, SRATIONAL, DOUBLE
+        5 | 10 | 12 => 8,
+        // BYTE, ASCII, SBYTE, UNDEFINED
+        _ => 1,
+    }
+}
+
+fn read_tiff_u16(data: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if little_endian {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn read_tiff_u32(data: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+fn tiff_u32_bytes(value: u32, little_endian: bool) -> [u8; 4] {
+    if little_endian {
+        value.to_le_bytes()
+    } else {
+        value.to_be_bytes()
+    }
+}
+
+/// Returns the byte order and the offset of the first directory of a TIFF structure
+fn tiff_header(tiff: &[u8]) -> Result<(bool, usize), String> {
+    let little_endian = match tiff.get(0..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return Err("Not a tiff file".to_string()),
+    };
+    let ifd_offset = read_tiff_u32(tiff, 4, little_endian).ok_or("Truncated tiff header")?;
+    Ok((little_endian, ifd_offset as usize))
+}
+
+fn read_ifd(tiff: &[u8], offset: usize, little_endian: bool) -> Result<Vec<IfdEntry>, String> {
+    let truncated = || "Truncated tiff directory".to_string();
+    let entry_count = read_tiff_u16(tiff, offset, little_endian).ok_or_else(truncated)?;
+
+    let mut entries = vec![];
+    for i in 0..entry_count as usize {
+        let entry_offset = offset + 2 + i * 12;
+        let tag = read_tiff_u16(tiff, entry_offset, little_endian).ok_or_else(truncated)?;
+        let field_type =
+            read_tiff_u16(tiff, entry_offset + 2, little_endian).ok_or_else(truncated)?;
+        let count = read_tiff_u32(tiff, entry_offset + 4, little_endian).ok_or_else(truncated)?;
+        let size = tiff_field_type_size(field_type) * count as usize;
+        // Values of up to 4 bytes are stored in the entry itself
+        let data_offset = if size <= 4 {
+            entry_offset + 8
+        } else {
+            read_tiff_u32(tiff, entry_offset + 8, little_endian).ok_or_else(truncated)? as usize
+        };
+        let data = tiff
+            .get(data_offset..data_offset + size)
+            .ok_or_else(truncated)?
+            .to_vec();
+        entries.push(IfdEntry {
+            tag,
+            field_type,
+            count,
+            data,
+        });
+    }
+    Ok(entries)
+}
+
+/// Append a directory to the end of a TIFF file and return its offset
+fn append_ifd(
+    buffer: &mut Vec<u8>,
+    entries: &[IfdEntry],
+    little_endian: bool,
+) -> Result<u32, String> {
+    // Directories have to start on a word boundary
+    if buffer.len() & 1 == 1 {
+        buffer.push(0);
+    }
+    let ifd_offset = buffer.len();
+    let mut value_offset = ifd_offset + 2 + entries.len() * 12 + 4;
+    let too_large = || "Tiff file is too large to add metadata".to_string();
+
+    let mut values = vec![];
+    let entry_count = entries.len() as u16;
+    buffer.extend_from_slice(&if little_endian {
+        entry_count.to_le_bytes()
+    } else {
+        entry_count.to_be_bytes()
+    });
+    for entry in entries.iter() {
+        let (tag, field_type) = if little_endian {
+            (entry.tag.to_le_bytes(), entry.field_type.to_le_bytes())
+        } else {
+            (entry.tag.to_be_bytes(), entry.field_type.to_be_bytes())
+        };
+        buffer.extend_from_slice(&tag);
+        buffer.extend_from_slice(&field_type);
+        buffer.extend_from_slice(&tiff_u32_bytes(entry.count, little_endian));
+        if entry.data.len() <= 4 {
+            let mut inline_value = [0; 4];
+            inline_value[..entry.data.len()].copy_from_slice(&entry.data);
+            buffer.extend_from_slice(&inline_value);
+        } else {
+            let offset = u32::try_from(value_offset).map_err(|_| too_large())?;
+            buffer.extend_from_slice(&tiff_u32_bytes(offset, little_endian));
+            values.extend_from_slice(&entry.data);
+            if entry.data.len() & 1 == 1 {
+                values.push(0);
+            }
+            value_offset = ifd_offset + 2 + entries.len() * 12 + 4 + values.len();
+        }
+    }
+    // Offset of the next directory, there is none
+    buffer.extend_from_slice(&[0; 4]);
+    buffer.extend_from_slice(&values);
+
+    u32::try_from(ifd_offset).map_err(|_| too_large())
+}
+
+fn set_ifd_entry(entries: &mut Vec<IfdEntry>, entry: IfdEntry) {
+    entries.retain(|existing| existing.tag != entry.tag);
+    entries.push(entry);
+}
+
+fn ifd_pointer_entry(tag: u16, offset: u32, little_endian: bool) -> IfdEntry {
+    IfdEntry {
+        tag,
+        // LONG
+        field_type: 4,
+        count: 1,
+        data: tiff_u32_bytes(offset, little_endian).to_vec(),
+    }
+}
+
+/// Copy the directories of an exif TIFF structure into `buffer`, which is a TIFF file with the
+/// same byte order, and add their entries to `ifd0_entries`
+fn append_exif_ifds(
+    buffer: &mut Vec<u8>,
+    ifd0_entries: &mut Vec<IfdEntry>,
+    exif: &[u8],
+    little_endian: bool,
+) -> Result<(), String> {
+    let (exif_little_endian, exif_ifd0_offset) = tiff_header(exif)?;
+    if exif_little_endian != little_endian {
+        return Err("Exif byte order doesn't match the tiff file".to_string());
+    }
+
+    let sub_ifd_offset = |entry: &IfdEntry| -> Result<usize, String> {
+        read_tiff_u32(&entry.data, 0, little_endian)
+            .map(|offset| offset as usize)
+            .ok_or_else(|| "Invalid exif directory pointer".to_string())
+    };
+    for entry in read_ifd(exif, exif_ifd0_offset, little_endian)? {
+        match entry.tag {
+            TIFF_EXIF_IFD_TAG | TIFF_GPS_IFD_TAG => {
+                let mut sub_entries = read_ifd(exif, sub_ifd_offset(&entry)?, little_endian)?;
+                if let Some(interop_entry) = sub_entries
+                    .iter()
+                    .find(|sub_entry| sub_entry.tag == TIFF_INTEROP_IFD_TAG)
+                    .cloned()
+                {
+                    let interop_entries =
+                        read_ifd(exif, sub_ifd_offset(&interop_entry)?, little_endian)?;
+                    let interop_offset = append_ifd(buffer, &interop_entries, little_endian)?;
+                    set_ifd_entry(
+                        &mut sub_entries,
+                        ifd_pointer_entry(TIFF_INTEROP_IFD_TAG, interop_offset, little_endian),
+                    );
+                }
+                sub_entries.sort_by_key(|sub_entry| sub_entry.tag);
+                let offset = append_ifd(buffer, &sub_entries, little_endian)?;
+                set_ifd_entry(
+                    ifd0_entries,
+                    ifd_pointer_entry(entry.tag, offset, little_endian),
+                );
+            }
+            _ => set_ifd_entry(ifd0_entries, entry),
+        }
+    }
+    Ok(())
+}
+
+/// Add exif, xmp and iptc to the first directory of a tiff file. The new directory and the exif
+/// sub directories are appended to the end of the file.
+pub fn embed_in_tiff(tiff: &[u8], metadata: &EmbeddedMetadata) -> Result<Vec<u8>, String> {
+    if metadata.exif.is_none() && metadata.xmp.is_none() && metadata.iptc.is_none() {
+        return Ok(tiff.to_vec());
+    }
+
+    let (little_endian, ifd0_offset) = tiff_header(tiff)?;
+    let mut entries = read_ifd(tiff, ifd0_offset, little_endian)?;
+    let mut buffer = tiff.to_vec();
+
+    if let Some(exif) = &metadata.exif {
+        append_exif_ifds(&mut buffer, &mut entries, exif, little_endian)?;
+    }
+    if let Some(xmp) = &metadata.xmp {
+        set_ifd_entry(
+            &mut entries,
+            IfdEntry {
+                tag: TIFF_XMP_TAG,
+                // BYTE
+                field_type: 1,
+                count: xmp.len() as u32,
+                data: xmp.clone(),
+            },
+        );
+    }
+    if let Some(iptc) = &metadata.iptc {
+        set_ifd_entry(
+            &mut entries,
+            IfdEntry {
+                tag: TIFF_IPTC_TAG,
+                // UNDEFINED
+                field_type: 7,
+                count: iptc.len() as u32,
+                data: iptc.clone(),
+            },
+        );
+    }
+
+    // Entries have to be sorted by tag
+    entries.sort_by_key(|entry| entry.tag);
+    let new_ifd0_offset = append_ifd(&mut buffer, &entries, little_endian)?;
+    buffer[4..8].copy_from_slice(&tiff_u32_bytes(new_ifd0_offset, little_endian));
+    Ok(buffer)
+}
+
+/// Position of an ISOBMFF box inside its file
+#[derive(Debug, Clone, Copy)]
+struct IsoBox {
+    box_type: [u8; 4],
+    start: usize,
+    content_start: usize,
+    end: usize,
+}
+
+/// List the boxes between `start` and `end`. Only 32 bit box sizes are supported, which is all
+/// our avif encoder writes.
+fn iso_boxes(data: &[u8], start: usize, end: usize) -> Result<Vec<IsoBox>, String> {
+    let mut boxes = vec![];
+    let mut offset = start;
+    while offset + 8 <= end {
+        let size = read_u32_be(data, offset).unwrap() as usize;
+        let box_type: [u8; 4] = data[offset + 4..offset + 8].try_into().unwrap();
+        let box_end = match size {
+            0 => end,
+            1 => return Err("64 bit box sizes are not supported".to_string()),
+            size if size < 8 || offset + size > end => {
+                return Err(format!("Invalid size of {} box", box_type.escape_ascii()))
+            }
+            size => offset + size,
+        };
+        boxes.push(IsoBox {
+            box_type,
+            start: offset,
+            content_start: offset + 8,
+            end: box_end,
+        });
+        offset = box_end;
+    }
+    Ok(boxes)
+}
+
+fn find_box(boxes: &[IsoBox], box_type: &[u8; 4]) -> Result<IsoBox, String> {
+    boxes
+        .iter()
+        .find(|iso_box| &iso_box.box_type == box_type)
+        .copied()
+        .ok_or_else(|| format!("Missing {} box", box_type.escape_ascii()))
+}
+
+fn push_iso_box(buffer: &mut Vec<u8>, box_type: &[u8; 4], content: &[u8]) {
+    buffer.extend_from_slice(&((content.len() + 8) as u32).to_be_bytes());
+    buffer.extend_from_slice(box_type);
+    buffer.extend_from_slice(content);
+}
+
+/// Read a big endian number of 0, 2, 4 or 8 bytes
+fn read_be(data: &[u8], offset: usize, size: usize) -> Result<u64, String> {
+    let bytes = data
+        .get(offset..offset + size)
+        .ok_or("Truncated avif item data")?;
+    Ok(bytes
+        .iter()
+        .fold(0, |value, byte| (value << 8) | u64::from(*byte)))
+}
+
+fn write_be(data: &mut [u8], offset: usize, size: usize, value: u64) -> Result<(), String> {
+    if size < 8 && value >> (size * 8) != 0 {
+        return Err("Avif item offset does not fit after adding metadata".to_string());
+    }
+    for i in 0..size {
+        data[offset + i] = (value >> ((size - 1 - i) * 8)) as u8;
+    }
+    Ok(())
+}
+
+/// Add a property association to the primary item in the content of an `ipma` box
+fn add_property_association(
+    ipma: &[u8],
+    primary_item_id: u32,
+    property_index: usize,
+) -> Result<Vec<u8>, String> {
+    if ipma.len() < 8 {
+        return Err("Truncated ipma box".to_string());
+    }
+    let version = ipma[0];
+    let uses_large_indexes = ipma[3] & 1 == 1;
+    if !uses_large_indexes && property_index > 0x7F {
+        return Err("Too many avif item properties".to_string());
+    }
+    let entry_count = read_u32_be(ipma, 4).ok_or("Truncated ipma box")?;
+
+    let mut new_ipma = ipma[0..8].to_vec();
+    let mut offset = 8;
+    let mut found_primary_item = false;
+    for _ in 0..entry_count {
+        let item_id_size = if version < 1 { 2 } else { 4 };
+        let item_id = read_be(ipma, offset, item_id_size)? as u32;
+        let association_count = read_be(ipma, offset + item_id_size, 1)? as usize;
+        let association_size = if uses_large_indexes { 2 } else { 1 };
+        let entry_end = offset + item_id_size + 1 + association_count * association_size;
+        if entry_end > ipma.len() {
+            return Err("Truncated ipma box".to_string());
+        }
+
+        if item_id == primary_item_id {
+            found_primary_item = true;
+            if association_count == 0xFF {
+                return Err("Too many avif item properties".to_string());
+            }
+            new_ipma.extend_from_slice(&ipma[offset..offset + item_id_size]);
+            new_ipma.push(association_count as u8 + 1);
+            new_ipma.extend_from_slice(&ipma[offset + item_id_size + 1..entry_end]);
+            // Color information is not essential, so the top bit stays unset
+            if uses_large_indexes {
+                new_ipma.extend_from_slice(&(property_index as u16).to_be_bytes());
+            } else {
+                new_ipma.push(property_index as u8);
+            }
+        } else {
+            new_ipma.extend_from_slice(&ipma[offset..entry_end]);
+        }
+        offset = entry_end;
+    }
+
+    if !found_primary_item {
+        return Err("Primary avif item has no properties".to_string());
+    }
+    Ok(new_ipma)
+}
+
+/// Shift the file offsets of item data stored after the meta box by `delta` bytes
+fn shift_item_locations(
+    data: &mut [u8],
+    iloc: IsoBox,
+    meta_end: usize,
+    delta: usize,
+) -> Result<(), String> {
+    let content = iloc.content_start;
+    let version = data[content];
+    let sizes = read_be(data, content + 4, 2)? as usize;
+    let offset_size = (sizes >> 12) & 0xF;
+    let length_size = (sizes >> 8) & 0xF;
+    let base_offset_size = (sizes >> 4) & 0xF;
+    let index_size = if version >= 1 { sizes & 0xF } else { 0 };
+    let item_id_size = if version < 2 { 2 } else { 4 };
+
+    let mut offset = content + 6;
+    let item_count = read_be(data, offset, item_id_size)?;
+    offset += item_id_size;
+    for _ in 0..item_count {
+        offset += item_id_size;
+        let construction_method = if version >= 1 {
+            let value = read_be(data, offset, 2)? & 0xF;
+            offset += 2;
+            value
+        } else {
+            0
+        };
+        // Data reference index, 0 means the data is in this file
+        let data_reference_index = read_be(data, offset, 2)?;
+        offset += 2;
+        let is_file_offset = construction_method == 0 && data_reference_index == 0;
+
+        let base_offset = read_be(data, offset, base_offset_size)?;
+        if is_file_offset && base_offset >= meta_end as u64 {
+            write_be(data, offset, base_offset_size, base_offset + delta as u64)?;
+        }
+        offset += base_offset_size;
+
+        let extent_count = read_be(data, offset, 2)?;
+        offset += 2;
+        for _ in 0..extent_count {
+            offset += index_size;
+            // Extent offsets are only absolute when there is no base offset
+            let extent_offset = read_be(data, offset, offset_size)?;
+            if is_file_offset && base_offset == 0 && extent_offset >= meta_end as u64 {
+                write_be(data, offset, offset_size, extent_offset + delta as u64)?;
+            }
+            offset += offset_size + length_size;
+        }
+    }
+    Ok(())
+}
+
+/// Add an icc profile to the primary item of an avif file as a `colr` property
+pub fn embed_in_avif(avif: &[u8], metadata: &EmbeddedMetadata) -> Result<Vec<u8>, String> {
+    let Some(icc_profile) = &metadata.icc_profile else {
+        return Ok(avif.to_vec());
+    };
+
+    let top_level_boxes = iso_boxes(avif, 0, avif.len())?;
+    let meta = find_box(&top_level_boxes, b"meta")?;
+    // meta is a full box, with 4 bytes of version and flags before its children
+    let meta_children = iso_boxes(avif, meta.content_start + 4, meta.end)?;
+    let pitm = find_box(&meta_children, b"pitm")?;
+    let primary_item_id = if avif[pitm.content_start] == 0 {
+        read_be(avif, pitm.content_start + 4, 2)?
+    } else {
+        read_be(avif, pitm.content_start + 4, 4)?
+    } as u32;
+    let iprp = find_box(&meta_children, b"iprp")?;
+    let iprp_children = iso_boxes(avif, iprp.content_start, iprp.end)?;
+    let ipco = find_box(&iprp_children, b"ipco")?;
+    let property_count = iso_boxes(avif, ipco.content_start, ipco.end)?.len();
+
+    let mut colr = b"prof".to_vec();
+    colr.extend_from_slice(icc_profile);
+
+    let mut new_iprp_content = vec![];
+    for child in iprp_children.iter() {
+        match &child.box_type {
+            b"ipco" => {
+                let mut ipco_content = avif[child.content_start..child.end].to_vec();
+                push_iso_box(&mut ipco_content, b"colr", &colr);
+                push_iso_box(&mut new_iprp_content, b"ipco", &ipco_content);
+            }
+            b"ipma" => {
+                let ipma_content = add_property_association(
+                    &avif[child.content_start..child.end],
+                    primary_item_id,
+                    // Property indexes start at 1
+                    property_count + 1,
+                )?;
+                push_iso_box(&mut new_iprp_content, b"ipma", &ipma_content);
+            }
+            _ => new_iprp_content.extend_from_slice(&avif[child.start..child.end]),
+        }
+    }
+
+    let mut new_meta_content = avif[meta.content_start..meta.content_start + 4].to_vec();
+    let mut new_iloc = None;
+    for child in meta_children.iter() {
+        if &child.box_type == b"iprp" {
+            push_iso_box(&mut new_meta_content, b"iprp", &new_iprp_content);
+        } else {
+            if &child.box_type == b"iloc" {
+                // Position of the iloc box in the new file, which only changes if it comes after
+                // the iprp box
+                let start = meta.start + 8 + new_meta_content.len();
+                new_iloc = Some(IsoBox {
+                    box_type: child.box_type,
+                    start,
+                    content_start: start + 8,
+                    end: start + child.end - child.start,
+                });
+            }
+            new_meta_content.extend_from_slice(&avif[child.start..child.end]);
+        }
+    }
+
+    let mut buffer = Vec::with_capacity(avif.len() + colr.len() + 16);
+    buffer.extend_from_slice(&avif[..meta.start]);
+    push_iso_box(&mut buffer, b"meta", &new_meta_content);
+    let delta = buffer.len() - meta.end;
+    buffer.extend_from_slice(&avif[meta.end..]);
+
+    if let Some(iloc) = new_iloc {
+        shift_item_locations(&mut buffer, iloc, meta.end, delta)?;
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{Context, Field, In, Tag, Value};
+    use image::ImageEncoder;
+    use std::io::Cursor;
+
+    const WIDTH: u32 = 8;
+    const HEIGHT: u32 = 6;
+    const XMP: &[u8] = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF/></x:xmpmeta>"#;
+    const IPTC: &[u8] = b"\x1C\x02\x74\x00\x08Jane Doe";
+
+    fn pixels() -> Vec<u8> {
+        (0..WIDTH * HEIGHT * 3).map(|i| (i * 5) as u8).collect()
+    }
+
+    /// Exif with a tag in each of the first directory, the exif directory and the gps directory,
+    /// in the native byte order our tiff files are written in
+    fn exif() -> Vec<u8> {
+        let fields = [
+            Field {
+                tag: Tag::Make,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"Canon".to_vec()]),
+            },
+            Field {
+                tag: Tag::DateTimeOriginal,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"2024:05:01 10:20:30".to_vec()]),
+            },
+            Field {
+                tag: Tag::GPSLatitudeRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"N".to_vec()]),
+            },
+        ];
+        let mut writer = exif::experimental::Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut buffer = Cursor::new(Vec::new());
+        writer
+            .write(&mut buffer, cfg!(target_endian = "little"))
+            .unwrap();
+        buffer.into_inner()
+    }
+
+    fn metadata() -> EmbeddedMetadata {
+        EmbeddedMetadata {
+            icc_profile: None,
+            exif: Some(exif()),
+            xmp: Some(XMP.to_vec()),
+            iptc: Some(IPTC.to_vec()),
+        }
+    }
+
+    /// Read the exif back and check the tag of each directory made it
+    fn assert_exif_round_trips(file: &[u8]) -> exif::Exif {
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(file))
+            .unwrap();
+        for tag in [Tag::Make, Tag::DateTimeOriginal, Tag::GPSLatitudeRef] {
+            assert!(
+                exif.get_field(tag, In::PRIMARY).is_some(),
+                "{tag} is missing"
+            );
+        }
+        exif
+    }
+
+    fn assert_decodes(file: &[u8]) {
+        let image = image::load_from_memory(file).unwrap();
+        assert_eq!((image.width(), image.height()), (WIDTH, HEIGHT));
+    }
+
+    fn jpeg() -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .encode(&pixels(), WIDTH, HEIGHT, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        jpeg
+    }
+
+    #[test]
+    fn jpeg_round_trip() {
+        let jpeg = embed_in_jpeg(&jpeg(), &metadata()).unwrap();
+        assert_decodes(&jpeg);
+        assert_exif_round_trips(&jpeg);
+
+        let segments = jpeg_segments(&jpeg).unwrap();
+        // The JFIF header has to stay first
+        assert_eq!(segments[0].0, JPEG_APP0);
+        let xmp = segments
+            .iter()
+            .find_map(|(marker, payload)| {
+                payload
+                    .strip_prefix(JPEG_XMP_PREFIX)
+                    .filter(|_| *marker == JPEG_APP1)
+            })
+            .unwrap();
+        assert_eq!(xmp, XMP);
+        let photoshop_resources = segments
+            .iter()
+            .find_map(|(marker, payload)| {
+                payload
+                    .strip_prefix(JPEG_PHOTOSHOP_PREFIX)
+                    .filter(|_| *marker == JPEG_APP13)
+            })
+            .unwrap();
+        assert_eq!(
+            iptc_from_photoshop_resources(photoshop_resources).as_deref(),
+            Some(IPTC)
+        );
+    }
+
+    #[test]
+    fn jpeg_metadata_is_stripped() {
+        let original = jpeg();
+        let embedded = embed_in_jpeg(&original, &metadata()).unwrap();
+        let stripped = strip_jpeg_metadata(&embedded).unwrap();
+        assert_eq!(stripped, original);
+        assert!(matches!(
+            exif::Reader::new().read_from_container(&mut Cursor::new(&stripped)),
+            Err(exif::Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let mut png = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut png)
+            .write_image(&pixels(), WIDTH, HEIGHT, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let png = embed_in_png(&png, &metadata()).unwrap();
+        // The png decoder checks the crc of every chunk
+        assert_decodes(&png);
+        assert_exif_round_trips(&png);
+
+        let mut itxt = b"iTXtXML:com.adobe.xmp\0\0\0\0\0".to_vec();
+        itxt.extend_from_slice(XMP);
+        assert!(png.windows(itxt.len()).any(|window| window == itxt));
+    }
+
+    #[test]
+    fn webp_round_trip() {
+        let pixels = pixels();
+        let webp = webp::Encoder::from_rgb(&pixels, WIDTH, HEIGHT).encode_simple(true, 100.0);
+        let webp = embed_in_webp(&webp.unwrap(), (WIDTH, HEIGHT), false, &metadata()).unwrap();
+        assert_decodes(&webp);
+        assert_exif_round_trips(&webp);
+
+        let chunks = webp_chunks(&webp).unwrap();
+        let fourccs: Vec<&[u8; 4]> = chunks.iter().map(|(fourcc, _)| fourcc).collect();
+        assert_eq!(fourccs, [b"VP8X", b"VP8L", b"EXIF", b"XMP "]);
+        assert_eq!(chunks[0].1[0], VP8X_EXIF_FLAG | VP8X_XMP_FLAG);
+        assert_eq!(chunks[3].1, XMP);
+    }
+
+    #[test]
+    fn tiff_round_trip() {
+        let mut tiff = Cursor::new(Vec::new());
+        image::codecs::tiff::TiffEncoder::new(&mut tiff)
+            .write_image(&pixels(), WIDTH, HEIGHT, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let tiff = embed_in_tiff(&tiff.into_inner(), &metadata()).unwrap();
+        assert_decodes(&tiff);
+        let exif = assert_exif_round_trips(&tiff);
+
+        let bytes_of = |tag_number: u16| match &exif
+            .get_field(Tag(Context::Tiff, tag_number), In::PRIMARY)
+            .unwrap()
+            .value
+        {
+            Value::Byte(bytes) | Value::Undefined(bytes, _) => bytes.clone(),
+            value => panic!("Unexpected value {value:?}"),
+        };
+        assert_eq!(bytes_of(TIFF_XMP_TAG), XMP);
+        assert_eq!(bytes_of(TIFF_IPTC_TAG), IPTC);
+    }
+
+    #[test]
+    fn avif_round_trip() {
+        let pixels: Vec<ravif::RGB8> = pixels()
+            .chunks_exact(3)
+            .map(|p| ravif::RGB8::new(p[0], p[1], p[2]))
+            .collect();
+        let encoded = ravif::Encoder::new()
+            .with_speed(10)
+            .encode_rgb(ravif::Img::new(
+                pixels.as_slice(),
+                WIDTH as usize,
+                HEIGHT as usize,
+            ))
+            .unwrap();
+        let icc_profile = b"test icc profile".to_vec();
+        let metadata = EmbeddedMetadata {
+            icc_profile: Some(icc_profile.clone()),
+            ..EmbeddedMetadata::default()
+        };
+        let avif = embed_in_avif(&encoded.avif_file, &metadata).unwrap();
+        // Decoding only works if the item locations were moved along with the image data
+        assert_decodes(&avif);
+
+        let top_level_boxes = iso_boxes(&avif, 0, avif.len()).unwrap();
+        let meta = find_box(&top_level_boxes, b"meta").unwrap();
+        let meta_children = iso_boxes(&avif, meta.content_start + 4, meta.end).unwrap();
+        let iprp = find_box(&meta_children, b"iprp").unwrap();
+        let iprp_children = iso_boxes(&avif, iprp.content_start, iprp.end).unwrap();
+        let ipco = find_box(&iprp_children, b"ipco").unwrap();
+        let properties = iso_boxes(&avif, ipco.content_start, ipco.end).unwrap();
+        let colr = properties.last().unwrap();
+        assert_eq!(&colr.box_type, b"colr");
+        assert_eq!(&avif[colr.content_start..colr.content_start + 4], b"prof");
+        assert_eq!(&avif[colr.content_start + 4..colr.end], icc_profile);
+    }
+}
@@ -0,0 +1,652 @@
+use crate::color_management;
+use crate::dng_opcodes;
+use crate::export_error::{DecodeBackend, ExportError};
+use crate::image_helpers::{
+    self, ColorSpace, ExportImageFormat, ExportSettings, RawDevelopSettings,
+};
+use crate::metadata::SourceContainer;
+use crate::raw_develop::{self, DemosaicAlgorithm, RawDevelopOptions};
+use image::{DynamicImage, ImageFormat};
+use log::warn;
+use rawler::rawsource::RawSource;
+use serde::Serialize;
+use std::io::Read;
+use std::path::Path;
+
+/// How much of the start of a file is read to detect its format
+const SNIFF_LEN: u64 = 64 * 1024;
+
+/// A decoded image along with the icc profile of its pixels. No profile means sRGB
+pub struct DecodedImage {
+    pub image: DynamicImage,
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+/// A format we can read, along with how to recognize it
+pub trait ImageDecoder: Sync {
+    /// Name of the format shown to users
+    fn name(&self) -> &'static str;
+
+    /// Extensions of the format. Used in the file picker, and to pick a decoder for files whose
+    /// content isn't recognized
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// Whether a file is in this format, judged from the start of its content
+    fn sniff(&self, path: &Path, header: &[u8]) -> bool;
+
+    fn decode(
+        &self,
+        path: &Path,
+        export_settings: &ExportSettings,
+    ) -> Result<DecodedImage, ExportError>;
+
+    /// Image shown in the preview. `None` when the webview can show the file as it is. Raw files
+    /// are developed with the same settings as the export.
+    fn preview(
+        &self,
+        _path: &Path,
+        _raw_develop: &RawDevelopSettings,
+    ) -> Result<Option<DynamicImage>, ExportError> {
+        Ok(None)
+    }
+
+    /// Raw files are developed before exporting, and are the only files which can be converted
+    /// to DNG
+    fn is_raw(&self) -> bool {
+        false
+    }
+
+    /// The format a file is exported in for `ExportImageFormat::Original`. `None` when we can't
+    /// write the file's format.
+    fn export_format(&self, _path: &Path) -> Option<ExportImageFormat> {
+        None
+    }
+
+    /// Where the metadata of a file is read from, see `metadata::read_source_metadata`
+    fn source_container(&self, _path: &Path) -> SourceContainer {
+        if self.is_raw() {
+            SourceContainer::Raw
+        } else {
+            SourceContainer::Other
+        }
+    }
+}
+
+/// Raw files the development can't handle still get a preview, from the image embedded in the
+/// file
+fn raw_preview(
+    path: &Path,
+    developed: Result<DynamicImage, ExportError>,
+) -> Result<Option<DynamicImage>, ExportError> {
+    let image = developed
+        .map(|image| DynamicImage::ImageRgb8(image.into_rgb8()))
+        .or_else(|e| {
+            warn!("Error developing raw image, using the embedded jpeg instead {e:?}");
+            image_helpers::load_raw_image_embedded_jpeg(path)
+        })?;
+    Ok(Some(image))
+}
+
+/// Develops a raw with rawler, see `raw_develop`
+fn develop_with_rawler(
+    path: &Path,
+    raw_develop: &RawDevelopSettings,
+) -> Result<DecodedImage, ExportError> {
+    let options = image_helpers::rawler_develop_options(raw_develop);
+    let image = raw_develop::develop_raw_image(path, &options)?;
+    let image = if raw_develop.output_16bit {
+        image
+    } else {
+        DynamicImage::ImageRgb8(image.into_rgb8())
+    };
+    // rawler develops into sRGB
+    Ok(DecodedImage {
+        image,
+        icc_profile: None,
+    })
+}
+
+fn preview_with_rawler(
+    path: &Path,
+    raw_develop: &RawDevelopSettings,
+) -> Result<DynamicImage, ExportError> {
+    // AHD takes seconds on a full size sensor, too slow to redo on every settings change.
+    // Bilinear differs only in fine detail, which doesn't show at preview size.
+    let options = RawDevelopOptions {
+        demosaic: DemosaicAlgorithm::Bilinear,
+        ..image_helpers::rawler_develop_options(raw_develop)
+    };
+    raw_develop::develop_raw_image(path, &options)
+}
+
+/// The icc profile of the color space libraw develops into for these settings
+fn libraw_icc_profile(raw_develop: &RawDevelopSettings) -> Result<Option<Vec<u8>>, ExportError> {
+    let raw_color_space = image_helpers::libraw_output_color_space(raw_develop);
+    if raw_color_space == ColorSpace::Srgb {
+        return Ok(None);
+    }
+    color_management::color_space_icc_profile(raw_color_space)
+        .map(Some)
+        .map_err(ExportError::color_conversion)
+}
+
+struct HeifDecoder;
+impl ImageDecoder for HeifDecoder {
+    fn name(&self) -> &'static str {
+        "HEIF"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["heic", "heif"]
+    }
+
+    fn sniff(&self, _path: &Path, header: &[u8]) -> bool {
+        // An ftyp box naming a heif brand. AVIF files use the same container, so they are told
+        // apart by the avif brands
+        if header.len() < 12 || header[4..8] != *b"ftyp" {
+            return false;
+        }
+        let box_len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        // The major brand, then the compatible brands after the minor version
+        let compatible_brands = header
+            .get(16..box_len.min(header.len()))
+            .unwrap_or_default();
+        let brands: Vec<&[u8]> = std::iter::once(&header[8..12])
+            .chain(compatible_brands.chunks_exact(4))
+            .collect();
+        let is_avif = brands
+            .iter()
+            .any(|brand| matches!(*brand, b"avif" | b"avis"));
+        let is_heif = brands.iter().any(|brand| {
+            matches!(
+                *brand,
+                b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"mif1" | b"msf1"
+            )
+        });
+        is_heif && !is_avif
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _export_settings: &ExportSettings,
+    ) -> Result<DecodedImage, ExportError> {
+        Ok(DecodedImage {
+            image: image_helpers::load_heif_image(path)?,
+            icc_profile: color_management::read_heif_icc_profile(path),
+        })
+    }
+
+    fn export_format(&self, _path: &Path) -> Option<ExportImageFormat> {
+        Some(ExportImageFormat::Heic)
+    }
+
+    fn source_container(&self, _path: &Path) -> SourceContainer {
+        SourceContainer::Heif
+    }
+}
+
+/// DNGs are developed by rawler, see `raw_develop`
+struct DngDecoder;
+impl ImageDecoder for DngDecoder {
+    fn name(&self) -> &'static str {
+        "DNG"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["dng"]
+    }
+
+    fn sniff(&self, _path: &Path, header: &[u8]) -> bool {
+        dng_opcodes::is_dng_data(header)
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        export_settings: &ExportSettings,
+    ) -> Result<DecodedImage, ExportError> {
+        develop_with_rawler(path, &export_settings.raw_develop)
+    }
+
+    fn preview(
+        &self,
+        path: &Path,
+        raw_develop: &RawDevelopSettings,
+    ) -> Result<Option<DynamicImage>, ExportError> {
+        raw_preview(path, preview_with_rawler(path, raw_develop))
+    }
+
+    fn is_raw(&self) -> bool {
+        true
+    }
+}
+
+/// Makers whose cameras write tiff based raw files
+const RAW_CAMERA_MAKERS: &[&str] = &[
+    "canon",
+    "nikon",
+    "sony",
+    "fujifilm",
+    "olympus",
+    "om digital",
+    "panasonic",
+    "leica",
+    "pentax",
+    "ricoh",
+    "samsung",
+    "sigma",
+    "hasselblad",
+    "phase one",
+    "leaf",
+    "mamiya",
+    "kodak",
+    "minolta",
+    "konica minolta",
+    "epson",
+];
+
+/// Raw formats which have magic bytes of their own, rather than being plain tiffs
+fn has_raw_magic(header: &[u8]) -> bool {
+    let starts_with_any = |magics: &[&[u8]]| magics.iter().any(|magic| header.starts_with(magic));
+    // ORF and RW2 are tiffs with a different version number
+    starts_with_any(&[b"IIRO", b"IIRS", b"MMOR", b"IIU\0", b"FUJIFILMCCD-RAW", b"\0MRM"])
+        // CRW files are CIFF, with its signature after the byte order and header length
+        || header.get(6..14) == Some(b"HEAPCCDR")
+        // CR2 files are tiffs with their own signature right after the tiff header
+        || (header.starts_with(b"II*\0") && header.get(8..10) == Some(b"CR"))
+}
+
+/// Camera raw files, developed by rawler like DNGs. Cameras rawler can't read are developed
+/// by libraw instead.
+struct RawDecoder;
+impl ImageDecoder for RawDecoder {
+    fn name(&self) -> &'static str {
+        "Camera RAW"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &[
+            "raf", "cr2", "mrw", "arw", "srf", "sr2", "mef", "orf", "srw", "erf", "kdc", "dcs",
+            "rw2", "dcr", "pef", "crw", "raw", "iiq", "3rf", "nrw", "nef", "mos", "ari",
+        ]
+    }
+
+    fn sniff(&self, path: &Path, header: &[u8]) -> bool {
+        if has_raw_magic(header) {
+            return true;
+        }
+        // Most raw formats are plain tiffs though. Those are only left to rawler to recognize
+        // when they come from a camera maker, since rawler has to open the whole file to tell
+        // a raw from a tiff the camera or an editor saved. Anything else falls back to its
+        // extension in `decoder_for`.
+        if image::guess_format(header).ok() != Some(ImageFormat::Tiff) {
+            return false;
+        }
+        let is_from_raw_camera = dng_opcodes::tiff_make(header).is_some_and(|make| {
+            let make = make.to_ascii_lowercase();
+            RAW_CAMERA_MAKERS
+                .iter()
+                .any(|maker| make.starts_with(maker))
+        });
+        if !is_from_raw_camera {
+            return false;
+        }
+        let Ok(raw_source) = RawSource::new(path) else {
+            return false;
+        };
+        rawler::get_decoder(&raw_source).is_ok()
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        export_settings: &ExportSettings,
+    ) -> Result<DecodedImage, ExportError> {
+        let raw_develop = &export_settings.raw_develop;
+        develop_with_rawler(path, raw_develop).or_else(|e| {
+            warn!("Error developing {path:?} with rawler, using libraw instead {e:?}");
+            Ok(DecodedImage {
+                image: image_helpers::load_raw_image_libraw(path, raw_develop, false)?,
+                icc_profile: libraw_icc_profile(raw_develop)?,
+            })
+        })
+    }
+
+    fn preview(
+        &self,
+        path: &Path,
+        raw_develop: &RawDevelopSettings,
+    ) -> Result<Option<DynamicImage>, ExportError> {
+        let developed = preview_with_rawler(path, raw_develop).or_else(|e| {
+            warn!("Error developing {path:?} with rawler, using libraw instead {e:?}");
+            // Developed at half size, which is plenty for the preview and skips demosaicing
+            let image = image_helpers::load_raw_image_libraw(path, raw_develop, true)?;
+            color_management::convert_to_color_space(
+                image,
+                libraw_icc_profile(raw_develop)?.as_deref(),
+                ColorSpace::Srgb,
+            )
+            .map_err(ExportError::color_conversion)
+        });
+        raw_preview(path, developed)
+    }
+
+    fn is_raw(&self) -> bool {
+        true
+    }
+}
+
+/// Everything the image crate reads
+struct StandardDecoder;
+impl ImageDecoder for StandardDecoder {
+    fn name(&self) -> &'static str {
+        "Image"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &[
+            "jpeg", "jpg", "png", "tiff", "tif", "webp", "bmp", "ico", "gif", "avif", "dds", "exr",
+            "ff", "hdr", "pbm", "pgm", "ppm", "pnm", "qoi", "tga",
+        ]
+    }
+
+    fn sniff(&self, _path: &Path, header: &[u8]) -> bool {
+        image::guess_format(header).is_ok()
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _export_settings: &ExportSettings,
+    ) -> Result<DecodedImage, ExportError> {
+        let image = image_helpers::open_oriented_image(path).map_err(|e| match e {
+            image::ImageError::Unsupported(e) => ExportError::UnsupportedFormat(e.to_string()),
+            e => ExportError::decode(DecodeBackend::Image, e),
+        })?;
+        Ok(DecodedImage {
+            image,
+            icc_profile: color_management::read_icc_profile(path),
+        })
+    }
+
+    fn export_format(&self, path: &Path) -> Option<ExportImageFormat> {
+        let format = image::ImageReader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .ok()?
+            .format()?;
+        format
+            .extensions_str()
+            .iter()
+            .find_map(|extension| ExportImageFormat::from_extension(extension))
+    }
+
+    fn source_container(&self, path: &Path) -> SourceContainer {
+        if self.export_format(path) == Some(ExportImageFormat::Jpeg) {
+            SourceContainer::Jpeg
+        } else {
+            SourceContainer::Other
+        }
+    }
+}
+
+/// Every format we read. Decoders are tried in order, so ones with a stricter check of the
+/// content have to come before the ones their files would also pass for, like DNG before other
+/// tiff based raws and raws before plain tiffs.
+static DECODERS: &[&dyn ImageDecoder] = &[&HeifDecoder, &DngDecoder, &RawDecoder, &StandardDecoder];
+
+fn read_header(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    std::fs::File::open(path)?
+        .take(SNIFF_LEN)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// Find the decoder for a file from its content. Files whose content isn't recognized fall
+/// back to the decoder for their extension.
+pub fn decoder_for(path: &Path) -> Result<&'static dyn ImageDecoder, ExportError> {
+    let header =
+        read_header(path).map_err(|e| ExportError::read(format!("Error reading {path:?}"), e))?;
+    if let Some(decoder) = DECODERS.iter().find(|decoder| decoder.sniff(path, &header)) {
+        return Ok(*decoder);
+    }
+
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    DECODERS
+        .iter()
+        .find(|decoder| decoder.extensions().contains(&extension.as_str()))
+        .copied()
+        .ok_or_else(|| ExportError::UnsupportedFormat(format!("Unrecognized image {path:?}")))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadableFormat {
+    name: &'static str,
+    extensions: &'static [&'static str],
+    raw: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WritableFormat {
+    format: ExportImageFormat,
+    name: String,
+    /// Extension of exported files. `None` for `ExportImageFormat::Original`
+    extension: Option<&'static str>,
+    /// The `FileSettings` fields which apply to the format
+    options: &'static [&'static str],
+    raw_sources_only: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportedFormats {
+    readable: Vec<ReadableFormat>,
+    writable: Vec<WritableFormat>,
+}
+
+/// Formats offered for exporting, in the order they are listed
+const WRITABLE_FORMATS: [ExportImageFormat; 9] = [
+    ExportImageFormat::Jpeg,
+    ExportImageFormat::Png,
+    ExportImageFormat::JpegXl,
+    ExportImageFormat::Webp,
+    ExportImageFormat::Avif,
+    ExportImageFormat::Tiff,
+    ExportImageFormat::Heic,
+    ExportImageFormat::Dng,
+    ExportImageFormat::Original,
+];
+
+pub fn supported_formats() -> SupportedFormats {
+    let readable = DECODERS
+        .iter()
+        .map(|decoder| ReadableFormat {
+            name: decoder.name(),
+            extensions: decoder.extensions(),
+            raw: decoder.is_raw(),
+        })
+        .collect();
+    let writable = WRITABLE_FORMATS
+        .iter()
+        .map(|&format| WritableFormat {
+            format,
+            name: format.to_string(),
+            extension: format.extension(),
+            options: format.options(),
+            raw_sources_only: format == ExportImageFormat::Dng,
+        })
+        .collect();
+    SupportedFormats { readable, writable }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{self, TempFile};
+
+    const JPEG_HEADER: &[u8] = b"\xff\xd8\xff\xe0\0\x10JFIF\0\x01\x01\0\0\x01\0\x01\0\0";
+    const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// An ftyp box with the major brand followed by a minor version and the compatible brands
+    fn ftyp(major_brand: &[u8; 4], compatible_brands: &[&[u8; 4]]) -> Vec<u8> {
+        let box_len = 16 + 4 * compatible_brands.len() as u32;
+        let mut ftyp = box_len.to_be_bytes().to_vec();
+        ftyp.extend_from_slice(b"ftyp");
+        ftyp.extend_from_slice(major_brand);
+        ftyp.extend_from_slice(&[0; 4]);
+        for brand in compatible_brands {
+            ftyp.extend_from_slice(*brand);
+        }
+        // The start of the next box, so the header is longer than the ftyp box
+        ftyp.extend_from_slice(b"\0\0\0\x08meta");
+        ftyp
+    }
+
+    /// The start of a CR2 file: a tiff header followed by the CR2 signature and version
+    fn cr2_header() -> Vec<u8> {
+        let mut header = b"II*\0\x10\0\0\0CR\x02\0".to_vec();
+        header.resize(64, 0);
+        header
+    }
+
+    /// Names of the decoders whose sniffing accepts the header
+    fn sniffing_decoders(header: &[u8]) -> Vec<&'static str> {
+        // Only tiffs from camera makers are opened by path, which then fails for this one
+        let path = Path::new("does-not-exist");
+        DECODERS
+            .iter()
+            .filter(|decoder| decoder.sniff(path, header))
+            .map(|decoder| decoder.name())
+            .collect()
+    }
+
+    fn decoder_name_for(name: &str, data: &[u8]) -> Option<&'static str> {
+        let file = TempFile::new(name, data);
+        decoder_for(&file.0).ok().map(|decoder| decoder.name())
+    }
+
+    #[test]
+    fn heif_is_sniffed_by_its_brands() {
+        assert_eq!(
+            sniffing_decoders(&ftyp(b"heic", &[b"mif1", b"heic"])),
+            ["HEIF"]
+        );
+        assert_eq!(sniffing_decoders(&ftyp(b"mif1", &[b"heic"]))[0], "HEIF");
+        // AVIF uses the same container, but is left to the image crate
+        let avif = ftyp(b"avif", &[b"mif1", b"avif"]);
+        assert!(!HeifDecoder.sniff(Path::new(""), &avif));
+        assert!(!HeifDecoder.sniff(Path::new(""), &ftyp(b"mif1", &[b"avif"])));
+        // Brands past the end of the ftyp box don't count
+        let mut mp4 = ftyp(b"isom", &[]);
+        mp4.extend_from_slice(b"heic");
+        assert!(!HeifDecoder.sniff(Path::new(""), &mp4));
+        assert!(!HeifDecoder.sniff(Path::new(""), b"\0\0\0\x18ftyp"));
+        assert!(!HeifDecoder.sniff(Path::new(""), JPEG_HEADER));
+    }
+
+    #[test]
+    fn dng_is_sniffed_before_other_tiffs() {
+        let dng = test_fixtures::small_dng();
+        assert_eq!(sniffing_decoders(&dng)[0], "DNG");
+        assert!(!DngDecoder.sniff(Path::new(""), &test_fixtures::rgb_tiff("Canon", 4, 4)));
+    }
+
+    #[test]
+    fn raws_are_sniffed_by_their_magic_bytes() {
+        let mut crw = b"II\x1a\0\0\0HEAPCCDR".to_vec();
+        crw.resize(64, 0);
+        let headers: [&[u8]; 8] = [
+            b"IIRO\x08\0\0\0",
+            b"IIRS\x08\0\0\0",
+            b"MMOR\0\0\0\x08",
+            b"IIU\0\x18\0\0\0",
+            b"FUJIFILMCCD-RAW 0201FF383501",
+            b"\0MRM\0\0\x10\0",
+            &crw[..],
+            &cr2_header(),
+        ];
+        for header in headers {
+            assert_eq!(sniffing_decoders(header)[0], "Camera RAW", "{header:?}");
+        }
+        // A tiff header without the CR2 signature isn't a raw by itself
+        let mut tiff = cr2_header();
+        tiff[8..10].copy_from_slice(b"XX");
+        assert!(!has_raw_magic(&tiff));
+        assert!(!has_raw_magic(JPEG_HEADER));
+        assert!(!has_raw_magic(&[]));
+    }
+
+    #[test]
+    fn plain_tiffs_arent_raws() {
+        // Not from a camera maker, so rawler isn't asked
+        assert_eq!(
+            sniffing_decoders(&test_fixtures::rgb_tiff("Adobe", 4, 4)),
+            ["Image"]
+        );
+        // From a camera maker, but not a raw rawler can open
+        assert_eq!(
+            sniffing_decoders(&test_fixtures::rgb_tiff("Canon", 4, 4)),
+            ["Image"]
+        );
+    }
+
+    #[test]
+    fn standard_formats_are_sniffed_by_the_image_crate() {
+        assert_eq!(sniffing_decoders(JPEG_HEADER), ["Image"]);
+        assert_eq!(sniffing_decoders(PNG_HEADER), ["Image"]);
+        assert!(sniffing_decoders(b"not an image").is_empty());
+        assert!(sniffing_decoders(&[]).is_empty());
+    }
+
+    #[test]
+    fn content_wins_over_the_extension() {
+        let heic = ftyp(b"heic", &[b"mif1", b"heic"]);
+        assert_eq!(decoder_name_for("sniff-heic.jpg", &heic), Some("HEIF"));
+        let dng = test_fixtures::small_dng();
+        assert_eq!(decoder_name_for("sniff-dng.tif", &dng), Some("DNG"));
+        assert_eq!(
+            decoder_name_for("sniff-cr2.jpg", &cr2_header()),
+            Some("Camera RAW")
+        );
+        let tiff = test_fixtures::rgb_tiff("Adobe", 4, 4);
+        assert_eq!(decoder_name_for("sniff-tiff.nef", &tiff), Some("Image"));
+        assert_eq!(
+            decoder_name_for("sniff-jpeg.heic", JPEG_HEADER),
+            Some("Image")
+        );
+    }
+
+    #[test]
+    fn unrecognized_content_falls_back_to_the_extension() {
+        let unknown = b"not an image";
+        assert_eq!(
+            decoder_name_for("sniff-unknown.NEF", unknown),
+            Some("Camera RAW")
+        );
+        assert_eq!(
+            decoder_name_for("sniff-unknown.heic", unknown),
+            Some("HEIF")
+        );
+        assert_eq!(decoder_name_for("sniff-unknown.dng", unknown), Some("DNG"));
+        assert_eq!(
+            decoder_name_for("sniff-unknown.png", unknown),
+            Some("Image")
+        );
+        let file = TempFile::new("sniff-unknown.txt", unknown);
+        assert!(matches!(
+            decoder_for(&file.0),
+            Err(ExportError::UnsupportedFormat(_))
+        ));
+        // Files which can't be read are errors of their own
+        assert!(matches!(
+            decoder_for(Path::new("does-not-exist.jpg")),
+            Err(ExportError::Read { .. })
+        ));
+    }
+}
@@ -0,0 +1,988 @@
+use crate::export_error::BoxError;
+use log::warn;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const TAG_NEW_SUBFILE_TYPE: u16 = 254;
+const TAG_MAKE: u16 = 271;
+const TAG_SUB_IFDS: u16 = 330;
+const TAG_DNG_VERSION: u16 = 50706;
+const TAG_OPCODE_LIST_2: u16 = 51009;
+const TAG_OPCODE_LIST_3: u16 = 51022;
+const OPCODE_WARP_RECTILINEAR: u32 = 1;
+const OPCODE_FIX_VIGNETTE_RADIAL: u32 = 3;
+const OPCODE_GAIN_MAP: u32 = 9;
+/// Opcodes with this flag may be skipped by readers which don't support them
+const OPCODE_FLAG_OPTIONAL: u32 = 1;
+
+/// Just enough of a tiff reader to find the tags of the raw image in a DNG. Reads only the IFDs
+/// and the values asked for, so the image data of large files is never loaded.
+struct TiffReader<R> {
+    source: RefCell<R>,
+    len: u64,
+    little_endian: bool,
+}
+impl<R: Read + Seek> TiffReader<R> {
+    fn new(mut source: R) -> Option<Self> {
+        let len = source.seek(SeekFrom::End(0)).ok()?;
+        source.seek(SeekFrom::Start(0)).ok()?;
+        let mut header = [0u8; 4];
+        source.read_exact(&mut header).ok()?;
+        let little_endian = match header {
+            [b'I', b'I', 42, 0] => true,
+            [b'M', b'M', 0, 42] => false,
+            _ => return None,
+        };
+        Some(TiffReader {
+            source: RefCell::new(source),
+            len,
+            little_endian,
+        })
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Option<Vec<u8>> {
+        // Checking against the file size keeps corrupt counts from allocating huge buffers
+        if offset.checked_add(len)? as u64 > self.len {
+            return None;
+        }
+        let mut source = self.source.borrow_mut();
+        source.seek(SeekFrom::Start(offset as u64)).ok()?;
+        let mut bytes = vec![0u8; len];
+        source.read_exact(&mut bytes).ok()?;
+        Some(bytes)
+    }
+
+    fn u16_from(&self, bytes: &[u8]) -> Option<u16> {
+        let bytes = bytes.get(..2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32_from(&self, bytes: &[u8]) -> Option<u32> {
+        let bytes = bytes.get(..4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        self.u16_from(&self.bytes(offset, 2)?)
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        self.u32_from(&self.bytes(offset, 4)?)
+    }
+
+    fn first_ifd(&self) -> Option<usize> {
+        self.u32_at(4).map(|offset| offset as usize)
+    }
+
+    /// The entries of an IFD, read in one go along with the offset of the next IFD
+    fn read_ifd(&self, ifd: usize) -> Option<(Vec<IfdEntry>, usize)> {
+        let count = self.u16_at(ifd)? as usize;
+        let block = self.bytes(ifd + 2, count * 12 + 4)?;
+        let entries = block
+            .chunks_exact(12)
+            .take(count)
+            .map(|entry| {
+                let value = [entry[8], entry[9], entry[10], entry[11]];
+                Some(IfdEntry {
+                    tag: self.u16_from(entry)?,
+                    field_type: self.u16_from(&entry[2..])?,
+                    count: self.u32_from(&entry[4..])? as usize,
+                    value,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let next_ifd = self.u32_from(&block[count * 12..])? as usize;
+        Some((entries, next_ifd))
+    }
+
+    fn entries(&self, ifd: usize) -> Option<Vec<IfdEntry>> {
+        self.read_ifd(ifd).map(|(entries, _)| entries)
+    }
+
+    fn next_ifd(&self, ifd: usize) -> Option<usize> {
+        match self.read_ifd(ifd)?.1 {
+            0 => None,
+            offset => Some(offset),
+        }
+    }
+
+    /// The bytes of an entry's value, which are stored in the entry itself when they fit
+    fn value(&self, entry: &IfdEntry) -> Option<Vec<u8>> {
+        let type_size = match entry.field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        };
+        let len = entry.count.checked_mul(type_size)?;
+        if len <= 4 {
+            Some(entry.value[..len].to_vec())
+        } else {
+            self.bytes(self.u32_from(&entry.value)? as usize, len)
+        }
+    }
+
+    fn u32_values(&self, entry: &IfdEntry) -> Option<Vec<u32>> {
+        let value = self.value(entry)?;
+        match entry.field_type {
+            3 => Some(
+                value
+                    .chunks_exact(2)
+                    .filter_map(|bytes| self.u16_from(bytes).map(u32::from))
+                    .collect(),
+            ),
+            4 | 13 => Some(
+                value
+                    .chunks_exact(4)
+                    .filter_map(|bytes| self.u32_from(bytes))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Whether the first IFD has the DNGVersion tag
+    fn is_dng(&self) -> bool {
+        self.first_ifd()
+            .and_then(|ifd| self.entries(ifd))
+            .is_some_and(|entries| entries.iter().any(|entry| entry.tag == TAG_DNG_VERSION))
+    }
+
+    /// All IFDs of the file: the main chain and the sub IFDs hanging off it
+    fn all_ifds(&self) -> Vec<usize> {
+        let mut pending: Vec<usize> = Vec::new();
+        let mut ifd = self.first_ifd();
+        while let Some(offset) = ifd {
+            // Guard against files whose IFDs point back at each other
+            if pending.contains(&offset) || pending.len() > 64 {
+                break;
+            }
+            pending.push(offset);
+            ifd = self.next_ifd(offset);
+        }
+        let mut ifds = Vec::new();
+        while let Some(offset) = pending.pop() {
+            if ifds.contains(&offset) || ifds.len() > 64 {
+                continue;
+            }
+            ifds.push(offset);
+            let sub_ifds = self
+                .entries(offset)
+                .unwrap_or_default()
+                .iter()
+                .filter(|entry| entry.tag == TAG_SUB_IFDS)
+                .filter_map(|entry| self.u32_values(entry))
+                .flatten()
+                .map(|sub_ifd| sub_ifd as usize)
+                .collect::<Vec<_>>();
+            pending.extend(sub_ifds);
+        }
+        ifds
+    }
+}
+
+struct IfdEntry {
+    tag: u16,
+    field_type: u16,
+    count: usize,
+    /// The value itself when it fits in 4 bytes, otherwise its offset
+    value: [u8; 4],
+}
+
+/// Reads the big endian values opcode parameters are stored as
+struct OpcodeReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+impl OpcodeReader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + N)?
+            .try_into()
+            .ok()?;
+        self.offset += N;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_be_bytes)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.take().map(f32::from_be_bytes)
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        self.take().map(f64::from_be_bytes)
+    }
+}
+
+/// The GainMap opcode, which phones and some cameras use to correct lens shading. Scales the
+/// samples of an area by gains interpolated from a grid spread over the image.
+pub struct GainMap {
+    top: usize,
+    left: usize,
+    bottom: usize,
+    right: usize,
+    plane: usize,
+    planes: usize,
+    row_pitch: usize,
+    col_pitch: usize,
+    points_v: usize,
+    points_h: usize,
+    spacing_v: f64,
+    spacing_h: f64,
+    origin_v: f64,
+    origin_h: f64,
+    map_planes: usize,
+    gains: Vec<f32>,
+}
+impl GainMap {
+    fn parse(reader: &mut OpcodeReader) -> Option<Self> {
+        let mut next = || reader.u32().map(|value| value as usize);
+        let (top, left, bottom, right) = (next()?, next()?, next()?, next()?);
+        let (plane, planes, row_pitch, col_pitch) = (next()?, next()?, next()?, next()?);
+        let (points_v, points_h) = (next()?, next()?);
+        let (spacing_v, spacing_h) = (reader.f64()?, reader.f64()?);
+        let (origin_v, origin_h) = (reader.f64()?, reader.f64()?);
+        let map_planes = reader.u32()? as usize;
+        let gain_count = points_v.checked_mul(points_h)?.checked_mul(map_planes)?;
+        if gain_count == 0 {
+            return None;
+        }
+        let gains = (0..gain_count)
+            .map(|_| reader.f32())
+            .collect::<Option<Vec<f32>>>()?;
+        Some(GainMap {
+            top,
+            left,
+            bottom,
+            right,
+            plane,
+            planes,
+            row_pitch: row_pitch.max(1),
+            col_pitch: col_pitch.max(1),
+            points_v,
+            points_h,
+            spacing_v,
+            spacing_h,
+            origin_v,
+            origin_h,
+            map_planes,
+            gains,
+        })
+    }
+
+    fn gain(&self, map_v: f64, map_h: f64, map_plane: usize) -> f32 {
+        let at =
+            |v: usize, h: usize| self.gains[(v * self.points_h + h) * self.map_planes + map_plane];
+        let v0 = map_v.floor() as usize;
+        let h0 = map_h.floor() as usize;
+        let v1 = (v0 + 1).min(self.points_v - 1);
+        let h1 = (h0 + 1).min(self.points_h - 1);
+        let fv = (map_v - v0 as f64) as f32;
+        let fh = (map_h - h0 as f64) as f32;
+        let top = at(v0, h0) * (1.0 - fh) + at(v0, h1) * fh;
+        let bottom = at(v1, h0) * (1.0 - fh) + at(v1, h1) * fh;
+        top * (1.0 - fv) + bottom * fv
+    }
+
+    /// Position on the map grid of a coordinate relative to the image, 0 to 1
+    fn map_position(relative: f64, origin: f64, spacing: f64, points: usize) -> f64 {
+        if points < 2 || spacing <= 0.0 {
+            return 0.0;
+        }
+        ((relative - origin) / spacing).clamp(0.0, (points - 1) as f64)
+    }
+
+    /// Apply the gains to `samples`, which hold `samples_per_pixel` values for each pixel of an
+    /// image `width` pixels wide. The opcode's coordinates are relative to `area`, the active
+    /// area of the sensor as (left, top, width, height).
+    pub fn apply(
+        &self,
+        samples: &mut [f32],
+        width: usize,
+        samples_per_pixel: usize,
+        area: (usize, usize, usize, usize),
+    ) {
+        let (area_left, area_top, area_width, area_height) = area;
+        let bottom = self.bottom.min(area_height);
+        // Columns past the edge of the image would wrap around into the next row
+        let right = self
+            .right
+            .min(area_width)
+            .min(width.saturating_sub(area_left));
+        let last_plane = (self.plane + self.planes).min(samples_per_pixel);
+        for row in (self.top..bottom).step_by(self.row_pitch) {
+            let map_v = Self::map_position(
+                row as f64 / area_height as f64,
+                self.origin_v,
+                self.spacing_v,
+                self.points_v,
+            );
+            for col in (self.left..right).step_by(self.col_pitch) {
+                let map_h = Self::map_position(
+                    col as f64 / area_width as f64,
+                    self.origin_h,
+                    self.spacing_h,
+                    self.points_h,
+                );
+                let pixel = ((row + area_top) * width + col + area_left) * samples_per_pixel;
+                for plane in self.plane..last_plane {
+                    let map_plane = (plane - self.plane).min(self.map_planes - 1);
+                    if let Some(sample) = samples.get_mut(pixel + plane) {
+                        *sample *= self.gain(map_v, map_h, map_plane);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An area of the image as (left, top, width, height)
+type Area = (usize, usize, usize, usize);
+
+/// The optical center of an opcode in pixels, from its position relative to `area`, along with
+/// the distance from it to the farthest corner of the area. Radii are relative to that distance.
+fn optical_center(center: (f64, f64), area: Area) -> (f64, f64, f64) {
+    let (left, top, width, height) = area;
+    let (right, bottom) = ((left + width) as f64 - 1.0, (top + height) as f64 - 1.0);
+    let (left, top) = (left as f64, top as f64);
+    let cx = left + center.0 * (right - left);
+    let cy = top + center.1 * (bottom - top);
+    let max_distance = [(left, top), (right, top), (left, bottom), (right, bottom)]
+        .iter()
+        .map(|(x, y)| (x - cx).hypot(y - cy))
+        .fold(0.0, f64::max);
+    (cx, cy, max_distance)
+}
+
+/// Sample a plane of `image` at a position between pixels, staying inside `area`
+fn sample_bilinear(
+    image: &[[f32; 3]],
+    width: usize,
+    area: Area,
+    x: f64,
+    y: f64,
+    plane: usize,
+) -> f32 {
+    let (left, top, area_width, area_height) = area;
+    let (right, bottom) = (left + area_width - 1, top + area_height - 1);
+    let x = x.clamp(left as f64, right as f64);
+    let y = y.clamp(top as f64, bottom as f64);
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(right), (y0 + 1).min(bottom));
+    let fx = (x - x0 as f64) as f32;
+    let fy = (y - y0 as f64) as f32;
+    let at = |x: usize, y: usize| image[y * width + x][plane];
+    let upper = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+    let lower = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+    upper * (1.0 - fy) + lower * fy
+}
+
+/// The WarpRectilinear opcode, which corrects the distortion of a lens, and with separate
+/// coefficients for each color its lateral chromatic aberration
+pub struct WarpRectilinear {
+    /// The radial kr0 to kr3 and tangential kt0 and kt1 coefficients of each plane
+    coefficients: Vec<[f64; 6]>,
+    center: (f64, f64),
+}
+impl WarpRectilinear {
+    fn parse(reader: &mut OpcodeReader) -> Option<Self> {
+        let planes = reader.u32()? as usize;
+        if !(1..=3).contains(&planes) {
+            return None;
+        }
+        let coefficients = (0..planes)
+            .map(|_| {
+                let mut coefficients = [0.0; 6];
+                for coefficient in &mut coefficients {
+                    *coefficient = reader.f64()?;
+                }
+                Some(coefficients)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let center = (reader.f64()?, reader.f64()?);
+        Some(WarpRectilinear {
+            coefficients,
+            center,
+        })
+    }
+
+    /// Every pixel of `area` is replaced with the one the lens moved it to
+    fn apply(&self, rgb: &mut [[f32; 3]], width: usize, area: Area) {
+        let (left, top, area_width, area_height) = area;
+        let (cx, cy, max_distance) = optical_center(self.center, area);
+        if max_distance <= 0.0 {
+            return;
+        }
+        // Pixels are read from all over the image, so read them from a copy
+        let source = rgb.to_vec();
+        for y in top..top + area_height {
+            let dy = (y as f64 - cy) / max_distance;
+            for x in left..left + area_width {
+                let dx = (x as f64 - cx) / max_distance;
+                let r2 = dx * dx + dy * dy;
+                for plane in 0..3 {
+                    let [kr0, kr1, kr2, kr3, kt0, kt1] =
+                        self.coefficients[plane.min(self.coefficients.len() - 1)];
+                    let radial = kr0 + r2 * (kr1 + r2 * (kr2 + r2 * kr3));
+                    let source_dx = dx * radial + kt0 * 2.0 * dx * dy + kt1 * (r2 + 2.0 * dx * dx);
+                    let source_dy = dy * radial + kt1 * 2.0 * dx * dy + kt0 * (r2 + 2.0 * dy * dy);
+                    rgb[y * width + x][plane] = sample_bilinear(
+                        &source,
+                        width,
+                        area,
+                        cx + source_dx * max_distance,
+                        cy + source_dy * max_distance,
+                        plane,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The FixVignetteRadial opcode, which brightens the corners a lens darkens with a gain that
+/// grows with the distance from the optical center
+pub struct FixVignetteRadial {
+    /// Coefficients k0 to k4 of the even powers of the radius, from r² to r¹⁰
+    coefficients: [f64; 5],
+    center: (f64, f64),
+}
+impl FixVignetteRadial {
+    fn parse(reader: &mut OpcodeReader) -> Option<Self> {
+        let mut coefficients = [0.0; 5];
+        for coefficient in &mut coefficients {
+            *coefficient = reader.f64()?;
+        }
+        let center = (reader.f64()?, reader.f64()?);
+        Some(FixVignetteRadial {
+            coefficients,
+            center,
+        })
+    }
+
+    fn apply(&self, rgb: &mut [[f32; 3]], width: usize, area: Area) {
+        let (left, top, area_width, area_height) = area;
+        let (cx, cy, max_distance) = optical_center(self.center, area);
+        if max_distance <= 0.0 {
+            return;
+        }
+        let [k0, k1, k2, k3, k4] = self.coefficients;
+        for y in top..top + area_height {
+            let dy = (y as f64 - cy) / max_distance;
+            for x in left..left + area_width {
+                let dx = (x as f64 - cx) / max_distance;
+                let r2 = dx * dx + dy * dy;
+                let gain = 1.0 + r2 * (k0 + r2 * (k1 + r2 * (k2 + r2 * (k3 + r2 * k4))));
+                rgb[y * width + x]
+                    .iter_mut()
+                    .for_each(|value| *value *= gain as f32);
+            }
+        }
+    }
+}
+
+/// Opcodes from OpcodeList3, which apply to the image once it has been demosaiced
+pub enum ImageOpcode {
+    WarpRectilinear(WarpRectilinear),
+    FixVignetteRadial(FixVignetteRadial),
+}
+impl ImageOpcode {
+    /// Apply the opcode to the demosaiced `rgb` pixels of an image `width` pixels wide. The
+    /// opcode's coordinates are relative to `area`, the active area of the sensor as (left, top,
+    /// width, height).
+    pub fn apply(&self, rgb: &mut [[f32; 3]], width: usize, area: Area) {
+        let (left, top, area_width, area_height) = area;
+        let fits = left + area_width <= width && (top + area_height) * width <= rgb.len();
+        if area_width == 0 || area_height == 0 || !fits {
+            return;
+        }
+        match self {
+            ImageOpcode::WarpRectilinear(warp) => warp.apply(rgb, width, area),
+            ImageOpcode::FixVignetteRadial(vignette) => vignette.apply(rgb, width, area),
+        }
+    }
+}
+
+/// The opcodes of a DNG we support. Files which aren't DNGs have none.
+#[derive(Default)]
+pub struct DngOpcodes {
+    /// From OpcodeList2, applied to the raw data right after it is scaled to its black and white
+    /// levels
+    pub gain_maps: Vec<GainMap>,
+    /// From OpcodeList3, applied after demosaicing
+    pub image_opcodes: Vec<ImageOpcode>,
+}
+
+/// Parse an opcode list with `parse`, which is called for the `supported` opcodes. Other opcodes
+/// are skipped, with a warning when the file marks them as required.
+fn parse_opcode_list<T>(
+    data: &[u8],
+    supported: &[u32],
+    parse: impl Fn(u32, &mut OpcodeReader) -> Option<T>,
+) -> Result<Vec<T>, BoxError> {
+    let mut reader = OpcodeReader { data, offset: 0 };
+    let count = reader.u32().ok_or("Truncated opcode list")?;
+    let mut opcodes = Vec::new();
+    for _ in 0..count {
+        let (Some(id), Some(_version), Some(flags), Some(size)) =
+            (reader.u32(), reader.u32(), reader.u32(), reader.u32())
+        else {
+            return Err("Truncated opcode list".into());
+        };
+        let parameters = reader
+            .data
+            .get(reader.offset..)
+            .and_then(|rest| rest.get(..size as usize))
+            .ok_or("Truncated opcode list")?;
+        reader.offset += size as usize;
+
+        if supported.contains(&id) {
+            let mut parameter_reader = OpcodeReader {
+                data: parameters,
+                offset: 0,
+            };
+            let opcode = parse(id, &mut parameter_reader)
+                .ok_or_else(|| format!("Invalid DNG opcode {id}"))?;
+            opcodes.push(opcode);
+        } else if flags & OPCODE_FLAG_OPTIONAL == 0 {
+            warn!("Skipping unsupported DNG opcode {id} which the file marks as required");
+        }
+    }
+    Ok(opcodes)
+}
+
+/// Whether the data starts like a DNG: a tiff with the DNGVersion tag in its first IFD. Works
+/// with just the start of a file, as long as it reaches the first IFD.
+pub fn is_dng_data(data: &[u8]) -> bool {
+    TiffReader::new(Cursor::new(data)).is_some_and(|tiff| tiff.is_dng())
+}
+
+/// The Make tag from the first IFD of a tiff, naming the maker of the camera which took it.
+/// Works with just the start of a file, like `is_dng_data`.
+pub fn tiff_make(data: &[u8]) -> Option<String> {
+    let tiff = TiffReader::new(Cursor::new(data))?;
+    let entries = tiff.entries(tiff.first_ifd()?)?;
+    let make = entries.iter().find(|entry| entry.tag == TAG_MAKE)?;
+    let value = tiff.value(make)?;
+    let make = String::from_utf8_lossy(&value);
+    Some(make.trim_end_matches('\0').trim().to_owned())
+}
+
+/// Reads the opcodes of the raw image of a DNG: the gain maps from OpcodeList2 and the lens
+/// corrections from OpcodeList3. Other opcodes aren't supported and are skipped with a warning.
+pub fn read_opcodes(path: &Path) -> Result<DngOpcodes, BoxError> {
+    let file = BufReader::new(File::open(path)?);
+    // Only the header and the IFDs are read, so other raw formats are cheap to rule out
+    let Some(tiff) = TiffReader::new(file).filter(|tiff| tiff.is_dng()) else {
+        return Ok(DngOpcodes::default());
+    };
+    let raw_ifd_entries = tiff.all_ifds().into_iter().find_map(|ifd| {
+        let entries = tiff.entries(ifd)?;
+        let is_raw_image = entries
+            .iter()
+            .find(|entry| entry.tag == TAG_NEW_SUBFILE_TYPE)
+            .and_then(|entry| tiff.u32_values(entry))
+            .map_or(true, |values| values.first() == Some(&0));
+        let has_opcodes = entries
+            .iter()
+            .any(|entry| matches!(entry.tag, TAG_OPCODE_LIST_2 | TAG_OPCODE_LIST_3));
+        (is_raw_image && has_opcodes).then_some(entries)
+    });
+    let Some(entries) = raw_ifd_entries else {
+        return Ok(DngOpcodes::default());
+    };
+    let opcode_list = |tag: u16| {
+        entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .and_then(|entry| tiff.value(entry))
+    };
+
+    let gain_maps = match opcode_list(TAG_OPCODE_LIST_2) {
+        Some(data) => parse_opcode_list(&data, &[OPCODE_GAIN_MAP], |_, reader| {
+            GainMap::parse(reader)
+        })?,
+        None => Vec::new(),
+    };
+    let image_opcodes = match opcode_list(TAG_OPCODE_LIST_3) {
+        Some(data) => parse_opcode_list(
+            &data,
+            &[OPCODE_WARP_RECTILINEAR, OPCODE_FIX_VIGNETTE_RADIAL],
+            |id, reader| match id {
+                OPCODE_WARP_RECTILINEAR => {
+                    WarpRectilinear::parse(reader).map(ImageOpcode::WarpRectilinear)
+                }
+                _ => FixVignetteRadial::parse(reader).map(ImageOpcode::FixVignetteRadial),
+            },
+        )?,
+        None => Vec::new(),
+    };
+    Ok(DngOpcodes {
+        gain_maps,
+        image_opcodes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{self, TempFile};
+
+    fn be_u32s(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    fn be_f64s(values: &[f64]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    fn opcode(id: u32, flags: u32, parameters: &[u8]) -> Vec<u8> {
+        let mut opcode = be_u32s(&[id, 0x01030000, flags, parameters.len() as u32]);
+        opcode.extend_from_slice(parameters);
+        opcode
+    }
+
+    fn opcode_list(opcodes: &[Vec<u8>]) -> Vec<u8> {
+        let mut list = be_u32s(&[opcodes.len() as u32]);
+        opcodes
+            .iter()
+            .for_each(|opcode| list.extend_from_slice(opcode));
+        list
+    }
+
+    /// A gain map over the whole image, with `gains` spread evenly from edge to edge. `area` is
+    /// top, left, bottom and right, `planes` the first plane and the number of planes.
+    fn gain_map_parameters(
+        area: [u32; 4],
+        planes: (u32, u32),
+        pitch: (u32, u32),
+        points: (u32, u32),
+        gains: &[f32],
+    ) -> Vec<u8> {
+        let (points_v, points_h) = points;
+        let spacing = |points: u32| 1.0 / (points.max(2) - 1) as f64;
+        let mut parameters = be_u32s(&area);
+        parameters.extend(be_u32s(&[planes.0, planes.1, pitch.0, pitch.1]));
+        parameters.extend(be_u32s(&[points_v, points_h]));
+        parameters.extend(be_f64s(&[spacing(points_v), spacing(points_h), 0.0, 0.0]));
+        parameters.extend(be_u32s(&[1]));
+        parameters.extend(gains.iter().flat_map(|gain| gain.to_be_bytes()));
+        parameters
+    }
+
+    fn parse_gain_map(parameters: &[u8]) -> Option<GainMap> {
+        GainMap::parse(&mut OpcodeReader {
+            data: parameters,
+            offset: 0,
+        })
+    }
+
+    fn warp_parameters(coefficients: &[[f64; 6]], center: (f64, f64)) -> Vec<u8> {
+        let mut parameters = be_u32s(&[coefficients.len() as u32]);
+        coefficients
+            .iter()
+            .for_each(|plane| parameters.extend(be_f64s(plane)));
+        parameters.extend(be_f64s(&[center.0, center.1]));
+        parameters
+    }
+
+    fn vignette_parameters(coefficients: [f64; 5], center: (f64, f64)) -> Vec<u8> {
+        let mut parameters = be_f64s(&coefficients);
+        parameters.extend(be_f64s(&[center.0, center.1]));
+        parameters
+    }
+
+    fn parse_image_opcodes(list: &[u8]) -> Result<Vec<ImageOpcode>, BoxError> {
+        parse_opcode_list(
+            list,
+            &[OPCODE_WARP_RECTILINEAR, OPCODE_FIX_VIGNETTE_RADIAL],
+            |id, reader| match id {
+                OPCODE_WARP_RECTILINEAR => {
+                    WarpRectilinear::parse(reader).map(ImageOpcode::WarpRectilinear)
+                }
+                _ => FixVignetteRadial::parse(reader).map(ImageOpcode::FixVignetteRadial),
+            },
+        )
+    }
+
+    /// A single row of `width` pixels whose values are their x coordinate
+    fn ramp(width: usize) -> Vec<[f32; 3]> {
+        (0..width).map(|x| [x as f32; 3]).collect()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn gain_map_interpolates_between_its_points() {
+        let parameters = gain_map_parameters([0, 0, 4, 4], (0, 1), (1, 1), (1, 2), &[1.0, 3.0]);
+        let gain_map = parse_gain_map(&parameters).unwrap();
+        let mut samples = vec![1.0; 16];
+        gain_map.apply(&mut samples, 4, 1, (0, 0, 4, 4));
+        // Columns are at 0, 0.25, 0.5 and 0.75 of the way across
+        for row in samples.chunks_exact(4) {
+            for (sample, expected) in row.iter().zip([1.0, 1.5, 2.0, 2.5]) {
+                assert_close(*sample, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn gain_map_only_touches_its_rows_planes_and_area() {
+        let parameters = gain_map_parameters([0, 0, 2, 2], (1, 1), (2, 1), (1, 1), &[2.0]);
+        let gain_map = parse_gain_map(&parameters).unwrap();
+        // 4x4 pixels of 3 samples, with the opcode's coordinates inside a 2x2 area at (1, 1)
+        let mut samples = vec![1.0; 4 * 4 * 3];
+        gain_map.apply(&mut samples, 4, 3, (1, 1, 2, 2));
+        for (index, sample) in samples.iter().enumerate() {
+            let (pixel, plane) = (index / 3, index % 3);
+            let (row, col) = (pixel / 4, pixel % 4);
+            // Only the first row of the area is scaled, the pitch skips the second
+            let scaled = row == 1 && (1..3).contains(&col) && plane == 1;
+            assert_eq!(
+                *sample,
+                if scaled { 2.0 } else { 1.0 },
+                "{row} {col} {plane}"
+            );
+        }
+    }
+
+    #[test]
+    fn gain_map_outside_the_samples_is_ignored() {
+        let parameters = gain_map_parameters([0, 0, 100, 100], (0, 3), (1, 1), (1, 1), &[2.0]);
+        let gain_map = parse_gain_map(&parameters).unwrap();
+        let mut samples = vec![1.0; 4];
+        gain_map.apply(&mut samples, 2, 1, (0, 0, 100, 100));
+        assert_eq!(samples, [2.0; 4]);
+    }
+
+    #[test]
+    fn invalid_gain_maps_are_rejected() {
+        let no_points = gain_map_parameters([0, 0, 4, 4], (0, 1), (1, 1), (0, 0), &[]);
+        assert!(parse_gain_map(&no_points).is_none());
+        let missing_gains = gain_map_parameters([0, 0, 4, 4], (0, 1), (1, 1), (2, 2), &[1.0]);
+        assert!(parse_gain_map(&missing_gains).is_none());
+        let too_many_points =
+            gain_map_parameters([0, 0, 4, 4], (0, 1), (1, 1), (u32::MAX, u32::MAX), &[1.0]);
+        assert!(parse_gain_map(&too_many_points).is_none());
+        assert!(parse_gain_map(&[0; 10]).is_none());
+    }
+
+    #[test]
+    fn warp_rectilinear_moves_pixels_towards_the_center() {
+        // The red plane is magnified 2x around the middle, the others are left as they are
+        let identity = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let list = opcode_list(&[opcode(
+            OPCODE_WARP_RECTILINEAR,
+            0,
+            &warp_parameters(
+                &[[0.5, 0.0, 0.0, 0.0, 0.0, 0.0], identity, identity],
+                (0.5, 0.5),
+            ),
+        )]);
+        let opcodes = parse_image_opcodes(&list).unwrap();
+        let mut rgb = ramp(5);
+        opcodes[0].apply(&mut rgb, 5, (0, 0, 5, 1));
+        let red: Vec<f32> = rgb.iter().map(|pixel| pixel[0]).collect();
+        assert_eq!(red, [1.0, 1.5, 2.0, 2.5, 3.0]);
+        for (x, pixel) in rgb.iter().enumerate() {
+            assert_close(pixel[1], x as f32);
+            assert_close(pixel[2], x as f32);
+        }
+    }
+
+    #[test]
+    fn warp_rectilinear_with_one_plane_applies_to_all_colors() {
+        let list = opcode_list(&[opcode(
+            OPCODE_WARP_RECTILINEAR,
+            0,
+            &warp_parameters(&[[0.5, 0.0, 0.0, 0.0, 0.0, 0.0]], (0.5, 0.5)),
+        )]);
+        let opcodes = parse_image_opcodes(&list).unwrap();
+        let mut rgb = ramp(5);
+        opcodes[0].apply(&mut rgb, 5, (0, 0, 5, 1));
+        assert_eq!(rgb[4], [3.0; 3]);
+    }
+
+    #[test]
+    fn warp_rectilinear_needs_one_to_three_planes() {
+        for planes in [0, 4] {
+            let mut parameters = warp_parameters(&[[1.0; 6]; 4], (0.5, 0.5));
+            parameters[..4].copy_from_slice(&(planes as u32).to_be_bytes());
+            let list = opcode_list(&[opcode(OPCODE_WARP_RECTILINEAR, 0, &parameters)]);
+            assert!(parse_image_opcodes(&list).is_err(), "{planes} planes");
+        }
+    }
+
+    #[test]
+    fn fix_vignette_radial_brightens_towards_the_corners() {
+        let list = opcode_list(&[opcode(
+            OPCODE_FIX_VIGNETTE_RADIAL,
+            0,
+            &vignette_parameters([1.0, 0.0, 0.0, 0.0, 0.0], (0.5, 0.5)),
+        )]);
+        let opcodes = parse_image_opcodes(&list).unwrap();
+        let mut rgb = vec![[1.0; 3]; 5];
+        opcodes[0].apply(&mut rgb, 5, (0, 0, 5, 1));
+        // The gain is 1 + r², with r going from 0 in the middle to 1 at the ends
+        let gains: Vec<f32> = rgb.iter().map(|pixel| pixel[0]).collect();
+        assert_eq!(gains, [2.0, 1.25, 1.0, 1.25, 2.0]);
+    }
+
+    #[test]
+    fn image_opcodes_outside_the_image_are_ignored() {
+        let list = opcode_list(&[opcode(
+            OPCODE_FIX_VIGNETTE_RADIAL,
+            0,
+            &vignette_parameters([1.0, 0.0, 0.0, 0.0, 0.0], (0.5, 0.5)),
+        )]);
+        let opcodes = parse_image_opcodes(&list).unwrap();
+        let mut rgb = vec![[1.0; 3]; 5];
+        for area in [(3, 0, 5, 1), (0, 0, 5, 2), (0, 0, 0, 1), (0, 0, 5, 0)] {
+            opcodes[0].apply(&mut rgb, 5, area);
+        }
+        assert_eq!(rgb, [[1.0; 3]; 5]);
+    }
+
+    #[test]
+    fn unsupported_opcodes_are_skipped() {
+        let vignette = vignette_parameters([1.0, 0.0, 0.0, 0.0, 0.0], (0.5, 0.5));
+        let list = opcode_list(&[
+            opcode(4, OPCODE_FLAG_OPTIONAL, &[0; 12]),
+            opcode(OPCODE_FIX_VIGNETTE_RADIAL, 0, &vignette),
+            opcode(5, 0, &[]),
+        ]);
+        let opcodes = parse_image_opcodes(&list).unwrap();
+        assert_eq!(opcodes.len(), 1);
+        assert!(matches!(opcodes[0], ImageOpcode::FixVignetteRadial(_)));
+    }
+
+    #[test]
+    fn malformed_opcode_lists_are_errors() {
+        let vignette = vignette_parameters([1.0, 0.0, 0.0, 0.0, 0.0], (0.5, 0.5));
+        let list = opcode_list(&[opcode(OPCODE_FIX_VIGNETTE_RADIAL, 0, &vignette)]);
+
+        let more_opcodes_than_data = {
+            let mut list = list.clone();
+            list[..4].copy_from_slice(&2u32.to_be_bytes());
+            list
+        };
+        let truncated_header = list[..10].to_vec();
+        let truncated_parameters = list[..list.len() - 1].to_vec();
+        let huge_size = {
+            let mut list = list.clone();
+            list[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+            list
+        };
+        let short_parameters = opcode_list(&[opcode(OPCODE_FIX_VIGNETTE_RADIAL, 0, &[0; 40])]);
+        for (name, list) in [
+            ("empty", &[][..]),
+            ("more opcodes than data", &more_opcodes_than_data[..]),
+            ("truncated header", &truncated_header[..]),
+            ("truncated parameters", &truncated_parameters[..]),
+            ("huge size", &huge_size[..]),
+            ("short parameters", &short_parameters[..]),
+        ] {
+            assert!(parse_image_opcodes(list).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn is_dng_data_checks_for_the_dng_version() {
+        let dng = test_fixtures::small_dng();
+        assert!(is_dng_data(&dng));
+        // Only the start of the file is needed, up to the end of the first IFD
+        assert!(is_dng_data(&dng[..1024]));
+        assert!(!is_dng_data(&dng[..16]));
+        assert!(!is_dng_data(&test_fixtures::rgb_tiff("Canon", 4, 4)));
+        assert!(!is_dng_data(b"\xff\xd8\xff\xe0\0\x10JFIF\0"));
+        assert!(!is_dng_data(&[]));
+    }
+
+    #[test]
+    fn tiff_make_reads_the_make_tag() {
+        assert_eq!(
+            tiff_make(&test_fixtures::small_dng()).as_deref(),
+            Some("Canon")
+        );
+        let padded = test_fixtures::rgb_tiff("NIKON CORPORATION  ", 4, 4);
+        assert_eq!(tiff_make(&padded).as_deref(), Some("NIKON CORPORATION"));
+        // Short makes are stored in the entry itself rather than at an offset
+        assert_eq!(
+            tiff_make(&test_fixtures::rgb_tiff("DJI", 4, 4)).as_deref(),
+            Some("DJI")
+        );
+        let no_make = test_fixtures::TiffBuilder::default()
+            .longs(256, &[1])
+            .build(&[0]);
+        assert_eq!(tiff_make(&no_make), None);
+        // Data which ends before the first IFD does
+        let dng = test_fixtures::small_dng();
+        assert_eq!(tiff_make(&dng[..200]), None);
+        assert_eq!(tiff_make(b"not a tiff at all"), None);
+    }
+
+    #[test]
+    fn read_opcodes_finds_both_lists_of_a_dng() {
+        let gain_map = gain_map_parameters([0, 0, 32, 32], (0, 1), (1, 1), (1, 1), &[1.5]);
+        let vignette = vignette_parameters([0.5, 0.0, 0.0, 0.0, 0.0], (0.5, 0.5));
+        let dng = test_fixtures::bayer_dng(32, 32)
+            .undefined(
+                TAG_OPCODE_LIST_2,
+                &opcode_list(&[opcode(OPCODE_GAIN_MAP, 0, &gain_map)]),
+            )
+            .undefined(
+                TAG_OPCODE_LIST_3,
+                &opcode_list(&[opcode(OPCODE_FIX_VIGNETTE_RADIAL, 0, &vignette)]),
+            )
+            .build(&test_fixtures::bayer_dng_strip(32, 32));
+        let file = TempFile::new("opcodes.dng", &dng);
+        let opcodes = read_opcodes(&file.0).unwrap();
+        assert_eq!(opcodes.gain_maps.len(), 1);
+        assert_eq!(opcodes.image_opcodes.len(), 1);
+    }
+
+    #[test]
+    fn read_opcodes_of_other_files_is_empty() {
+        for (name, data) in [
+            ("plain.dng", test_fixtures::small_dng()),
+            ("plain.tif", test_fixtures::rgb_tiff("Canon", 4, 4)),
+            ("empty.dng", Vec::new()),
+        ] {
+            let file = TempFile::new(name, &data);
+            let opcodes = read_opcodes(&file.0).unwrap();
+            assert!(opcodes.gain_maps.is_empty() && opcodes.image_opcodes.is_empty());
+        }
+    }
+
+    #[test]
+    fn read_opcodes_with_a_broken_list_is_an_error() {
+        let dng = test_fixtures::bayer_dng(32, 32)
+            .undefined(TAG_OPCODE_LIST_3, &[0, 0, 0, 1, 0, 0])
+            .build(&test_fixtures::bayer_dng_strip(32, 32));
+        let file = TempFile::new("broken-opcodes.dng", &dng);
+        assert!(read_opcodes(&file.0).is_err());
+    }
+}
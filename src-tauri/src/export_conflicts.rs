@@ -0,0 +1,134 @@
+use crate::export_jobs::ExportJob;
+use crate::image_helpers::ExistingFileAction;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Emitted when an export file already exists and `ExistingFileAction::AskWhatToDo` is set.
+/// The frontend answers with the `resolve_file_conflict` command.
+pub const FILE_CONFLICT_EVENT: &str = "export-file-conflict";
+
+/// How often a worker waiting for an answer checks whether the export has been cancelled
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileConflict {
+    conflict_id: u32,
+    image_path: String,
+    export_file_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileConflictAnswer {
+    pub action: ExistingFileAction,
+    /// Use the same action for all remaining conflicts of the export
+    pub apply_to_all: bool,
+}
+
+/// Conflicts waiting for an answer from the frontend, managed as tauri state
+#[derive(Default)]
+pub struct PendingFileConflicts {
+    next_id: AtomicU32,
+    senders: Mutex<HashMap<u32, Sender<FileConflictAnswer>>>,
+}
+impl PendingFileConflicts {
+    fn remove(&self, conflict_id: u32) {
+        self.senders.lock().unwrap().remove(&conflict_id);
+    }
+
+    pub fn answer(&self, conflict_id: u32, answer: FileConflictAnswer) -> Result<(), String> {
+        let sender = self.senders.lock().unwrap().remove(&conflict_id);
+        match sender {
+            Some(sender) => sender
+                .send(answer)
+                .map_err(|_| format!("Export for file conflict {conflict_id} has stopped")),
+            None => Err(format!("No pending file conflict with id {conflict_id}")),
+        }
+    }
+}
+
+/// Asks the frontend what to do with existing export files for one export batch
+pub struct FileConflictPrompt {
+    app: AppHandle,
+    job: Arc<ExportJob>,
+    remembered_action: Mutex<Option<ExistingFileAction>>,
+}
+impl FileConflictPrompt {
+    pub fn new(app: AppHandle, job: Arc<ExportJob>) -> Self {
+        FileConflictPrompt {
+            app,
+            job,
+            remembered_action: Mutex::new(None),
+        }
+    }
+
+    /// Blocks until the frontend has answered, and breaks if the export is cancelled in the
+    /// meantime. The rest of the batch is held at its next checkpoint until then. Never returns
+    /// `ExistingFileAction::AskWhatToDo`
+    pub fn ask(
+        &self,
+        image_path: &Path,
+        export_file_path: &Path,
+    ) -> ControlFlow<(), ExistingFileAction> {
+        // Holding the lock while waiting makes sure the user gets one question at a time
+        let mut remembered_action = self.remembered_action.lock().unwrap();
+        if self.job.is_cancelled() {
+            return ControlFlow::Break(());
+        }
+        if let Some(action) = *remembered_action {
+            return ControlFlow::Continue(action);
+        }
+
+        let _hold = self.job.hold_for_prompt();
+        let pending_conflicts = self.app.state::<PendingFileConflicts>();
+        let conflict_id = pending_conflicts.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = channel();
+        pending_conflicts
+            .senders
+            .lock()
+            .unwrap()
+            .insert(conflict_id, sender);
+
+        let conflict = FileConflict {
+            conflict_id,
+            image_path: image_path.to_string_lossy().to_string(),
+            export_file_path: export_file_path.to_string_lossy().to_string(),
+        };
+        if let Err(e) = self.app.emit(FILE_CONFLICT_EVENT, conflict) {
+            warn!("Error asking what to do with existing file {export_file_path:?} {e:?}");
+            pending_conflicts.remove(conflict_id);
+            return ControlFlow::Continue(ExistingFileAction::Skip);
+        }
+
+        let answer = loop {
+            match receiver.recv_timeout(CANCEL_POLL_INTERVAL) {
+                Ok(answer) => break answer,
+                Err(RecvTimeoutError::Timeout) if self.job.is_cancelled() => {
+                    pending_conflicts.remove(conflict_id);
+                    return ControlFlow::Break(());
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return ControlFlow::Continue(ExistingFileAction::Skip)
+                }
+            }
+        };
+        let action = match answer.action {
+            ExistingFileAction::AskWhatToDo => ExistingFileAction::Skip,
+            action => action,
+        };
+        if answer.apply_to_all {
+            *remembered_action = Some(action);
+        }
+        ControlFlow::Continue(action)
+    }
+}
@@ -0,0 +1,195 @@
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::error::Error;
+use std::fmt;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// The library an image was decoded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecodeBackend {
+    Image,
+    Libraw,
+    Rawler,
+    Libheif,
+}
+impl fmt::Display for DecodeBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DecodeBackend::Image => "image",
+            DecodeBackend::Libraw => "libraw",
+            DecodeBackend::Rawler => "rawler",
+            DecodeBackend::Libheif => "libheif",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Machine readable reason for an image failing to export
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportErrorCode {
+    ReadFailed,
+    DecodeFailed,
+    UnsupportedFormat,
+    ResizeFailed,
+    ColorConversionFailed,
+    EncodeFailed,
+    WriteFailed,
+    PermissionDenied,
+    InvalidSettings,
+    Internal,
+}
+
+/// Everything that can go wrong while loading, processing or exporting an image. The underlying
+/// error is kept as the source, and the whole chain is sent along when serialized for the
+/// frontend.
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    /// Reading the source file failed before it got to a decoder
+    #[error("{context}: {source}")]
+    Read {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Error decoding image with {backend}: {source}")]
+    Decode {
+        backend: DecodeBackend,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Unsupported image format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Error resizing image: {source}")]
+    Resize {
+        #[source]
+        source: BoxError,
+    },
+    #[error("Error converting colors: {source}")]
+    ColorConversion {
+        #[source]
+        source: BoxError,
+    },
+    #[error("Error encoding {format} image: {source}")]
+    Encode {
+        format: &'static str,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Invalid settings: {0}")]
+    InvalidSettings(String),
+    /// A decoder or encoder panicked. The panic is caught so the rest of the batch still exports
+    #[error("Unexpected error: {0}")]
+    Panicked(String),
+}
+impl ExportError {
+    pub fn read(context: impl Into<String>, source: std::io::Error) -> Self {
+        ExportError::Read {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        ExportError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn decode(backend: DecodeBackend, source: impl Into<BoxError>) -> Self {
+        ExportError::Decode {
+            backend,
+            source: source.into(),
+        }
+    }
+
+    pub fn resize(source: impl Into<BoxError>) -> Self {
+        ExportError::Resize {
+            source: source.into(),
+        }
+    }
+
+    pub fn color_conversion(source: impl Into<BoxError>) -> Self {
+        ExportError::ColorConversion {
+            source: source.into(),
+        }
+    }
+
+    pub fn encode(format: &'static str, source: impl Into<BoxError>) -> Self {
+        ExportError::Encode {
+            format,
+            source: source.into(),
+        }
+    }
+
+    /// Whether an io error somewhere in the chain was caused by missing permissions
+    fn is_permission_denied(&self) -> bool {
+        let mut source: Option<&(dyn Error + 'static)> = Some(self);
+        while let Some(error) = source {
+            let io_error = error
+                .downcast_ref::<std::io::Error>()
+                .or_else(|| match error.downcast_ref::<image::ImageError>() {
+                    Some(image::ImageError::IoError(io_error)) => Some(io_error),
+                    _ => None,
+                });
+            if io_error.is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied) {
+                return true;
+            }
+            source = error.source();
+        }
+        false
+    }
+
+    pub fn code(&self) -> ExportErrorCode {
+        if self.is_permission_denied() {
+            return ExportErrorCode::PermissionDenied;
+        }
+        match self {
+            ExportError::Read { .. } => ExportErrorCode::ReadFailed,
+            // Source files are read through their decoders or `ExportError::Read`, so the
+            // remaining io errors come from writing the export
+            ExportError::Io { .. } => ExportErrorCode::WriteFailed,
+            ExportError::Decode { .. } => ExportErrorCode::DecodeFailed,
+            ExportError::UnsupportedFormat(_) => ExportErrorCode::UnsupportedFormat,
+            ExportError::Resize { .. } => ExportErrorCode::ResizeFailed,
+            ExportError::ColorConversion { .. } => ExportErrorCode::ColorConversionFailed,
+            ExportError::Encode { .. } => ExportErrorCode::EncodeFailed,
+            ExportError::InvalidSettings(_) => ExportErrorCode::InvalidSettings,
+            ExportError::Panicked(_) => ExportErrorCode::Internal,
+        }
+    }
+
+    /// Messages of the errors which caused this one, outermost first
+    fn causes(&self) -> Vec<String> {
+        let mut causes = Vec::new();
+        let mut source = self.source();
+        while let Some(error) = source {
+            causes.push(error.to_string());
+            source = error.source();
+        }
+        causes
+    }
+}
+impl Serialize for ExportError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let backend = match self {
+            ExportError::Decode { backend, .. } => Some(*backend),
+            _ => None,
+        };
+        let mut state = serializer.serialize_struct("ExportError", 4)?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("backend", &backend)?;
+        state.serialize_field("causes", &self.causes())?;
+        state.end()
+    }
+}
@@ -0,0 +1,187 @@
+use log::info;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+struct JobStatus {
+    state: JobState,
+    /// Questions to the user which are waiting for an answer. The job is held while there are
+    /// any, apart from the workers asking them.
+    pending_prompts: usize,
+}
+impl JobStatus {
+    fn is_held(&self) -> bool {
+        match self.state {
+            JobState::Running => self.pending_prompts > 0,
+            JobState::Paused => true,
+            JobState::Cancelled => false,
+        }
+    }
+}
+
+/// Lets a running export be paused, resumed or cancelled from other commands
+pub struct ExportJob {
+    status: Mutex<JobStatus>,
+    status_changed: Condvar,
+}
+impl ExportJob {
+    fn new() -> Self {
+        ExportJob {
+            status: Mutex::new(JobStatus {
+                state: JobState::Running,
+                pending_prompts: 0,
+            }),
+            status_changed: Condvar::new(),
+        }
+    }
+
+    fn set_state(&self, new_state: JobState) {
+        let mut status = self.status.lock().unwrap();
+        // A cancelled job can't be resumed
+        if status.state != JobState::Cancelled {
+            status.state = new_state;
+            self.status_changed.notify_all();
+        }
+    }
+
+    /// Whether the job has been cancelled. Unlike `checkpoint` this doesn't wait while paused
+    pub fn is_cancelled(&self) -> bool {
+        self.status.lock().unwrap().state == JobState::Cancelled
+    }
+
+    /// Called by the export workers between steps. Blocks while the job is paused or the user
+    /// is being asked something, and breaks once it has been cancelled.
+    pub fn checkpoint(&self) -> ControlFlow<()> {
+        let mut status = self.status.lock().unwrap();
+        while status.is_held() {
+            status = self.status_changed.wait(status).unwrap();
+        }
+        if status.state == JobState::Cancelled {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+
+    /// Holds the other workers at their next checkpoint until the returned guard is dropped,
+    /// so the batch doesn't run on while the user decides something that affects it
+    pub fn hold_for_prompt(&self) -> PromptHold<'_> {
+        self.status.lock().unwrap().pending_prompts += 1;
+        PromptHold { job: self }
+    }
+}
+
+/// Keeps an `ExportJob` held while a question to the user is pending
+pub struct PromptHold<'a> {
+    job: &'a ExportJob,
+}
+impl Drop for PromptHold<'_> {
+    fn drop(&mut self) {
+        self.job.status.lock().unwrap().pending_prompts -= 1;
+        self.job.status_changed.notify_all();
+    }
+}
+
+/// Export jobs which are still running, managed as tauri state
+#[derive(Default)]
+pub struct ExportJobs {
+    next_id: AtomicU32,
+    jobs: Mutex<HashMap<u32, Arc<ExportJob>>>,
+}
+impl ExportJobs {
+    pub fn start(&self) -> (u32, Arc<ExportJob>) {
+        let job_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let job = Arc::new(ExportJob::new());
+        self.jobs.lock().unwrap().insert(job_id, job.clone());
+        (job_id, job)
+    }
+
+    pub fn finish(&self, job_id: u32) {
+        self.jobs.lock().unwrap().remove(&job_id);
+    }
+
+    fn job(&self, job_id: u32) -> Result<Arc<ExportJob>, String> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(&job_id)
+            .cloned()
+            .ok_or_else(|| format!("No running export job with id {job_id}"))
+    }
+
+    pub fn cancel(&self, job_id: u32) -> Result<(), String> {
+        info!("Cancelling export job {job_id}");
+        self.job(job_id)?.set_state(JobState::Cancelled);
+        Ok(())
+    }
+
+    pub fn pause(&self, job_id: u32) -> Result<(), String> {
+        info!("Pausing export job {job_id}");
+        self.job(job_id)?.set_state(JobState::Paused);
+        Ok(())
+    }
+
+    pub fn resume(&self, job_id: u32) -> Result<(), String> {
+        info!("Resuming export job {job_id}");
+        self.job(job_id)?.set_state(JobState::Running);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn checkpoint_waits_while_a_prompt_is_pending() {
+        let job = &ExportJob::new();
+        let hold = job.hold_for_prompt();
+        let (sender, receiver) = channel();
+        thread::scope(|scope| {
+            scope.spawn(move || sender.send(job.checkpoint()).unwrap());
+            assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+            drop(hold);
+            assert_eq!(
+                receiver.recv_timeout(Duration::from_secs(5)),
+                Ok(ControlFlow::Continue(()))
+            );
+        });
+    }
+
+    #[test]
+    fn cancelling_releases_a_job_held_for_a_prompt() {
+        let job = &ExportJob::new();
+        let _hold = job.hold_for_prompt();
+        let (sender, receiver) = channel();
+        thread::scope(|scope| {
+            scope.spawn(move || sender.send(job.checkpoint()).unwrap());
+            job.set_state(JobState::Cancelled);
+            assert_eq!(
+                receiver.recv_timeout(Duration::from_secs(5)),
+                Ok(ControlFlow::Break(()))
+            );
+        });
+    }
+
+    #[test]
+    fn answering_a_prompt_doesnt_resume_a_paused_job() {
+        let job = ExportJob::new();
+        let hold = job.hold_for_prompt();
+        job.set_state(JobState::Paused);
+        drop(hold);
+        assert!(job.status.lock().unwrap().is_held());
+        job.set_state(JobState::Running);
+        assert_eq!(job.checkpoint(), ControlFlow::Continue(()));
+    }
+}
@@ -0,0 +1,476 @@
+use crate::image_helpers::{
+    is_raw_image, FileRenameExtensionCase, FileRenameToOption, FileRenaming,
+};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, NaiveDateTime};
+use log::warn;
+use std::path::Path;
+
+const DEFAULT_DATE_FORMAT: &str = "%Y%m%d";
+
+/// A piece of a file name template like `{name}_{seq:04}_{date:%Y%m%d}_{camera}`
+#[derive(Debug, PartialEq)]
+enum TemplateToken {
+    Literal(String),
+    /// File name of the source image without the extension
+    Name,
+    /// Sequence number of the image in the export batch, zero padded to `width` digits
+    Sequence {
+        width: usize,
+    },
+    /// Capture date of the image, or the file modified date if there is no capture date
+    Date {
+        format: String,
+    },
+    /// Camera make and model
+    Camera,
+    /// `FileRenaming.custom_name`
+    Custom,
+}
+
+/// Parse a file name template. Tokens are written as `{token}` or `{token:argument}`. `{{` and
+/// `}}` are a literal `{` and `}`.
+fn parse_template(template: &str) -> Result<Vec<TemplateToken>, String> {
+    let mut tokens = vec![];
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '}' => return Err(format!("Unmatched '}}' in file name template {template:?}")),
+            '{' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => token.push(c),
+                        None => {
+                            return Err(format!("Unclosed '{{' in file name template {template:?}"))
+                        }
+                    }
+                }
+                if !literal.is_empty() {
+                    tokens.push(TemplateToken::Literal(std::mem::take(&mut literal)));
+                }
+                tokens.push(parse_token(&token)?);
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        tokens.push(TemplateToken::Literal(literal));
+    }
+
+    Ok(tokens)
+}
+
+fn parse_token(token: &str) -> Result<TemplateToken, String> {
+    let (name, argument) = match token.split_once(':') {
+        Some((name, argument)) => (name.trim(), Some(argument)),
+        None => (token.trim(), None),
+    };
+
+    match (name, argument) {
+        ("name", None) => Ok(TemplateToken::Name),
+        ("camera", None) => Ok(TemplateToken::Camera),
+        ("custom", None) => Ok(TemplateToken::Custom),
+        ("seq", None) => Ok(TemplateToken::Sequence { width: 0 }),
+        ("seq", Some(width)) => match width.trim().parse::<usize>() {
+            Ok(width) if width <= 10 => Ok(TemplateToken::Sequence { width }),
+            _ => Err(format!("Invalid sequence width {width:?} in {{{token}}}")),
+        },
+        ("date", None) => Ok(TemplateToken::Date {
+            format: DEFAULT_DATE_FORMAT.to_string(),
+        }),
+        ("date", Some(format)) => {
+            // chrono panics while formatting with an invalid format string, so validate it here
+            if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+                Err(format!("Invalid date format {format:?} in {{{token}}}"))
+            } else {
+                Ok(TemplateToken::Date {
+                    format: format.to_string(),
+                })
+            }
+        }
+        _ => Err(format!("Unknown file name token {{{token}}}")),
+    }
+}
+
+/// The template each `FileRenameToOption` is a shorthand for
+fn template_for_option(file_renaming: &FileRenaming) -> &str {
+    match file_renaming.rename_to {
+        FileRenameToOption::OriginalName => "{name}",
+        FileRenameToOption::FilenameSequence => "{name}-{seq}",
+        FileRenameToOption::DateFilename => "{date}-{name}",
+        FileRenameToOption::CustomName => "{custom}",
+        FileRenameToOption::CustomNameSequence => "{custom}-{seq}",
+        FileRenameToOption::Template => &file_renaming.template,
+    }
+}
+
+/// Details read from the image metadata. Only read when the template needs them
+#[derive(Debug, Default)]
+struct CaptureInfo {
+    date: Option<NaiveDateTime>,
+    camera: Option<String>,
+}
+
+fn camera_name(make: &str, model: &str) -> Option<String> {
+    let make = make.trim();
+    let model = model.trim();
+    // Most cameras repeat the make in the model, e.g. "Canon" and "Canon EOS R5"
+    let camera = if make.is_empty() || model.to_lowercase().starts_with(&make.to_lowercase()) {
+        model.to_string()
+    } else if model.is_empty() {
+        make.to_string()
+    } else {
+        format!("{make} {model}")
+    };
+
+    if camera.is_empty() {
+        None
+    } else {
+        Some(camera)
+    }
+}
+
+fn parse_exif_date(date: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(date.trim(), "%Y:%m:%d %H:%M:%S").ok()
+}
+
+fn read_raw_capture_info(
+    path: &Path,
+) -> Result<CaptureInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let raw_source = rawler::rawsource::RawSource::new(path)?;
+    let decoder = rawler::get_decoder(&raw_source)?;
+    let params = rawler::decoders::RawDecodeParams::default();
+    let metadata = decoder.raw_metadata(&raw_source, &params)?;
+
+    Ok(CaptureInfo {
+        date: metadata
+            .exif
+            .date_time_original
+            .as_deref()
+            .and_then(parse_exif_date),
+        camera: camera_name(&metadata.make, &metadata.model),
+    })
+}
+
+fn read_exif_capture_info(
+    path: &Path,
+) -> Result<CaptureInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let file = std::fs::File::open(path)?;
+    let mut reader = std::io::BufReader::new(file);
+    let exif = exif::Reader::new().read_from_container(&mut reader)?;
+
+    let ascii_field = |tag: exif::Tag| -> Option<String> {
+        match exif
+            .get_field(tag, exif::In::PRIMARY)
+            .map(|field| &field.value)
+        {
+            Some(exif::Value::Ascii(values)) => values.first().map(|value| {
+                String::from_utf8_lossy(value)
+                    .trim_end_matches('\0')
+                    .to_string()
+            }),
+            _ => None,
+        }
+    };
+
+    Ok(CaptureInfo {
+        date: ascii_field(exif::Tag::DateTimeOriginal)
+            .or_else(|| ascii_field(exif::Tag::DateTime))
+            .as_deref()
+            .and_then(parse_exif_date),
+        camera: camera_name(
+            &ascii_field(exif::Tag::Make).unwrap_or_default(),
+            &ascii_field(exif::Tag::Model).unwrap_or_default(),
+        ),
+    })
+}
+
+fn read_capture_info(path: &Path) -> CaptureInfo {
+    let capture_info = if is_raw_image(path) {
+        read_raw_capture_info(path)
+    } else {
+        read_exif_capture_info(path)
+    };
+    let mut capture_info = capture_info.unwrap_or_else(|e| {
+        warn!("Error reading capture info from {path:?} {e:?}");
+        CaptureInfo::default()
+    });
+
+    if capture_info.date.is_none() {
+        capture_info.date = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .map(|modified| DateTime::<Local>::from(modified).naive_local());
+    }
+    capture_info
+}
+
+/// Replace characters which are not allowed in file names on any of the platforms we support
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows does not like file names ending with dots or spaces
+    name.trim_end_matches(|c| c == '.' || c == ' ').to_string()
+}
+
+/// Build the file name (with extension) an image is exported as.
+/// `sequence_index` is the position of the image in the export batch, starting at 0.
+pub fn export_file_name(
+    image_path: &Path,
+    sequence_index: usize,
+    extension: &str,
+    file_renaming: &FileRenaming,
+) -> Result<String, String> {
+    let source_name = image_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let file_name = if file_renaming.enable_renaming {
+        let tokens = parse_template(template_for_option(file_renaming))?;
+        let needs_capture_info = tokens
+            .iter()
+            .any(|token| matches!(token, TemplateToken::Date { .. } | TemplateToken::Camera));
+        let capture_info = if needs_capture_info {
+            read_capture_info(image_path)
+        } else {
+            CaptureInfo::default()
+        };
+        let sequence_number = file_renaming.start_number.unwrap_or(1) as usize + sequence_index;
+
+        let mut file_name = String::new();
+        for token in tokens.iter() {
+            match token {
+                TemplateToken::Literal(literal) => file_name.push_str(literal),
+                TemplateToken::Name => file_name.push_str(&source_name),
+                TemplateToken::Sequence { width } => {
+                    file_name.push_str(&format!("{sequence_number:0width$}"))
+                }
+                TemplateToken::Date { format } => {
+                    if let Some(date) = capture_info.date {
+                        file_name.push_str(&date.format(format).to_string());
+                    }
+                }
+                TemplateToken::Camera => {
+                    file_name.push_str(capture_info.camera.as_deref().unwrap_or("Unknown"))
+                }
+                TemplateToken::Custom => file_name.push_str(&file_renaming.custom_name),
+            }
+        }
+        sanitize_file_name(&file_name)
+    } else {
+        sanitize_file_name(&format!("{source_name}_exported"))
+    };
+
+    if file_name.is_empty() {
+        return Err(format!("Export file name for {image_path:?} is empty"));
+    }
+
+    let extension = match file_renaming.file_extensions_case {
+        FileRenameExtensionCase::Lower => extension.to_lowercase(),
+        FileRenameExtensionCase::Upper => extension.to_uppercase(),
+    };
+    Ok(format!("{file_name}.{extension}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template_renaming(template: &str) -> FileRenaming {
+        FileRenaming {
+            enable_renaming: true,
+            rename_to: FileRenameToOption::Template,
+            template: template.to_string(),
+            ..FileRenaming::default()
+        }
+    }
+
+    #[test]
+    fn parse_template_reads_tokens_and_literals() {
+        assert_eq!(
+            parse_template("{name}_{seq:04}-{ custom }").unwrap(),
+            vec![
+                TemplateToken::Name,
+                TemplateToken::Literal("_".to_string()),
+                TemplateToken::Sequence { width: 4 },
+                TemplateToken::Literal("-".to_string()),
+                TemplateToken::Custom,
+            ]
+        );
+        assert_eq!(
+            parse_template("{date}{camera}").unwrap(),
+            vec![
+                TemplateToken::Date {
+                    format: DEFAULT_DATE_FORMAT.to_string()
+                },
+                TemplateToken::Camera,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_template_unescapes_braces() {
+        assert_eq!(
+            parse_template("{{a}}{name}}}").unwrap(),
+            vec![
+                TemplateToken::Literal("{a}".to_string()),
+                TemplateToken::Name,
+                TemplateToken::Literal("}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_template_rejects_unmatched_braces() {
+        assert!(parse_template("{name").is_err());
+        assert!(parse_template("name}").is_err());
+    }
+
+    #[test]
+    fn parse_template_rejects_unknown_tokens() {
+        assert!(parse_template("{title}").is_err());
+        assert!(parse_template("{name:upper}").is_err());
+        assert!(parse_template("{}").is_err());
+    }
+
+    #[test]
+    fn parse_template_limits_sequence_width() {
+        assert_eq!(
+            parse_template("{seq:10}").unwrap(),
+            vec![TemplateToken::Sequence { width: 10 }]
+        );
+        assert!(parse_template("{seq:11}").is_err());
+        assert!(parse_template("{seq:-1}").is_err());
+        assert!(parse_template("{seq:four}").is_err());
+    }
+
+    #[test]
+    fn parse_template_rejects_invalid_date_formats() {
+        assert_eq!(
+            parse_template("{date:%Y-%m-%d}").unwrap(),
+            vec![TemplateToken::Date {
+                format: "%Y-%m-%d".to_string()
+            }]
+        );
+        assert!(parse_template("{date:%Q}").is_err());
+        assert!(parse_template("{date:%Y%}").is_err());
+    }
+
+    #[test]
+    fn sanitize_file_name_replaces_reserved_characters() {
+        assert_eq!(
+            sanitize_file_name("a/b\\c:d*e?f\"g<h>i|j"),
+            "a_b_c_d_e_f_g_h_i_j"
+        );
+        assert_eq!(sanitize_file_name("tab\there"), "tab_here");
+    }
+
+    #[test]
+    fn sanitize_file_name_trims_trailing_dots_and_spaces() {
+        assert_eq!(sanitize_file_name("photo. . "), "photo");
+        assert_eq!(sanitize_file_name(" .photo"), " .photo");
+        assert_eq!(sanitize_file_name("..."), "");
+    }
+
+    #[test]
+    fn camera_name_skips_repeated_make() {
+        assert_eq!(
+            camera_name("Canon", "Canon EOS R5").as_deref(),
+            Some("Canon EOS R5")
+        );
+        assert_eq!(
+            camera_name(" FUJIFILM ", "fujifilm X-T5").as_deref(),
+            Some("fujifilm X-T5")
+        );
+        assert_eq!(
+            camera_name("SONY", "ILCE-7M4").as_deref(),
+            Some("SONY ILCE-7M4")
+        );
+    }
+
+    #[test]
+    fn camera_name_uses_whichever_part_is_present() {
+        assert_eq!(camera_name("", "X100V").as_deref(), Some("X100V"));
+        assert_eq!(camera_name("Leica", "").as_deref(), Some("Leica"));
+        assert_eq!(camera_name(" ", ""), None);
+    }
+
+    #[test]
+    fn export_file_name_pads_sequence_numbers() {
+        let path = Path::new("/photos/IMG_0001.CR2");
+        let renaming = template_renaming("{name}_{seq:04}");
+        assert_eq!(
+            export_file_name(path, 0, "jpg", &renaming).unwrap(),
+            "IMG_0001_0001.jpg"
+        );
+        assert_eq!(
+            export_file_name(path, 41, "jpg", &renaming).unwrap(),
+            "IMG_0001_0042.jpg"
+        );
+        // Numbers wider than the padding are kept whole
+        let renaming = template_renaming("{seq:2}");
+        assert_eq!(
+            export_file_name(path, 122, "jpg", &renaming).unwrap(),
+            "123.jpg"
+        );
+    }
+
+    #[test]
+    fn export_file_name_counts_from_start_number() {
+        let path = Path::new("/photos/IMG_0001.CR2");
+        let renaming = FileRenaming {
+            enable_renaming: true,
+            rename_to: FileRenameToOption::CustomNameSequence,
+            custom_name: "trip".to_string(),
+            start_number: Some(100),
+            ..FileRenaming::default()
+        };
+        assert_eq!(
+            export_file_name(path, 0, "jpg", &renaming).unwrap(),
+            "trip-100.jpg"
+        );
+        assert_eq!(
+            export_file_name(path, 5, "jpg", &renaming).unwrap(),
+            "trip-105.jpg"
+        );
+    }
+
+    #[test]
+    fn export_file_name_applies_extension_case() {
+        let path = Path::new("/photos/IMG_0001.CR2");
+        let renaming = FileRenaming {
+            file_extensions_case: FileRenameExtensionCase::Upper,
+            ..FileRenaming::default()
+        };
+        assert_eq!(
+            export_file_name(path, 0, "jpg", &renaming).unwrap(),
+            "IMG_0001_exported.JPG"
+        );
+    }
+
+    #[test]
+    fn export_file_name_rejects_empty_names() {
+        let path = Path::new("/photos/IMG_0001.CR2");
+        let renaming = template_renaming("{custom}");
+        assert!(export_file_name(path, 0, "jpg", &renaming).is_err());
+    }
+}
@@ -1,65 +1,77 @@
+use crate::color_management;
+use crate::containers::{self, EmbeddedMetadata};
+use crate::decoders;
+use crate::export_error::{BoxError, DecodeBackend, ExportError};
+use crate::file_naming;
+use crate::jpeg_xl_encoder;
+use crate::libraw_processor::LibRaw;
+use crate::metadata::{self, SourceContainer};
+use crate::raw_develop::{self, RawDevelopOptions};
 use base64::{engine::general_purpose, Engine as _};
-use fast_image_resize::{
-    DifferentTypesOfPixelsError, Image as FirImage, ImageBufferError, MulDivImageError,
-    MulDivImagesError, PixelType, ResizeAlg, Resizer,
+use fast_image_resize::{FilterType, Image as FirImage, MulDiv, PixelType, ResizeAlg, Resizer};
+use image::{DynamicImage, ImageBuffer, ImageDecoder, ImageEncoder, ImageFormat};
+use libheif_rs::{
+    color_profile_types, Channel as HeifChannel, ColorProfileRaw, ColorSpace as HeifColorSpace,
+    CompressionFormat, EncoderParameterValue, EncoderQuality, HeifContext, Image as HeifImage,
+    LibHeif, RgbChroma,
 };
-use image::{DynamicImage, ImageBuffer, ImageFormat, Rgba};
-use libheif_rs::{ColorSpace as HeifColorSpace, HeifContext, LibHeif, RgbChroma};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use std::ffi::OsStr;
+use std::collections::HashSet;
 use std::fmt;
-use std::ops::Deref;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::ops::ControlFlow;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
 use std::{
-    cmp::max,
+    cmp::{max, min},
     io::{Cursor, Read},
     num::NonZeroU32,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum ExistingFileAction {
+    #[default]
     AskWhatToDo,
     ChooseNewName,
     OverwriteWithoutWarning,
     Skip,
 }
 
-/// We do not really care about the underlying error, so wrap all fast_image_resize errors to a single type
-#[derive(Debug)]
-pub enum ResizeImageError {
-    Error,
-}
-impl From<ImageBufferError> for ResizeImageError {
-    fn from(_: ImageBufferError) -> Self {
-        ResizeImageError::Error
-    }
-}
-
-impl From<MulDivImageError> for ResizeImageError {
-    fn from(_: MulDivImageError) -> Self {
-        ResizeImageError::Error
-    }
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportLocation {
+    pub folder_path: String,
+    #[serde(default)]
+    pub existing_file_action: ExistingFileAction,
 }
 
-impl From<MulDivImagesError> for ResizeImageError {
-    fn from(_: MulDivImagesError) -> Self {
-        ResizeImageError::Error
-    }
+/// Steps of exporting an image, reported as each of them finishes. The export stops there if
+/// the report breaks.
+#[derive(Debug, Clone, Copy)]
+pub enum ExportStage {
+    Decoded,
+    Resized,
+    Encoded,
 }
 
-impl From<DifferentTypesOfPixelsError> for ResizeImageError {
-    fn from(_: DifferentTypesOfPixelsError) -> Self {
-        ResizeImageError::Error
-    }
-}
+/// Export paths picked by the images of the current batch. Images are exported in parallel, so
+/// a path has to count as taken before its file is written.
+#[derive(Default)]
+pub struct ExportPathClaims(Mutex<HashSet<PathBuf>>);
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct ExportLocation {
-    pub folder_path: String,
+/// What happened to an image which was exported without errors
+#[derive(Debug)]
+pub enum ExportOutcome {
+    Exported(PathBuf),
+    /// The image was exported at its own size, which is smaller than the requested size, because
+    /// `ImageSizing::enlarge` is off
+    ExportedSmallerThanTarget(PathBuf),
+    /// The export file already existed and `ExistingFileAction::Skip` was chosen for it
+    Skipped(PathBuf),
+    /// The export job was cancelled before the image was written
+    Cancelled,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -70,11 +82,14 @@ pub enum FileRenameToOption {
     DateFilename,
     CustomName,
     CustomNameSequence,
+    /// Build the name from `FileRenaming.template`, e.g. `{name}_{seq:04}_{date:%Y%m%d}_{camera}`
+    Template,
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum FileRenameExtensionCase {
+    #[default]
     Lower,
     Upper,
 }
@@ -87,42 +102,107 @@ pub struct FileRenaming {
     pub custom_name: String,
     pub start_number: Option<u32>,
     pub file_extensions_case: FileRenameExtensionCase,
+    #[serde(default)]
+    pub template: String,
+}
+impl Default for FileRenaming {
+    fn default() -> Self {
+        FileRenaming {
+            enable_renaming: false,
+            rename_to: FileRenameToOption::OriginalName,
+            custom_name: String::new(),
+            start_number: None,
+            file_extensions_case: FileRenameExtensionCase::Lower,
+            template: String::new(),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum ExportImageFormat {
     Jpeg,
     Png,
-    // JpegXl,
-    // Avif,
+    JpegXl,
+    Avif,
     // Psd,
-    // Tiff,
-    // Dng,
-    // Webp,
-    // Heic,
+    Tiff,
+    /// Only for raw sources, which are converted without being developed
+    Dng,
+    Webp,
+    Heic,
     // Heif,
+    /// Write each image in the format it was read from
+    Original,
 }
 impl fmt::Display for ExportImageFormat {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ExportImageFormat::Jpeg => write!(f, "JPEG"),
             ExportImageFormat::Png => write!(f, "PNG"),
-            // ExportImageFormat::JpegXl => write!(f, "JPEG XL"),
-            // ExportImageFormat::Avif => write!(f, "AVIF"),
+            ExportImageFormat::JpegXl => write!(f, "JPEG XL"),
+            ExportImageFormat::Avif => write!(f, "AVIF"),
             // ExportImageFormat::Psd => write!(f, "PSD"),
-            // ExportImageFormat::Tiff => write!(f, "TIFF"),
-            // ExportImageFormat::Dng => write!(f, "DNG"),
-            // ExportImageFormat::Webp => write!(f, "WebP"),
-            // ExportImageFormat::Heic => write!(f, "HEIC"),
+            ExportImageFormat::Tiff => write!(f, "TIFF"),
+            ExportImageFormat::Dng => write!(f, "DNG"),
+            ExportImageFormat::Webp => write!(f, "WebP"),
+            ExportImageFormat::Heic => write!(f, "HEIC"),
             // ExportImageFormat::Heif => write!(f, "HEIF"),
+            ExportImageFormat::Original => write!(f, "Original"),
         }
     }
 }
+impl ExportImageFormat {
+    /// File extension used for exported files. `None` for `Original`, whose files keep the
+    /// extension of the format they are resolved to
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            ExportImageFormat::Jpeg => Some("jpg"),
+            ExportImageFormat::Png => Some("png"),
+            ExportImageFormat::JpegXl => Some("jxl"),
+            ExportImageFormat::Avif => Some("avif"),
+            ExportImageFormat::Tiff => Some("tif"),
+            ExportImageFormat::Dng => Some("dng"),
+            ExportImageFormat::Webp => Some("webp"),
+            ExportImageFormat::Heic => Some("heic"),
+            ExportImageFormat::Original => None,
+        }
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
+    /// `FileSettings` fields which apply when exporting to the format
+    pub fn options(&self) -> &'static [&'static str] {
+        match self {
+            ExportImageFormat::Jpeg => &["quality"],
+            ExportImageFormat::Png => &[],
+            ExportImageFormat::JpegXl => &["quality", "jpegXl"],
+            ExportImageFormat::Avif => &["quality", "avif"],
+            ExportImageFormat::Tiff => &["tiff"],
+            ExportImageFormat::Dng => &["dng"],
+            ExportImageFormat::Webp => &["quality", "webp"],
+            ExportImageFormat::Heic => &["quality", "heic"],
+            ExportImageFormat::Original => &["originalFallbackFormat"],
+        }
+    }
+
+    /// Find the format we can write for a source file extension, if any
+    pub fn from_extension(extension: &str) -> Option<ExportImageFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" | "jpe" => Some(ExportImageFormat::Jpeg),
+            "png" => Some(ExportImageFormat::Png),
+            "jxl" => Some(ExportImageFormat::JpegXl),
+            "avif" => Some(ExportImageFormat::Avif),
+            "tif" | "tiff" => Some(ExportImageFormat::Tiff),
+            "webp" => Some(ExportImageFormat::Webp),
+            "heic" | "heif" => Some(ExportImageFormat::Heic),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ColorSpace {
+    #[default]
     Srgb,
     DisplayP3,
     AdobeRgb,
@@ -130,11 +210,180 @@ pub enum ColorSpace {
     Rec2020,
 }
 
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebpSettings {
+    /// Use VP8L lossless encoding. `FileSettings.quality` is ignored when this is set
+    pub lossless: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AvifBitDepth {
+    Eight,
+    /// 10 bit gives better compression even for 8 bit sources, so it's the default. 16 bit
+    /// sources keep their extra precision
+    #[default]
+    Ten,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AvifSettings {
+    /// Encoder speed from 1 (slowest, smallest files) to 10 (fastest)
+    pub speed: u8,
+    pub bit_depth: AvifBitDepth,
+}
+impl Default for AvifSettings {
+    fn default() -> Self {
+        AvifSettings {
+            speed: 6,
+            bit_depth: AvifBitDepth::Ten,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TiffBitDepth {
+    #[default]
+    Eight,
+    Sixteen,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TiffCompression {
+    None,
+    #[default]
+    Lzw,
+    Deflate,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TiffSettings {
+    pub bit_depth: TiffBitDepth,
+    pub compression: TiffCompression,
+    /// Write an alpha channel (RGBA) instead of plain RGB
+    pub alpha: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct JpegXlSettings {
+    /// Butteraugli distance, 0.0 is mathematically lossless and 1.0 visually lossless. When it is
+    /// not set, the distance is derived from `FileSettings.quality` the same way cjxl does it
+    pub distance: Option<f32>,
+    /// Encoder effort from 1 (fastest) to 9 (slowest, smallest files)
+    pub effort: u8,
+    pub lossless: bool,
+    /// Repack JPEG sources into JPEG XL without decoding them. Only used when resizing is off
+    pub lossless_jpeg_transcode: bool,
+}
+impl Default for JpegXlSettings {
+    fn default() -> Self {
+        JpegXlSettings {
+            distance: None,
+            effort: 7,
+            lossless: false,
+            lossless_jpeg_transcode: true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeicChroma {
+    #[default]
+    #[serde(rename = "420")]
+    C420,
+    #[serde(rename = "422")]
+    C422,
+    #[serde(rename = "444")]
+    C444,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeicBitDepth {
+    #[default]
+    Eight,
+    Ten,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HeicSettings {
+    /// Lossless HEVC. Always uses 4:4:4 chroma, whatever `chroma` is set to
+    pub lossless: bool,
+    pub chroma: HeicChroma,
+    pub bit_depth: HeicBitDepth,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DngCompression {
+    #[default]
+    Lossless,
+    Uncompressed,
+}
+
+/// Preview images embedded in a DNG, which other apps show without developing the raw data
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DngPreview {
+    None,
+    Thumbnail,
+    /// A thumbnail and a larger preview
+    #[default]
+    Full,
+}
+
+/// DNGs always keep the camera's full exif, since they are meant for archiving the raw file.
+/// `ExportSettings.metadata_policy` doesn't apply to them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DngSettings {
+    pub compression: DngCompression,
+    /// Store the original raw file inside the DNG, so it can be extracted again later
+    pub embed_original: bool,
+    pub preview: DngPreview,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FileSettings {
     pub image_format: ExportImageFormat,
     pub quality: u8,
+    #[serde(default)]
+    pub webp: WebpSettings,
+    #[serde(default)]
+    pub avif: AvifSettings,
+    #[serde(default)]
+    pub tiff: TiffSettings,
+    #[serde(default)]
+    pub jpeg_xl: JpegXlSettings,
+    #[serde(default)]
+    pub heic: HeicSettings,
+    #[serde(default)]
+    pub dng: DngSettings,
+    /// Color space the pixels are converted to. Its icc profile is embedded in the exported file
+    #[serde(default)]
+    pub color_space: ColorSpace,
+    /// Format used with `ExportImageFormat::Original` for sources we can't write, e.g. raw or heic
+    #[serde(default = "default_original_fallback_format")]
+    pub original_fallback_format: ExportImageFormat,
+    /// Background color as `#rrggbb`, which transparent images are composited onto when the
+    /// export format has no alpha channel
+    #[serde(default = "default_matte_color")]
+    pub matte_color: String,
+}
+fn default_original_fallback_format() -> ExportImageFormat {
+    ExportImageFormat::Jpeg
+}
+fn default_matte_color() -> String {
+    "#ffffff".to_string()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -163,6 +412,39 @@ pub enum ResizeInOption {
     PixelsPerCm,
 }
 
+/// Filter used to resample pixels when resizing
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResamplingFilter {
+    /// Keeps hard pixel edges, for pixel art
+    Nearest,
+    Bilinear,
+    CatmullRom,
+    Mitchell,
+    /// Sharpest of the filters, best for photos
+    #[default]
+    Lanczos3,
+    /// Skips source pixels before filtering. Much faster for large reductions, at a small cost
+    /// in quality
+    Supersampling,
+}
+impl ResamplingFilter {
+    fn resize_alg(self, upscaling: bool) -> ResizeAlg {
+        match self {
+            // Supersampling only speeds up reductions, enlarging needs a regular filter
+            ResamplingFilter::Supersampling if upscaling => {
+                ResizeAlg::Convolution(FilterType::Lanczos3)
+            }
+            ResamplingFilter::Nearest => ResizeAlg::Nearest,
+            ResamplingFilter::Bilinear => ResizeAlg::Convolution(FilterType::Bilinear),
+            ResamplingFilter::CatmullRom => ResizeAlg::Convolution(FilterType::CatmullRom),
+            ResamplingFilter::Mitchell => ResizeAlg::Convolution(FilterType::Mitchell),
+            ResamplingFilter::Lanczos3 => ResizeAlg::Convolution(FilterType::Lanczos3),
+            ResamplingFilter::Supersampling => ResizeAlg::SuperSampling(FilterType::Lanczos3, 2),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ImageSizing {
@@ -176,6 +458,120 @@ pub struct ImageSizing {
     pub resize_in: ResizeIn,
     pub resize_resolution: f32,
     pub resize_resolution_in: ResizeInOption,
+    #[serde(default)]
+    pub resampling_filter: ResamplingFilter,
+    #[serde(default)]
+    pub linear_light: bool,
+}
+
+/// Which exif, xmp and iptc metadata of the source image is written to the exported file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataPolicy {
+    #[default]
+    KeepAll,
+    /// Keep everything except GPS and location fields
+    RemoveLocation,
+    CopyrightAndContactOnly,
+    StripAll,
+}
+
+/// How many images of a batch are exported at the same time
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BatchSettings {
+    /// Number of images exported in parallel. 0 uses one per cpu core
+    pub max_parallel_exports: u32,
+    /// Rough limit for the memory taken up by the images being exported, in megabytes. An image
+    /// only starts exporting once its estimated share fits in the budget. 0 means no limit
+    pub memory_budget_mb: u32,
+}
+impl Default for BatchSettings {
+    fn default() -> Self {
+        BatchSettings {
+            max_parallel_exports: 0,
+            memory_budget_mb: 2048,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RawWhiteBalance {
+    /// The white balance the camera recorded
+    #[default]
+    AsShot,
+    /// Worked out from the whole image
+    Auto,
+    /// From `RawDevelopSettings.temperature` and `RawDevelopSettings.tint`
+    Custom,
+}
+
+/// How libraw treats highlights where some of the channels are clipped
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RawHighlightMode {
+    #[default]
+    Clip,
+    Unclip,
+    Blend,
+    Rebuild,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RawDemosaicAlgorithm {
+    Linear,
+    Vng,
+    Ppg,
+    #[default]
+    Ahd,
+    Dcb,
+    Dht,
+    Aahd,
+}
+
+/// How raw files are developed when exporting. Raws are developed by rawler, which develops
+/// into sRGB and has no noise reduction, so `noise_threshold` and `output_color_space` only
+/// apply to raws from cameras rawler can't read, which libraw develops instead.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RawDevelopSettings {
+    pub white_balance: RawWhiteBalance,
+    /// Color temperature in kelvin, used with `RawWhiteBalance::Custom`
+    pub temperature: u32,
+    /// Green (negative) to magenta (positive) shift from -150 to 150, used with
+    /// `RawWhiteBalance::Custom`
+    pub tint: f32,
+    /// Exposure change in stops, from -2 to 3
+    pub exposure_compensation: f32,
+    /// Brighten the image so its brightest pixels are white
+    pub auto_brightness: bool,
+    pub highlight_mode: RawHighlightMode,
+    pub demosaic_algorithm: RawDemosaicAlgorithm,
+    /// Develop to 16 bits per channel instead of 8
+    pub output_16bit: bool,
+    /// Strength of wavelet noise reduction, usually between 100 and 1000. 0 turns it off
+    pub noise_threshold: f32,
+    /// Color space libraw develops into. Exported files are converted from it to
+    /// `FileSettings.color_space`
+    pub output_color_space: ColorSpace,
+}
+impl Default for RawDevelopSettings {
+    fn default() -> Self {
+        RawDevelopSettings {
+            white_balance: RawWhiteBalance::AsShot,
+            temperature: 5500,
+            tint: 0.0,
+            exposure_compensation: 0.0,
+            auto_brightness: false,
+            highlight_mode: RawHighlightMode::Clip,
+            demosaic_algorithm: RawDemosaicAlgorithm::Ahd,
+            output_16bit: true,
+            noise_threshold: 0.0,
+            output_color_space: ColorSpace::Srgb,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -184,40 +580,37 @@ pub struct ExportSettings {
     pub export_location: ExportLocation,
     pub file_settings: FileSettings,
     pub image_sizing: ImageSizing,
+    #[serde(default)]
+    pub file_renaming: FileRenaming,
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy,
+    #[serde(default)]
+    pub batch: BatchSettings,
+    #[serde(default)]
+    pub raw_develop: RawDevelopSettings,
 }
 
-fn is_heif_image(image_path: &Path) -> bool {
-    image_path
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .is_some_and(|ext| ext.eq_ignore_ascii_case("heif") || ext.eq_ignore_ascii_case("heic"))
-}
+/// Whether a file is a raw file, judged by its content like exports are, see
+/// `decoders::decoder_for`
 pub fn is_raw_image(path: &Path) -> bool {
-    let raw_image_extensions = [
-        "raf", "cr2", "mrw", "arw", "srf", "sr2", "mef", "orf", "srw", "erf", "kdc", "dcs", "rw2",
-        "dcr", "dng", "pef", "crw", "raw", "iiq", "3rf", "nrw", "nef", "mos", "ari",
-    ];
-    if let Some(extension) = path.extension() {
-        let extension = extension.to_ascii_lowercase();
-        let extension = extension.to_string_lossy();
-        raw_image_extensions.contains(&extension.as_ref())
-    } else {
-        false
-    }
+    decoders::decoder_for(path).is_ok_and(|decoder| decoder.is_raw())
 }
-pub fn load_heif_image(
-    path: &Path,
-) -> Result<DynamicImage, Box<dyn std::error::Error + Send + Sync>> {
+pub fn load_heif_image(path: &Path) -> Result<DynamicImage, ExportError> {
+    let decode_error = |e: BoxError| ExportError::decode(DecodeBackend::Libheif, e);
     let path_str_res = path.to_str();
     if path_str_res.is_none() {
-        return Err("Error converting path to string".into());
+        return Err(decode_error("Error converting path to string".into()));
     }
     let path_str = path_str_res.unwrap();
-    let read_ctx = HeifContext::read_from_file(path_str)?;
-    let handle = read_ctx.primary_image_handle()?;
+    let read_ctx = HeifContext::read_from_file(path_str).map_err(|e| decode_error(e.into()))?;
+    let handle = read_ctx
+        .primary_image_handle()
+        .map_err(|e| decode_error(e.into()))?;
 
     let lib_heif = LibHeif::new();
-    let image = lib_heif.decode(&handle, HeifColorSpace::Rgb(RgbChroma::Rgb), None)?;
+    let image = lib_heif
+        .decode(&handle, HeifColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(|e| decode_error(e.into()))?;
     let planes = image.planes();
     let interleaved_plane_res = planes.interleaved;
 
@@ -246,108 +639,413 @@ pub fn load_heif_image(
                     let dyn_image = image::DynamicImage::ImageRgb8(rgb_image);
                     Ok(dyn_image)
                 }
-                None => Err("Error converting raw data to RgbImage".into()),
+                None => Err(decode_error("Error converting raw data to RgbImage".into())),
             }
         }
-        None => Err("Error converting planes to interleaved planes during heif read".into()),
+        None => Err(decode_error(
+            "Error converting planes to interleaved planes during heif read".into(),
+        )),
+    }
+}
+/// CIE 1931 xy chromaticity of a black body at `temperature` kelvin, shifted off the black body
+/// curve by `tint`. Uses the cubic approximation of Kim et al., which holds from 1667K to 25000K.
+pub(crate) fn white_point_chromaticity(temperature: u32, tint: f32) -> (f32, f32) {
+    let planckian = |temperature: f32| {
+        let t = temperature.clamp(1667.0, 25000.0);
+        let x = if t <= 4000.0 {
+            -0.2661239e9 / t.powi(3) - 0.2343589e6 / t.powi(2) + 0.8776956e3 / t + 0.179910
+        } else {
+            -3.0258469e9 / t.powi(3) + 2.1070379e6 / t.powi(2) + 0.2226347e3 / t + 0.240390
+        };
+        let y = if t <= 2222.0 {
+            -1.1063814 * x.powi(3) - 1.3481102 * x.powi(2) + 2.18555832 * x - 0.20219683
+        } else if t <= 4000.0 {
+            -0.9549476 * x.powi(3) - 1.37418593 * x.powi(2) + 2.09137015 * x - 0.16748867
+        } else {
+            3.081758 * x.powi(3) - 5.8733867 * x.powi(2) + 3.75112997 * x - 0.37001483
+        };
+        // CIE 1960 uv, where distances from the curve are perceptually even
+        let denominator = -2.0 * x + 12.0 * y + 3.0;
+        (4.0 * x / denominator, 6.0 * y / denominator)
+    };
+    let temperature = temperature as f32;
+    let (u, v) = planckian(temperature);
+    // Tint moves perpendicular to the black body curve, magenta being below it
+    let (next_u, next_v) = planckian(temperature + 10.0);
+    let (du, dv) = (next_u - u, next_v - v);
+    let length = (du * du + dv * dv).sqrt().max(f32::EPSILON);
+    let (normal_u, normal_v) = if du > 0.0 {
+        (dv / length, -du / length)
+    } else {
+        (-dv / length, du / length)
+    };
+    let offset = tint / 3000.0;
+    let (u, v) = (u + normal_u * offset, v + normal_v * offset);
+    let denominator = 2.0 * u - 8.0 * v + 4.0;
+    (3.0 * u / denominator, 2.0 * v / denominator)
+}
+
+/// libraw white balance multipliers for a custom temperature and tint. The white point is
+/// taken to linear sRGB and then back to the camera's own colors through libraw's camera
+/// matrix. Returns `None` if the camera has no usable matrix.
+fn custom_white_balance_multipliers(
+    color: &libraw_sys::libraw_colordata_t,
+    temperature: u32,
+    tint: f32,
+) -> Option<[f32; 4]> {
+    const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+        [3.2404542, -1.5371385, -0.4985314],
+        [-0.969266, 1.8760108, 0.041556],
+        [0.0556434, -0.2040259, 1.0572252],
+    ];
+    let (x, y) = white_point_chromaticity(temperature, tint);
+    let xyz = [x / y, 1.0, (1.0 - x - y) / y];
+    let rgb = XYZ_TO_SRGB.map(|row| row[0] * xyz[0] + row[1] * xyz[1] + row[2] * xyz[2]);
+
+    // rgb_cam takes daylight balanced camera colors to sRGB
+    let rgb_cam = color.rgb_cam.map(|row| [row[0], row[1], row[2]]);
+    let cam_rgb = raw_develop::invert_matrix(rgb_cam)?;
+    let mut multipliers = [0.0; 4];
+    for c in 0..3 {
+        let camera_white: f32 = (0..3).map(|i| cam_rgb[c][i] * rgb[i]).sum();
+        let multiplier = color.pre_mul[c] / camera_white;
+        if !(multiplier.is_finite() && multiplier > 0.0) {
+            return None;
+        }
+        multipliers[c] = multiplier;
+    }
+    let green = multipliers[1];
+    multipliers
+        .iter_mut()
+        .for_each(|multiplier| *multiplier /= green);
+    multipliers[3] = multipliers[1];
+    Some(multipliers)
+}
+
+/// libraw output color space number and the gamma curve matching our icc profile of the
+/// color space. libraw has no Display P3 output, so it develops into Rec. 2020 for it and the
+/// export converts from there.
+fn libraw_output_color(color_space: ColorSpace) -> (i32, [f64; 2]) {
+    match color_space {
+        ColorSpace::Srgb => (1, [1.0 / 2.4, 12.92]),
+        ColorSpace::AdobeRgb => (2, [256.0 / 563.0, 0.0]),
+        ColorSpace::ProphotoRgb => (4, [1.0 / 1.8, 16.0]),
+        ColorSpace::DisplayP3 | ColorSpace::Rec2020 => (8, [0.45, 4.5]),
+    }
+}
+
+/// The color space `load_raw_image_libraw` develops into for these settings
+pub(crate) fn libraw_output_color_space(raw_develop: &RawDevelopSettings) -> ColorSpace {
+    match raw_develop.output_color_space {
+        ColorSpace::DisplayP3 => ColorSpace::Rec2020,
+        color_space => color_space,
     }
 }
-/// Load raw image using libraw
+
+/// Options for developing raws with rawler. Its two demosaic algorithms and highlight modes
+/// stand in for the closest of libraw's.
+pub(crate) fn rawler_develop_options(raw_develop: &RawDevelopSettings) -> RawDevelopOptions {
+    RawDevelopOptions {
+        white_balance: match raw_develop.white_balance {
+            RawWhiteBalance::AsShot => raw_develop::WhiteBalance::AsShot,
+            RawWhiteBalance::Auto => raw_develop::WhiteBalance::Auto,
+            RawWhiteBalance::Custom => raw_develop::WhiteBalance::Custom {
+                temperature: raw_develop.temperature,
+                tint: raw_develop.tint,
+            },
+        },
+        demosaic: match raw_develop.demosaic_algorithm {
+            RawDemosaicAlgorithm::Linear => raw_develop::DemosaicAlgorithm::Bilinear,
+            _ => raw_develop::DemosaicAlgorithm::Ahd,
+        },
+        highlights: match raw_develop.highlight_mode {
+            RawHighlightMode::Clip => raw_develop::HighlightMode::Clip,
+            _ => raw_develop::HighlightMode::Blend,
+        },
+        exposure_compensation: raw_develop.exposure_compensation.clamp(-2.0, 3.0),
+        auto_brightness: raw_develop.auto_brightness,
+    }
+}
+
+/// Load raw image using libraw, for cameras rawler can't read. `half_size` develops each 2x2 block of the sensor into one
+/// pixel, which is much faster and good enough for previews
 pub fn load_raw_image_libraw(
     path: &Path,
-) -> Result<DynamicImage, Box<dyn std::error::Error + Send + Sync>> {
-    let buf = std::fs::read(path)?;
+    raw_develop: &RawDevelopSettings,
+    half_size: bool,
+) -> Result<DynamicImage, ExportError> {
+    let decode_error = |e: BoxError| ExportError::decode(DecodeBackend::Libraw, e);
+    let buf = std::fs::read(path).map_err(|e| ExportError::decode(DecodeBackend::Libraw, e))?;
 
-    let processor = libraw::Processor::new();
-    let processed = processor.process_8bit(&buf)?;
-
-    let width = processed.width() as u32;
-    let height = processed.height() as u32;
-    let buf = processed.deref().to_vec();
+    let mut libraw = LibRaw::open_buffer(&buf).map_err(decode_error)?;
+    let custom_multipliers = match raw_develop.white_balance {
+        RawWhiteBalance::Custom => {
+            let multipliers = custom_white_balance_multipliers(
+                libraw.color(),
+                raw_develop.temperature,
+                raw_develop.tint,
+            );
+            if multipliers.is_none() {
+                warn!("No camera matrix for custom white balance, using the camera white balance");
+            }
+            multipliers
+        }
+        _ => None,
+    };
 
-    let img_buffer_res: Option<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> =
-        image::ImageBuffer::from_vec(width, height, buf);
+    let params = libraw.params_mut();
+    match (raw_develop.white_balance, custom_multipliers) {
+        (_, Some(multipliers)) => params.user_mul = multipliers,
+        (RawWhiteBalance::Auto, _) => params.use_auto_wb = 1,
+        _ => params.use_camera_wb = 1,
+    }
+    if raw_develop.exposure_compensation != 0.0 {
+        params.exp_correc = 1;
+        // libraw takes a linear shift from 0.25 (-2 stops) to 8 (+3 stops)
+        params.exp_shift = 2f32.powf(raw_develop.exposure_compensation.clamp(-2.0, 3.0));
+    }
+    params.no_auto_bright = i32::from(!raw_develop.auto_brightness);
+    params.highlight = match raw_develop.highlight_mode {
+        RawHighlightMode::Clip => 0,
+        RawHighlightMode::Unclip => 1,
+        RawHighlightMode::Blend => 2,
+        RawHighlightMode::Rebuild => 5,
+    };
+    params.user_qual = match raw_develop.demosaic_algorithm {
+        RawDemosaicAlgorithm::Linear => 0,
+        RawDemosaicAlgorithm::Vng => 1,
+        RawDemosaicAlgorithm::Ppg => 2,
+        RawDemosaicAlgorithm::Ahd => 3,
+        RawDemosaicAlgorithm::Dcb => 4,
+        RawDemosaicAlgorithm::Dht => 11,
+        RawDemosaicAlgorithm::Aahd => 12,
+    };
+    params.output_bps = if raw_develop.output_16bit { 16 } else { 8 };
+    params.half_size = i32::from(half_size);
+    params.threshold = raw_develop.noise_threshold.max(0.0);
+    let (output_color, gamma) = libraw_output_color(libraw_output_color_space(raw_develop));
+    params.output_color = output_color;
+    params.gamm[0] = gamma[0];
+    params.gamm[1] = gamma[1];
 
-    if let Some(img_buffer) = img_buffer_res {
-        let dyn_img = image::DynamicImage::ImageRgb8(img_buffer);
-        return Ok(dyn_img);
+    libraw.develop().map_err(decode_error)
+}
+/// Scale a size to fit inside the max size, keeping its aspect ratio. The result can be larger
+/// than the current size. If one of the max values is set to 0, the size in that dimension is not
+/// restricted
+fn fit_size((width, height): (u32, u32), (max_width, max_height): (u32, u32)) -> (u32, u32) {
+    if max_width == 0 && max_height == 0 {
+        return (width, height);
     }
+    let wratio = if max_width > 0 {
+        max_width as f32 / width as f32
+    } else {
+        f32::MAX
+    };
+    let hratio = if max_height > 0 {
+        max_height as f32 / height as f32
+    } else {
+        f32::MAX
+    };
+
+    let ratio = f32::min(wratio, hratio);
 
-    Err("Failed to create image buffer".into())
+    let new_width = max((width as f32 * ratio).round() as u32, 1);
+    let new_height = max((height as f32 * ratio).round() as u32, 1);
+    (new_width, new_height)
 }
 /// Get the actual size from the current size and the max size
-/// If either width nor height is smaller or equal to max_width and max_height, the new size is
-/// reduced to the larger of the two. If one of the max values is set to 0, the size in that dimension
-/// is not restricted
+/// The size is scaled to fit inside max_width and max_height. Unless `enlarge` is set, an image
+/// which already fits keeps its current size. If one of the max values is set to 0, the size in
+/// that dimension is not restricted
 pub fn restrict_size(
     (width, height): (u32, u32),
     (max_width, max_height): (u32, u32),
+    enlarge: bool,
 ) -> (u32, u32) {
-    if (width > max_width || height > max_height) && (max_width != 0 || max_height != 0) {
-        let wratio = if max_width > 0 {
-            max_width as f32 / width as f32
-        } else {
-            f32::MAX
-        };
-        let hratio = if max_height > 0 {
-            max_height as f32 / height as f32
-        } else {
-            f32::MAX
-        };
-
-        let ratio = f32::min(wratio, hratio);
-
-        let new_width = max((width as f32 * ratio).round() as u32, 1);
-        let new_height = max((height as f32 * ratio).round() as u32, 1);
+    let (new_width, new_height) = fit_size((width, height), (max_width, max_height));
+    if !enlarge && (new_width > width || new_height > height) {
+        (width, height)
+    } else {
         (new_width, new_height)
+    }
+}
+/// Largest image we resize to. Anything bigger is almost certainly a typo in the settings, and
+/// would need tens of gigabytes of memory.
+const MAX_RESIZE_PIXELS: u64 = 1_000_000_000;
+/// Decode an sRGB encoded 16 bit sample to linear light
+fn srgb_to_linear(value: u16) -> u16 {
+    let v = value as f32 / 65535.0;
+    let linear = if v <= 0.04045 {
+        v / 12.92
     } else {
-        (width, height)
+        ((v + 0.055) / 1.055).powf(2.4)
+    };
+    (linear * 65535.0).round() as u16
+}
+/// Encode a linear light 16 bit sample with the sRGB transfer curve
+fn linear_to_srgb(value: u16) -> u16 {
+    let v = value as f32 / 65535.0;
+    let encoded = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 65535.0).round() as u16
+}
+/// Run the color channels of 16 bit RGB(A) samples through a lookup table, leaving alpha as is
+pub(crate) fn apply_transfer_lut(samples: &mut [u16], channels: usize, lut: &[u16]) {
+    for pixel in samples.chunks_exact_mut(channels) {
+        for sample in &mut pixel[..3] {
+            *sample = lut[*sample as usize];
+        }
     }
 }
-/// Resize an image buffer with the nearest neighbor method
-/// Resize an image buffer with the nearest neighbor method
+fn srgb_to_linear_lut() -> &'static [u16] {
+    static LUT: OnceLock<Vec<u16>> = OnceLock::new();
+    LUT.get_or_init(|| (0..=u16::MAX).map(srgb_to_linear).collect())
+}
+pub(crate) fn linear_to_srgb_lut() -> &'static [u16] {
+    static LUT: OnceLock<Vec<u16>> = OnceLock::new();
+    LUT.get_or_init(|| (0..=u16::MAX).map(linear_to_srgb).collect())
+}
+/// Resize an image buffer with the given resampling filter. With `linear_light` the pixels are
+/// blended in linear light instead of on their sRGB encoded values, which keeps fine detail and
+/// high contrast edges from getting darker when downscaling.
 pub fn resize_image(
     dyn_image: DynamicImage,
     new_width: u32,
     new_height: u32,
-) -> Result<DynamicImage, ResizeImageError> {
-    // TODO: Can we handle both rgb8 (jpeg) and rgba8 (png) images here?
-    // or rgba8 works for both already?
-    let src_image = dyn_image.into_rgba8();
-    let width = src_image.width();
-    let height = src_image.height();
-    let src_image_data = FirImage::from_vec_u8(
-        NonZeroU32::new(width).unwrap(),
-        NonZeroU32::new(height).unwrap(),
-        src_image.into_raw(),
-        PixelType::U8x4,
-    )?;
-    // let src_image_data = src_image_data_res.jk
-    let mut dst_image = FirImage::new(
-        NonZeroU32::new(new_width).unwrap(),
-        NonZeroU32::new(new_height).unwrap(),
-        src_image_data.pixel_type(),
-    );
-    let mut dst_view = dst_image.view_mut();
+    resampling_filter: ResamplingFilter,
+    linear_light: bool,
+) -> Result<DynamicImage, ExportError> {
+    let width = dyn_image.width();
+    let height = dyn_image.height();
+    let (Some(src_width), Some(src_height)) = (NonZeroU32::new(width), NonZeroU32::new(height))
+    else {
+        return Err(ExportError::resize(format!(
+            "Can't resize an empty {width}x{height} image"
+        )));
+    };
+    let (Some(dst_width), Some(dst_height)) =
+        (NonZeroU32::new(new_width), NonZeroU32::new(new_height))
+    else {
+        return Err(ExportError::InvalidSettings(format!(
+            "Can't resize to an empty {new_width}x{new_height} image"
+        )));
+    };
+    if u64::from(new_width) * u64::from(new_height) > MAX_RESIZE_PIXELS {
+        return Err(ExportError::InvalidSettings(format!(
+            "Resizing to {new_width}x{new_height} is larger than the supported {} megapixels",
+            MAX_RESIZE_PIXELS / 1_000_000
+        )));
+    }
 
-    let mut fast_resizer = Resizer::new(ResizeAlg::Nearest);
+    let has_alpha = dyn_image.color().has_alpha();
+    let is_high_bit_depth =
+        dyn_image.color().bytes_per_pixel() / dyn_image.color().channel_count() > 1;
+    let channels = if has_alpha { 4 } else { 3 };
 
-    fast_resizer.resize(&src_image_data.view(), &mut dst_view)?;
-    // mul_div.divide_alpha_inplace(&mut dst_view)?;
-    let image_buffer: ImageBuffer<Rgba<u8>, Vec<u8>> =
-        ImageBuffer::from_raw(new_width, new_height, dst_image.buffer().to_vec()).unwrap();
+    // Linear light needs more than 8 bits per sample to avoid banding in the shadows, so 8 bit
+    // images are resized in 16 bits and reduced back to 8 bits afterwards
+    let resize_in_16_bit = is_high_bit_depth || linear_light;
+    let (src_buffer, pixel_type) = match (resize_in_16_bit, has_alpha) {
+        (false, false) => (dyn_image.into_rgb8().into_raw(), PixelType::U8x3),
+        (false, true) => (dyn_image.into_rgba8().into_raw(), PixelType::U8x4),
+        (true, _) => {
+            let mut samples = if has_alpha {
+                dyn_image.into_rgba16().into_raw()
+            } else {
+                dyn_image.into_rgb16().into_raw()
+            };
+            if linear_light {
+                apply_transfer_lut(&mut samples, channels, srgb_to_linear_lut());
+            }
+            let pixel_type = if has_alpha {
+                PixelType::U16x4
+            } else {
+                PixelType::U16x3
+            };
+            (bytemuck::cast_slice(&samples).to_vec(), pixel_type)
+        }
+    };
+    let mut src_image_data = FirImage::from_vec_u8(src_width, src_height, src_buffer, pixel_type)
+        .map_err(ExportError::resize)?;
+    let mut dst_image = FirImage::new(dst_width, dst_height, pixel_type);
+
+    // Resampling straight alpha mixes in the color of fully transparent pixels, which shows up
+    // as dark fringes around transparent edges. Premultiplied pixels don't have that problem.
+    let mul_div = MulDiv::default();
+    if has_alpha {
+        mul_div
+            .multiply_alpha_inplace(&mut src_image_data.view_mut())
+            .map_err(ExportError::resize)?;
+    }
 
-    Ok(image_buffer.into())
+    let upscaling = new_width > width || new_height > height;
+    let mut fast_resizer = Resizer::new(resampling_filter.resize_alg(upscaling));
+    fast_resizer
+        .resize(&src_image_data.view(), &mut dst_image.view_mut())
+        .map_err(ExportError::resize)?;
+
+    if has_alpha {
+        mul_div
+            .divide_alpha_inplace(&mut dst_image.view_mut())
+            .map_err(ExportError::resize)?;
+    }
+
+    if !resize_in_16_bit {
+        let dst_buffer = dst_image.buffer().to_vec();
+        return Ok(if has_alpha {
+            DynamicImage::ImageRgba8(
+                ImageBuffer::from_raw(new_width, new_height, dst_buffer).unwrap(),
+            )
+        } else {
+            DynamicImage::ImageRgb8(
+                ImageBuffer::from_raw(new_width, new_height, dst_buffer).unwrap(),
+            )
+        });
+    }
+
+    // The resizer buffer isn't guaranteed to be aligned for u16, so copy the samples out
+    let mut dst_samples: Vec<u16> = bytemuck::pod_collect_to_vec(dst_image.buffer());
+    if linear_light {
+        apply_transfer_lut(&mut dst_samples, channels, linear_to_srgb_lut());
+    }
+    let resized_image = if has_alpha {
+        DynamicImage::ImageRgba16(
+            ImageBuffer::from_raw(new_width, new_height, dst_samples).unwrap(),
+        )
+    } else {
+        DynamicImage::ImageRgb16(ImageBuffer::from_raw(new_width, new_height, dst_samples).unwrap())
+    };
+    Ok(match (is_high_bit_depth, has_alpha) {
+        (true, _) => resized_image,
+        (false, false) => DynamicImage::ImageRgb8(resized_image.into_rgb8()),
+        (false, true) => DynamicImage::ImageRgba8(resized_image.into_rgba8()),
+    })
 }
 pub fn resize_and_rotate(
     image: DynamicImage,
     rotate: i32,
     max_width: u32,
     max_height: u32,
-) -> Result<DynamicImage, ResizeImageError> {
-    let (new_width, new_height) =
-        restrict_size((image.width(), image.height()), (max_width, max_height));
-    match resize_image(image, new_width, new_height) {
+    resampling_filter: ResamplingFilter,
+    linear_light: bool,
+    enlarge: bool,
+) -> Result<DynamicImage, ExportError> {
+    let (new_width, new_height) = restrict_size(
+        (image.width(), image.height()),
+        (max_width, max_height),
+        enlarge,
+    );
+    match resize_image(
+        image,
+        new_width,
+        new_height,
+        resampling_filter,
+        linear_light,
+    ) {
         Ok(image) => Ok(match rotate {
             90 => image.rotate90(),
             180 => image.rotate180(),
@@ -360,15 +1058,33 @@ pub fn resize_and_rotate(
         }
     }
 }
-fn resize_image_with_export_settings(
-    image: DynamicImage,
+/// The box an image of `width` x `height` is fit into as per the image sizing settings, where a
+/// 0 leaves that dimension unrestricted. See `fit_size`.
+fn max_size_for_settings(
+    (width, height): (u32, u32),
     image_sizing: &ImageSizing,
-) -> Result<DynamicImage, String> {
-    let width = image.width();
-    let height = image.height();
+) -> Result<(u32, u32), ExportError> {
     let max_width: u32;
     let max_height: u32;
 
+    match image_sizing.resize_to_fit {
+        ResizeToFitOption::WidthAndHeight
+        | ResizeToFitOption::Dimensions
+        | ResizeToFitOption::Pixels => {
+            check_size_setting("Width", image_sizing.resize_width)?;
+            check_size_setting("Height", image_sizing.resize_height)?;
+        }
+        ResizeToFitOption::LongEdge | ResizeToFitOption::ShortEdge => {
+            check_size_setting("Size", image_sizing.resize_size)?;
+        }
+        ResizeToFitOption::Megapixels => {
+            check_size_setting("Megapixels", image_sizing.resize_size_megapixels)?;
+        }
+    }
+    if image_sizing.resize_in != ResizeIn::Pixels {
+        check_size_setting("Resolution", image_sizing.resize_resolution)?;
+    }
+
     let pixels_per_inch = if image_sizing.resize_resolution_in == ResizeInOption::PixelsPerInch {
         image_sizing.resize_resolution
     } else {
@@ -394,16 +1110,32 @@ fn resize_image_with_export_settings(
             }
         }
         ResizeToFitOption::Dimensions => {
-            // TODO - We don't support dimensions for now. It needs to consider the orientation somehow.
-            if image_sizing.resize_in == ResizeIn::Pixels {
-                max_width = image_sizing.resize_width as u32;
-                max_height = image_sizing.resize_height as u32;
+            // The box is turned to match the orientation of the image, so a 1500x1000 box fits
+            // a portrait image as 1000x1500
+            let (box_width, box_height) = if image_sizing.resize_in == ResizeIn::Pixels {
+                (
+                    image_sizing.resize_width as u32,
+                    image_sizing.resize_height as u32,
+                )
             } else if image_sizing.resize_in == ResizeIn::Inches {
-                max_width = (image_sizing.resize_width * pixels_per_inch) as u32;
-                max_height = (image_sizing.resize_height * pixels_per_inch) as u32;
+                (
+                    (image_sizing.resize_width * pixels_per_inch) as u32,
+                    (image_sizing.resize_height * pixels_per_inch) as u32,
+                )
             } else {
-                max_width = (image_sizing.resize_width * pixels_per_cm) as u32;
-                max_height = (image_sizing.resize_height * pixels_per_cm) as u32;
+                (
+                    (image_sizing.resize_width * pixels_per_cm) as u32,
+                    (image_sizing.resize_height * pixels_per_cm) as u32,
+                )
+            };
+            let box_long_edge = max(box_width, box_height);
+            let box_short_edge = min(box_width, box_height);
+            if width >= height {
+                max_width = box_long_edge;
+                max_height = box_short_edge;
+            } else {
+                max_width = box_short_edge;
+                max_height = box_long_edge;
             }
         }
         ResizeToFitOption::LongEdge => {
@@ -443,10 +1175,12 @@ fn resize_image_with_export_settings(
             }
         }
         ResizeToFitOption::Megapixels => {
-            let megapixels = image_sizing.resize_size_megapixels;
-            let ratio = (megapixels * 1_000_000.0 / (width * height) as f32).sqrt();
-            max_width = (width as f32 * ratio) as u32;
-            max_height = (height as f32 * ratio) as u32;
+            // Sensors can have more pixels than fit in a u32
+            let pixels = u64::from(width) * u64::from(height);
+            let megapixels = f64::from(image_sizing.resize_size_megapixels);
+            let ratio = (megapixels * 1_000_000.0 / pixels as f64).sqrt();
+            max_width = (f64::from(width) * ratio) as u32;
+            max_height = (f64::from(height) * ratio) as u32;
         }
         ResizeToFitOption::Pixels => {
             max_width = image_sizing.resize_width as u32;
@@ -454,237 +1188,1462 @@ fn resize_image_with_export_settings(
         }
     }
 
-    let resized_image = resize_and_rotate(image, 0, max_width, max_height);
-    match resized_image {
-        Ok(resized_image) => Ok(resized_image),
-        Err(e) => Err(format!("Error resizing image {e:?}")),
+    // Edge sizes leave the other dimension unrestricted by setting it to 0
+    let is_empty_target = match image_sizing.resize_to_fit {
+        ResizeToFitOption::LongEdge | ResizeToFitOption::ShortEdge => {
+            max_width == 0 && max_height == 0
+        }
+        _ => max_width == 0 || max_height == 0,
+    };
+    if is_empty_target {
+        return Err(ExportError::InvalidSettings(
+            "The requested size is less than a pixel".to_string(),
+        ));
+    }
+    Ok((max_width, max_height))
+}
+/// Resize an image as per the image sizing settings. Also returns whether the image was left
+/// smaller than the requested size because enlarging is turned off.
+fn resize_image_with_export_settings(
+    image: DynamicImage,
+    image_sizing: &ImageSizing,
+) -> Result<(DynamicImage, bool), ExportError> {
+    let width = image.width();
+    let height = image.height();
+    let (max_width, max_height) = max_size_for_settings((width, height), image_sizing)?;
+    let (target_width, target_height) = fit_size((width, height), (max_width, max_height));
+    let left_smaller = !image_sizing.enlarge && (target_width > width || target_height > height);
+    if left_smaller {
+        info!(
+            "Not enlarging {width}x{height} image to {target_width}x{target_height}, enlarging is off"
+        );
+    }
+
+    let resized_image = resize_and_rotate(
+        image,
+        0,
+        max_width,
+        max_height,
+        image_sizing.resampling_filter,
+        image_sizing.linear_light,
+        image_sizing.enlarge,
+    );
+    Ok((resized_image?, left_smaller))
+}
+/// Sizes in the settings have to be positive numbers
+fn check_size_setting(name: &str, value: f32) -> Result<(), ExportError> {
+    if value.is_finite() && value > 0.0 {
+        Ok(())
+    } else {
+        Err(ExportError::InvalidSettings(format!(
+            "{name} has to be a positive number, not {value}"
+        )))
     }
 }
-fn convert_image_for_format(image: DynamicImage, image_format: &ExportImageFormat) -> DynamicImage {
-    match image_format {
+/// Resolve the format an image is written in. `ExportImageFormat::Original` becomes the format of
+/// the source file's content, or the configured fallback format if we can't write the source
+/// format. Returns the format along with whether the source file extension should be kept, which
+/// it is when it matches the content.
+fn resolve_export_format(
+    image_path: &Path,
+    decoder: &dyn decoders::ImageDecoder,
+    file_settings: &FileSettings,
+) -> (ExportImageFormat, bool) {
+    if file_settings.image_format != ExportImageFormat::Original {
+        return (file_settings.image_format, false);
+    }
+
+    match decoder.export_format(image_path) {
+        Some(source_format) => {
+            let extension_matches = image_path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(ExportImageFormat::from_extension)
+                == Some(source_format);
+            (source_format, extension_matches)
+        }
+        None => match file_settings.original_fallback_format {
+            ExportImageFormat::Original => (ExportImageFormat::Jpeg, false),
+            fallback_format => (fallback_format, false),
+        },
+    }
+}
+
+/// Parse a `#rrggbb` color
+fn parse_hex_color(color: &str) -> Result<[u8; 3], ExportError> {
+    let hex = color.trim().trim_start_matches('#');
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+    };
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(ExportError::InvalidSettings(format!(
+            "Invalid matte color {color:?}"
+        ))),
+    }
+}
+
+/// Composite an image with an alpha channel onto a solid background color. Images without
+/// alpha are returned as they are.
+fn flatten_alpha(image: DynamicImage, matte_color: [u8; 3]) -> DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+
+    let is_high_bit_depth = image.color().bytes_per_pixel() / image.color().channel_count() > 1;
+    if is_high_bit_depth {
+        let matte_color = matte_color.map(|c| u32::from(c) * 257);
+        let rgba_image = image.into_rgba16();
+        let mut rgb_image: ImageBuffer<image::Rgb<u16>, Vec<u16>> =
+            ImageBuffer::new(rgba_image.width(), rgba_image.height());
+        for (dst, src) in rgb_image.pixels_mut().zip(rgba_image.pixels()) {
+            let alpha = u32::from(src[3]);
+            for c in 0..3 {
+                dst[c] = ((u32::from(src[c]) * alpha + matte_color[c] * (65535 - alpha) + 32767)
+                    / 65535) as u16;
+            }
+        }
+        DynamicImage::ImageRgb16(rgb_image)
+    } else {
+        let matte_color = matte_color.map(u32::from);
+        let rgba_image = image.into_rgba8();
+        let mut rgb_image = image::RgbImage::new(rgba_image.width(), rgba_image.height());
+        for (dst, src) in rgb_image.pixels_mut().zip(rgba_image.pixels()) {
+            let alpha = u32::from(src[3]);
+            for c in 0..3 {
+                dst[c] = ((u32::from(src[c]) * alpha + matte_color[c] * (255 - alpha) + 127) / 255)
+                    as u8;
+            }
+        }
+        DynamicImage::ImageRgb8(rgb_image)
+    }
+}
+
+/// DNGs are converted from the raw file without decoding it, and `Original` is resolved to a
+/// concrete format before exporting, so neither is ever encoded from pixels
+fn not_encodable_error(image_format: ExportImageFormat) -> ExportError {
+    ExportError::UnsupportedFormat(format!(
+        "{image_format} images can't be encoded from pixels"
+    ))
+}
+
+fn convert_image_for_format(
+    image: DynamicImage,
+    image_format: ExportImageFormat,
+    file_settings: &FileSettings,
+) -> Result<DynamicImage, ExportError> {
+    let matte_color = parse_hex_color(&file_settings.matte_color)?;
+    let converted_image = match image_format {
         ExportImageFormat::Jpeg => {
-            // When we resize the file, we are converting it to rgba8. We use fast_image_resize
-            // library to do that and it somehow works even for jpeg images. They shouldn't since
-            // jpeg files don't have the alpha channel. But it does.
-            // So we need to convert it back to rgb8 before saving it as jpeg.
-            let image_buffer = image.to_rgb8();
+            // jpeg has no alpha channel, so transparent pixels get the matte color instead of
+            // whatever color they happen to have
+            let image_buffer = flatten_alpha(image, matte_color).to_rgb8();
             image::DynamicImage::ImageRgb8(image_buffer)
         }
         ExportImageFormat::Png => {
             let image_buffer = image.to_rgba8();
             image::DynamicImage::ImageRgba8(image_buffer)
         }
+        ExportImageFormat::Webp | ExportImageFormat::JpegXl => {
+            // We only hand 8 bit rgb or rgba buffers to libwebp and libjxl. Keep the alpha
+            // channel around only if the image had one to begin with.
+            if image.color().has_alpha() {
+                image::DynamicImage::ImageRgba8(image.to_rgba8())
+            } else {
+                image::DynamicImage::ImageRgb8(image.to_rgb8())
+            }
+        }
+        ExportImageFormat::Avif => {
+            // Like heic, 10 bit avif gets 16 bit samples, which `encode_avif` scales down itself
+            match (&file_settings.avif.bit_depth, image.color().has_alpha()) {
+                (AvifBitDepth::Eight, false) => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+                (AvifBitDepth::Eight, true) => image::DynamicImage::ImageRgba8(image.to_rgba8()),
+                (AvifBitDepth::Ten, false) => image::DynamicImage::ImageRgb16(image.to_rgb16()),
+                (AvifBitDepth::Ten, true) => image::DynamicImage::ImageRgba16(image.to_rgba16()),
+            }
+        }
+        ExportImageFormat::Heic => {
+            // 10 bit heic keeps 16 bit samples until `fill_heif_plane` scales them, so high bit
+            // depth sources don't lose their extra precision on the way
+            match (file_settings.heic.bit_depth, image.color().has_alpha()) {
+                (HeicBitDepth::Eight, false) => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+                (HeicBitDepth::Eight, true) => image::DynamicImage::ImageRgba8(image.to_rgba8()),
+                (HeicBitDepth::Ten, false) => image::DynamicImage::ImageRgb16(image.to_rgb16()),
+                (HeicBitDepth::Ten, true) => image::DynamicImage::ImageRgba16(image.to_rgba16()),
+            }
+        }
+        ExportImageFormat::Tiff => {
+            let tiff_settings = &file_settings.tiff;
+            let image = if tiff_settings.alpha {
+                image
+            } else {
+                flatten_alpha(image, matte_color)
+            };
+            match (&tiff_settings.bit_depth, tiff_settings.alpha) {
+                (TiffBitDepth::Eight, false) => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+                (TiffBitDepth::Eight, true) => image::DynamicImage::ImageRgba8(image.to_rgba8()),
+                (TiffBitDepth::Sixteen, false) => image::DynamicImage::ImageRgb16(image.to_rgb16()),
+                (TiffBitDepth::Sixteen, true) => {
+                    image::DynamicImage::ImageRgba16(image.to_rgba16())
+                }
+            }
+        }
+        ExportImageFormat::Dng | ExportImageFormat::Original => {
+            return Err(not_encodable_error(image_format))
+        }
+    };
+    Ok(converted_image)
+}
+
+fn encode_jpeg(
+    image: &DynamicImage,
+    file_settings: &FileSettings,
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, ExportError> {
+    let mut buffer = Vec::new();
+    let mut encoder =
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, file_settings.quality);
+    if let Some(icc_profile) = &metadata.icc_profile {
+        encoder
+            .set_icc_profile(icc_profile.clone())
+            .map_err(|e| ExportError::encode("jpeg", e))?;
+    }
+    match encoder.encode_image(image) {
+        Ok(_) => {
+            containers::embed_in_jpeg(&buffer, metadata).map_err(|e| ExportError::encode("jpeg", e))
+        }
+        Err(e) => Err(ExportError::encode("jpeg", e)),
     }
 }
 
-fn save_image_to_disk(
-    image_file: DynamicImage,
-    export_file_path: &Path,
+fn encode_png(image: &DynamicImage, metadata: &EmbeddedMetadata) -> Result<Vec<u8>, ExportError> {
+    let mut buffer = Vec::new();
+    let mut encoder = image::codecs::png::PngEncoder::new(&mut buffer);
+    if let Some(icc_profile) = &metadata.icc_profile {
+        encoder
+            .set_icc_profile(icc_profile.clone())
+            .map_err(|e| ExportError::encode("png", e))?;
+    }
+    let encoded = encoder.write_image(
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        image.color().into(),
+    );
+    match encoded {
+        Ok(_) => {
+            containers::embed_in_png(&buffer, metadata).map_err(|e| ExportError::encode("png", e))
+        }
+        Err(e) => Err(ExportError::encode("png", e)),
+    }
+}
+
+fn encode_webp(
+    image: &DynamicImage,
+    file_settings: &FileSettings,
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, ExportError> {
+    let encoder = match image {
+        DynamicImage::ImageRgb8(buffer) => {
+            webp::Encoder::from_rgb(buffer.as_raw(), buffer.width(), buffer.height())
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            webp::Encoder::from_rgba(buffer.as_raw(), buffer.width(), buffer.height())
+        }
+        _ => {
+            return Err(ExportError::encode(
+                "webp",
+                format!("Unsupported color type for webp {:?}", image.color()),
+            ))
+        }
+    };
+    let quality = f32::from(file_settings.quality.min(100));
+    let encoded = encoder.encode_simple(file_settings.webp.lossless, quality);
+
+    match encoded {
+        Ok(webp_memory) => containers::embed_in_webp(
+            &webp_memory,
+            (image.width(), image.height()),
+            image.color().has_alpha(),
+            metadata,
+        )
+        .map_err(|e| ExportError::encode("webp", e)),
+        Err(e) => Err(ExportError::encode("webp", format!("{e:?}"))),
+    }
+}
+
+/// Full range BT.601 YCbCr, the same conversion ravif does for 8 bit rgb, from 16 bit samples
+/// so they keep their precision down to 10 bits
+fn rgb16_to_10_bit_ycbcr([r, g, b]: [u16; 3]) -> [u16; 3] {
+    const KR: f32 = 0.299;
+    const KB: f32 = 0.114;
+    const MAX: f32 = 1023.0;
+    let [r, g, b] = [r, g, b].map(|value| f32::from(value) / 65535.0 * MAX);
+    let y = KR * r + (1.0 - KR - KB) * g + KB * b;
+    let cb = (b - y) * 0.5 / (1.0 - KB) + 512.0;
+    let cr = (r - y) * 0.5 / (1.0 - KR) + 512.0;
+    [y, cb, cr].map(|value| value.round().clamp(0.0, MAX) as u16)
+}
+
+fn encode_avif(
+    image: &DynamicImage,
+    file_settings: &FileSettings,
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, ExportError> {
+    let avif_settings = &file_settings.avif;
+    // ravif panics for quality values outside 1..=100
+    let quality = f32::from(file_settings.quality.clamp(1, 100));
+    let bit_depth = match avif_settings.bit_depth {
+        AvifBitDepth::Eight => ravif::BitDepth::Eight,
+        AvifBitDepth::Ten => ravif::BitDepth::Ten,
+    };
+    let encoder = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_alpha_quality(quality)
+        .with_speed(avif_settings.speed.clamp(1, 10))
+        .with_bit_depth(bit_depth);
+
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+    let encoded = match image {
+        DynamicImage::ImageRgb8(buffer) => {
+            let pixels: Vec<ravif::RGB8> = buffer
+                .pixels()
+                .map(|p| ravif::RGB8::new(p[0], p[1], p[2]))
+                .collect();
+            encoder.encode_rgb(ravif::Img::new(pixels.as_slice(), width, height))
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            let pixels: Vec<ravif::RGBA8> = buffer
+                .pixels()
+                .map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3]))
+                .collect();
+            encoder.encode_rgba(ravif::Img::new(pixels.as_slice(), width, height))
+        }
+        DynamicImage::ImageRgb16(buffer) => encoder.encode_raw_planes_10_bit(
+            width,
+            height,
+            buffer
+                .pixels()
+                .map(|p| rgb16_to_10_bit_ycbcr([p[0], p[1], p[2]])),
+            None::<[u16; 0]>,
+            ravif::PixelRange::Full,
+            ravif::MatrixCoefficients::BT601,
+        ),
+        DynamicImage::ImageRgba16(buffer) => encoder.encode_raw_planes_10_bit(
+            width,
+            height,
+            buffer
+                .pixels()
+                .map(|p| rgb16_to_10_bit_ycbcr([p[0], p[1], p[2]])),
+            Some(buffer.pixels().map(|p| p[3] >> 6)),
+            ravif::PixelRange::Full,
+            ravif::MatrixCoefficients::BT601,
+        ),
+        _ => {
+            return Err(ExportError::encode(
+                "avif",
+                format!("Unsupported color type for avif {:?}", image.color()),
+            ))
+        }
+    };
+
+    match encoded {
+        Ok(encoded_image) => containers::embed_in_avif(&encoded_image.avif_file, metadata)
+            .map_err(|e| ExportError::encode("avif", e)),
+        Err(e) => Err(ExportError::encode("avif", e)),
+    }
+}
+
+fn write_tiff_image<C, D>(
+    buffer: &mut Cursor<Vec<u8>>,
+    (width, height): (u32, u32),
+    data: &[C::Inner],
+    compression: D,
+    image_sizing: &ImageSizing,
+    metadata: &EmbeddedMetadata,
+) -> tiff::TiffResult<()>
+where
+    C: tiff::encoder::colortype::ColorType,
+    D: tiff::encoder::compression::Compression,
+    [C::Inner]: tiff::encoder::TiffValue,
+{
+    let mut encoder = tiff::encoder::TiffEncoder::new(buffer)?;
+    let mut image = encoder.new_image_with_compression::<C, D>(width, height, compression)?;
+
+    let resolution_unit = if image_sizing.resize_resolution_in == ResizeInOption::PixelsPerCm {
+        tiff::tags::ResolutionUnit::Centimeter
+    } else {
+        tiff::tags::ResolutionUnit::Inch
+    };
+    // Keep two decimal places of the resolution, e.g. 118.11 pixels per cm
+    let resolution = tiff::encoder::Rational {
+        n: (image_sizing.resize_resolution.max(0.0) * 100.0).round() as u32,
+        d: 100,
+    };
+    image.resolution(resolution_unit, resolution);
+    if let Some(icc_profile) = &metadata.icc_profile {
+        // The tiff crate has no name for the InterColorProfile tag
+        image
+            .encoder()
+            .write_tag(tiff::tags::Tag::Unknown(34675), icc_profile.as_slice())?;
+    }
+    image.write_data(data)
+}
+
+fn encode_tiff_with_compression<D: tiff::encoder::compression::Compression>(
+    image: &DynamicImage,
+    compression: D,
+    image_sizing: &ImageSizing,
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, ExportError> {
+    use tiff::encoder::colortype::{RGB16, RGB8, RGBA16, RGBA8};
+
+    let mut buffer = Cursor::new(Vec::new());
+    let dimensions = (image.width(), image.height());
+    let write_result = match image {
+        DynamicImage::ImageRgb8(img) => write_tiff_image::<RGB8, D>(
+            &mut buffer,
+            dimensions,
+            img.as_raw(),
+            compression,
+            image_sizing,
+            metadata,
+        ),
+        DynamicImage::ImageRgba8(img) => write_tiff_image::<RGBA8, D>(
+            &mut buffer,
+            dimensions,
+            img.as_raw(),
+            compression,
+            image_sizing,
+            metadata,
+        ),
+        DynamicImage::ImageRgb16(img) => write_tiff_image::<RGB16, D>(
+            &mut buffer,
+            dimensions,
+            img.as_raw(),
+            compression,
+            image_sizing,
+            metadata,
+        ),
+        DynamicImage::ImageRgba16(img) => write_tiff_image::<RGBA16, D>(
+            &mut buffer,
+            dimensions,
+            img.as_raw(),
+            compression,
+            image_sizing,
+            metadata,
+        ),
+        _ => {
+            return Err(ExportError::encode(
+                "tiff",
+                format!("Unsupported color type for tiff {:?}", image.color()),
+            ))
+        }
+    };
+
+    match write_result {
+        Ok(_) => containers::embed_in_tiff(&buffer.into_inner(), metadata)
+            .map_err(|e| ExportError::encode("tiff", e)),
+        Err(e) => Err(ExportError::encode("tiff", e)),
+    }
+}
+
+fn encode_tiff(
+    image: &DynamicImage,
     export_settings: &ExportSettings,
-) -> Result<(), String> {
-    let parent_folder_path = export_file_path.parent().unwrap();
-    if std::fs::create_dir_all(parent_folder_path).is_err() {
-        return Err(format!("Error creating folder {export_file_path:?}"));
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, ExportError> {
+    use tiff::encoder::compression::{Deflate, DeflateLevel, Lzw, Uncompressed};
+
+    let image_sizing = &export_settings.image_sizing;
+    match export_settings.file_settings.tiff.compression {
+        TiffCompression::None => {
+            encode_tiff_with_compression(image, Uncompressed, image_sizing, metadata)
+        }
+        TiffCompression::Lzw => encode_tiff_with_compression(image, Lzw, image_sizing, metadata),
+        TiffCompression::Deflate => encode_tiff_with_compression(
+            image,
+            Deflate::with_level(DeflateLevel::Balanced),
+            image_sizing,
+            metadata,
+        ),
     }
+}
 
-    let image_format = &export_settings.file_settings.image_format;
-    info!("Saving exported image to {export_file_path:?}");
+/// Map the 0-100 quality to a butteraugli distance using the same formula as cjxl
+fn jpeg_xl_distance_from_quality(quality: u8) -> f32 {
+    let quality = f32::from(quality.min(100));
+    if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
+    } else {
+        6.4 + 2.5_f32.powf((30.0 - quality) / 5.0) / 6.25
+    }
+}
 
-    match image_format {
-        ExportImageFormat::Jpeg => {
-            let quality = export_settings.file_settings.quality;
-            let mut buffer = Vec::new();
-            let mut encoder =
-                image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
-            encoder.encode_image(&image_file).unwrap();
-            let save_result = std::fs::write(export_file_path, buffer);
-
-            match save_result {
-                Ok(_) => {
-                    info!("Image successfully saved to {export_file_path:?}");
-                    Ok(())
-                }
-                Err(e) => Err(format!("Error saving image - {e}")),
+fn jpeg_xl_speed(effort: u8) -> jpegxl_rs::encode::EncoderSpeed {
+    use jpegxl_rs::encode::EncoderSpeed;
+
+    match effort.clamp(1, 9) {
+        1 => EncoderSpeed::Lightning,
+        2 => EncoderSpeed::Thunder,
+        3 => EncoderSpeed::Falcon,
+        4 => EncoderSpeed::Cheetah,
+        5 => EncoderSpeed::Hare,
+        6 => EncoderSpeed::Wombat,
+        7 => EncoderSpeed::Squirrel,
+        8 => EncoderSpeed::Kitten,
+        _ => EncoderSpeed::Tortoise,
+    }
+}
+
+/// Encode an 8 bit rgb(a) image. jpegxl-rs can't tag images with an icc profile, so this goes
+/// through libjxl directly, see `jpeg_xl_encoder`
+fn encode_jpeg_xl(
+    image: &DynamicImage,
+    file_settings: &FileSettings,
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, ExportError> {
+    let jpeg_xl_settings = &file_settings.jpeg_xl;
+    let options = jpeg_xl_encoder::JxlFrameOptions {
+        distance: jpeg_xl_settings
+            .distance
+            .unwrap_or_else(|| jpeg_xl_distance_from_quality(file_settings.quality))
+            .clamp(0.0, 25.0),
+        lossless: jpeg_xl_settings.lossless,
+        effort: jpeg_xl_settings.effort,
+    };
+    let icc_profile = match &metadata.icc_profile {
+        Some(icc_profile) => icc_profile.clone(),
+        None => color_management::color_space_icc_profile(ColorSpace::Srgb)
+            .map_err(ExportError::color_conversion)?,
+    };
+    let (pixels, has_alpha) = match image {
+        DynamicImage::ImageRgb8(buffer) => (buffer.as_raw(), false),
+        DynamicImage::ImageRgba8(buffer) => (buffer.as_raw(), true),
+        _ => {
+            return Err(ExportError::encode(
+                "jpeg xl",
+                format!("Unsupported color type for jpeg xl {:?}", image.color()),
+            ))
+        }
+    };
+    jpeg_xl_encoder::encode_rgb8(
+        pixels,
+        image.width(),
+        image.height(),
+        has_alpha,
+        &icc_profile,
+        &options,
+    )
+    .map_err(|e| ExportError::encode("jpeg xl", e))
+}
+
+/// Repack a jpeg bitstream into jpeg xl. This keeps the original DCT coefficients, so the result
+/// decodes to exactly the same pixels while being ~20% smaller, and the jpeg can be reconstructed
+/// bit for bit from it.
+fn transcode_jpeg_to_jpeg_xl(
+    jpeg_data: &[u8],
+    file_settings: &FileSettings,
+) -> Result<Vec<u8>, ExportError> {
+    let encoder = jpegxl_rs::encoder_builder()
+        .speed(jpeg_xl_speed(file_settings.jpeg_xl.effort))
+        .build();
+    let mut encoder = match encoder {
+        Ok(encoder) => encoder,
+        Err(e) => return Err(ExportError::encode("jpeg xl", e)),
+    };
+    match encoder.encode_jpeg(jpeg_data) {
+        Ok(encoded_image) => Ok(encoded_image.data),
+        Err(e) => Err(ExportError::encode("jpeg xl", e)),
+    }
+}
+
+/// Repack the jpeg at `image_path`, whose content is `jpeg_data`, as a jpeg xl. The jpeg's
+/// metadata segments are carried over into the jpeg xl file, so they are swapped for the ones
+/// the metadata policy keeps. The pixels aren't rotated, so the source orientation stays.
+fn transcode_jpeg_source(
+    image_path: &Path,
+    jpeg_data: &[u8],
+    metadata_policy: MetadataPolicy,
+    file_settings: &FileSettings,
+) -> Result<Vec<u8>, ExportError> {
+    let source_metadata = metadata::read_source_metadata(image_path, SourceContainer::Jpeg);
+    let embedded_metadata = metadata::export_metadata(&source_metadata, metadata_policy, false);
+    let jpeg_data = containers::strip_jpeg_metadata(jpeg_data)
+        .and_then(|jpeg| containers::embed_in_jpeg(&jpeg, &embedded_metadata))
+        .map_err(|e| ExportError::encode("jpeg xl", e))?;
+    transcode_jpeg_to_jpeg_xl(&jpeg_data, file_settings)
+}
+
+/// Copy the pixels of an rgb(a) image into an interleaved libheif plane. 10 bit planes are
+/// scaled down from 16 bit samples and stored big endian in 16 bits each.
+fn fill_heif_plane(
+    image: &DynamicImage,
+    plane_data: &mut [u8],
+    stride: usize,
+    bit_depth: HeicBitDepth,
+) {
+    let has_alpha = image.color().has_alpha();
+    let channels = if has_alpha { 4 } else { 3 };
+    let width = image.width() as usize;
+    match bit_depth {
+        HeicBitDepth::Eight => {
+            let src = if has_alpha {
+                image.to_rgba8().into_raw()
+            } else {
+                image.to_rgb8().into_raw()
+            };
+            let row_length = width * channels;
+            for (y, src_row) in src.chunks_exact(row_length).enumerate() {
+                let row_start = y * stride;
+                plane_data[row_start..row_start + row_length].copy_from_slice(src_row);
             }
         }
-        ExportImageFormat::Png => {
-            let save_result = image_file.save_with_format(export_file_path, ImageFormat::Png);
-            match save_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(format!("Error saving image - {e}")),
+        HeicBitDepth::Ten => {
+            let src = if has_alpha {
+                image.to_rgba16().into_raw()
+            } else {
+                image.to_rgb16().into_raw()
+            };
+            let row_length = width * channels;
+            for (y, src_row) in src.chunks_exact(row_length).enumerate() {
+                let row_start = y * stride;
+                for (x, sample) in src_row.iter().enumerate() {
+                    // Round 16 bit samples down to 10 bits
+                    let sample = ((u32::from(*sample) + 32) >> 6).min(1023) as u16;
+                    let offset = row_start + x * 2;
+                    plane_data[offset..offset + 2].copy_from_slice(&sample.to_be_bytes());
+                }
             }
         }
     }
 }
+
+fn encode_heic(
+    image: &DynamicImage,
+    file_settings: &FileSettings,
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, ExportError> {
+    let heic_settings = &file_settings.heic;
+    let has_alpha = image.color().has_alpha();
+    let (width, height) = (image.width(), image.height());
+    let (chroma, bit_depth) = match (heic_settings.bit_depth, has_alpha) {
+        (HeicBitDepth::Eight, false) => (RgbChroma::Rgb, 8),
+        (HeicBitDepth::Eight, true) => (RgbChroma::Rgba, 8),
+        (HeicBitDepth::Ten, false) => (RgbChroma::HdrRgbBe, 10),
+        (HeicBitDepth::Ten, true) => (RgbChroma::HdrRgbaBe, 10),
+    };
+
+    let mut heif_image = HeifImage::new(width, height, HeifColorSpace::Rgb(chroma))
+        .map_err(|e| ExportError::encode("heic", e))?;
+    heif_image
+        .create_plane(HeifChannel::Interleaved, width, height, bit_depth)
+        .map_err(|e| ExportError::encode("heic", e))?;
+    {
+        let planes = heif_image.planes_mut();
+        let Some(plane) = planes.interleaved else {
+            return Err(ExportError::encode(
+                "heic",
+                "Error getting interleaved plane of heif image",
+            ));
+        };
+        fill_heif_plane(image, plane.data, plane.stride, heic_settings.bit_depth);
+    }
+    if let Some(icc_profile) = &metadata.icc_profile {
+        heif_image
+            .set_color_profile_raw(&ColorProfileRaw::new(
+                color_profile_types::PROF,
+                icc_profile.clone(),
+            ))
+            .map_err(|e| ExportError::encode("heic", e))?;
+    }
+
+    let lib_heif = LibHeif::new();
+    let mut encoder = lib_heif
+        .encoder_for_format(CompressionFormat::Hevc)
+        .map_err(|e| ExportError::encode("heic", e))?;
+    let (quality, chroma) = if heic_settings.lossless {
+        (EncoderQuality::LossLess, HeicChroma::C444)
+    } else {
+        (
+            EncoderQuality::Lossy(file_settings.quality.min(100)),
+            heic_settings.chroma,
+        )
+    };
+    encoder
+        .set_quality(quality)
+        .map_err(|e| ExportError::encode("heic", e))?;
+    let chroma = match chroma {
+        HeicChroma::C420 => "420",
+        HeicChroma::C422 => "422",
+        HeicChroma::C444 => "444",
+    };
+    encoder
+        .set_parameter_value("chroma", EncoderParameterValue::String(chroma.to_string()))
+        .map_err(|e| ExportError::encode("heic", e))?;
+
+    let mut context = HeifContext::new().map_err(|e| ExportError::encode("heic", e))?;
+    let handle = context
+        .encode_image(&heif_image, &mut encoder, None)
+        .map_err(|e| ExportError::encode("heic", e))?;
+    if let Some(exif) = &metadata.exif {
+        context
+            .add_exif_metadata(&handle, exif)
+            .map_err(|e| ExportError::encode("heic", e))?;
+    }
+    if let Some(xmp) = &metadata.xmp {
+        context
+            .add_xmp_metadata(&handle, xmp)
+            .map_err(|e| ExportError::encode("heic", e))?;
+    }
+    context
+        .write_to_bytes()
+        .map_err(|e| ExportError::encode("heic", e))
+}
+
+fn is_jpeg_data(data: &[u8]) -> bool {
+    data.starts_with(&[0xFF, 0xD8, 0xFF])
+}
+
+fn is_path_taken(path: &Path, claimed_paths: &HashSet<PathBuf>) -> bool {
+    path.exists() || claimed_paths.contains(path)
+}
+
+/// Find a path which isn't taken yet by adding " (2)", " (3)" etc. to the file name
+fn unique_file_path(path: &Path, claimed_paths: &HashSet<PathBuf>) -> PathBuf {
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string());
+
+    let mut counter = 2;
+    loop {
+        let file_name = match &extension {
+            Some(extension) => format!("{stem} ({counter}).{extension}"),
+            None => format!("{stem} ({counter})"),
+        };
+        let candidate = parent.join(file_name);
+        if !is_path_taken(&candidate, claimed_paths) {
+            return candidate;
+        }
+        counter += 1;
+    }
+}
+
+/// Apply the `ExistingFileAction` if something already exists at the export path, or another
+/// image of the batch is going to be written there. Returns the path to write to, which is then
+/// claimed for this image, or `None` if the image should be skipped. Breaks if the export is
+/// cancelled while the user is asked.
+fn resolve_existing_export_file(
+    export_file_path: PathBuf,
+    existing_file_action: ExistingFileAction,
+    ask_existing_file_action: &dyn Fn(&Path) -> ControlFlow<(), ExistingFileAction>,
+    export_path_claims: &ExportPathClaims,
+) -> ControlFlow<(), Option<PathBuf>> {
+    let mut claimed_paths = export_path_claims.0.lock().unwrap();
+    if !is_path_taken(&export_file_path, &claimed_paths) {
+        claimed_paths.insert(export_file_path.clone());
+        return ControlFlow::Continue(Some(export_file_path));
+    }
+
+    let action = match existing_file_action {
+        ExistingFileAction::AskWhatToDo => {
+            // The rest of the batch waits at its next checkpoint while the user is asked, but
+            // images already past it can still claim paths, so the claims are checked again
+            // once there is an answer
+            drop(claimed_paths);
+            let action = ask_existing_file_action(&export_file_path)?;
+            claimed_paths = export_path_claims.0.lock().unwrap();
+            action
+        }
+        action => action,
+    };
+    let export_file_path = match action {
+        // Another image of this export may have claimed the path while the user was asked,
+        // and two images must never be written to the same file
+        ExistingFileAction::OverwriteWithoutWarning
+            if claimed_paths.contains(&export_file_path) =>
+        {
+            let new_path = unique_file_path(&export_file_path, &claimed_paths);
+            info!("{export_file_path:?} is used by another image, exporting to {new_path:?}");
+            new_path
+        }
+        ExistingFileAction::OverwriteWithoutWarning => {
+            info!("Overwriting existing file {export_file_path:?}");
+            export_file_path
+        }
+        ExistingFileAction::ChooseNewName => unique_file_path(&export_file_path, &claimed_paths),
+        // Not being able to get an answer is treated the same as skipping, so that we never
+        // overwrite a file the user hasn't agreed to
+        ExistingFileAction::Skip | ExistingFileAction::AskWhatToDo => {
+            info!("Skipping export since {export_file_path:?} already exists");
+            return ControlFlow::Continue(None);
+        }
+    };
+    claimed_paths.insert(export_file_path.clone());
+    ControlFlow::Continue(Some(export_file_path))
+}
+
+fn write_export_file(buffer: Vec<u8>, export_file_path: &Path) -> Result<(), ExportError> {
+    let parent_folder_path = export_file_path.parent().unwrap();
+    if let Err(e) = std::fs::create_dir_all(parent_folder_path) {
+        return Err(ExportError::io(
+            format!("Error creating folder {parent_folder_path:?}"),
+            e,
+        ));
+    }
+
+    // The file is written under a temporary name and moved in place once complete, so a failed
+    // or interrupted write never leaves a truncated image at the export path
+    let file_name = export_file_path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let partial_file_path = parent_folder_path.join(format!(".{file_name}.partial"));
+    let save_result = std::fs::write(&partial_file_path, buffer)
+        .and_then(|_| std::fs::rename(&partial_file_path, export_file_path));
+    match save_result {
+        Ok(_) => {
+            info!("Image successfully saved to {export_file_path:?}");
+            Ok(())
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial_file_path);
+            Err(ExportError::io(
+                format!("Error saving image {export_file_path:?}"),
+                e,
+            ))
+        }
+    }
+}
+
+/// Open an image with the image crate, rotating the pixels according to its exif orientation.
+/// libraw and libheif already do this for the files they decode.
+pub(crate) fn open_oriented_image(path: &Path) -> image::ImageResult<DynamicImage> {
+    let mut decoder = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Convert a raw file to DNG with rawler's DNG writer. The raw data is carried over as it is,
+/// so settings which change pixels, like resizing, don't apply.
+fn convert_raw_to_dng(
+    image_path: &Path,
+    dng_settings: &DngSettings,
+) -> Result<Vec<u8>, ExportError> {
+    let raw_file = std::fs::File::open(image_path)
+        .map_err(|e| ExportError::decode(DecodeBackend::Rawler, e))?;
+    let original_file_name = image_path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let params = rawler::dng::convert::ConvertParams {
+        embedded: dng_settings.embed_original,
+        compression: match dng_settings.compression {
+            DngCompression::Lossless => rawler::dng::DngCompression::Lossless,
+            DngCompression::Uncompressed => rawler::dng::DngCompression::Uncompressed,
+        },
+        preview: dng_settings.preview == DngPreview::Full,
+        thumbnail: dng_settings.preview != DngPreview::None,
+        software: "Vikara".to_string(),
+        ..Default::default()
+    };
+
+    let mut buffer = Cursor::new(Vec::new());
+    rawler::dng::convert::convert_raw_stream(
+        std::io::BufReader::new(raw_file),
+        &mut buffer,
+        original_file_name,
+        &params,
+    )
+    .map_err(|e| ExportError::encode("dng", e))?;
+    Ok(buffer.into_inner())
+}
+
+fn encode_image(
+    image_file: DynamicImage,
+    image_format: ExportImageFormat,
+    export_settings: &ExportSettings,
+    metadata: &EmbeddedMetadata,
+) -> Result<Vec<u8>, ExportError> {
+    let file_settings = &export_settings.file_settings;
+    let buffer = match image_format {
+        ExportImageFormat::Jpeg => encode_jpeg(&image_file, file_settings, metadata)?,
+        ExportImageFormat::Png => encode_png(&image_file, metadata)?,
+        ExportImageFormat::Webp => encode_webp(&image_file, file_settings, metadata)?,
+        ExportImageFormat::Avif => encode_avif(&image_file, file_settings, metadata)?,
+        ExportImageFormat::Tiff => encode_tiff(&image_file, export_settings, metadata)?,
+        ExportImageFormat::JpegXl => encode_jpeg_xl(&image_file, file_settings, metadata)?,
+        ExportImageFormat::Heic => encode_heic(&image_file, file_settings, metadata)?,
+        ExportImageFormat::Dng | ExportImageFormat::Original => {
+            return Err(not_encodable_error(image_format))
+        }
+    };
+    Ok(buffer)
+}
 pub(crate) fn export_image(
     image_path: &str,
+    sequence_index: usize,
     export_settings: &ExportSettings,
-) -> Result<(), String> {
-    let image_file;
+    ask_existing_file_action: &dyn Fn(&Path) -> ControlFlow<(), ExistingFileAction>,
+    export_path_claims: &ExportPathClaims,
+    report_stage: &dyn Fn(ExportStage) -> ControlFlow<()>,
+) -> Result<ExportOutcome, ExportError> {
     let image_path = Path::new(image_path);
     let mut export_folder = Path::new(&export_settings.export_location.folder_path);
-    let image_name_without_extension = image_path.file_stem().unwrap().to_string_lossy();
     if export_settings.export_location.folder_path.is_empty() {
         export_folder = image_path.parent().unwrap();
     }
-    let export_file_path = export_folder.join(
-        image_name_without_extension.to_string()
-            + "_exported."
-            + &export_settings.file_settings.image_format.to_string(),
-    );
-
-    if is_raw_image(image_path) {
-        image_file = load_raw_image_libraw(image_path);
-    } else if is_heif_image(image_path) {
-        image_file = load_heif_image(image_path);
+    let file_settings = &export_settings.file_settings;
+    let decoder = decoders::decoder_for(image_path)?;
+    let (image_format, keep_source_extension) =
+        resolve_export_format(image_path, decoder, file_settings);
+    if image_format == ExportImageFormat::Dng && !decoder.is_raw() {
+        return Err(ExportError::UnsupportedFormat(format!(
+            "Only raw files can be converted to DNG, not {image_path:?}"
+        )));
+    }
+    let export_extension = if keep_source_extension {
+        image_path
+            .extension()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
     } else {
-        let image_open_result = image::open(image_path);
-        if let Ok(image) = image_open_result {
-            image_file = Ok(image);
-        } else {
-            let err = image_open_result.err().unwrap();
-            eprintln!("Error loading image {:?}", err);
-            image_file = Err(format!("Error loading image {:?}", err).into());
-        }
-    }
-
-    match image_file {
-        Ok(mut image_file) => {
-            // The order of applying the settings is important
-            // Because converting image to a format and applying quality might need
-            // to be done along with saving the image, which means quality and output
-            // format should be applied at the end
-            // We should start with resizing the image
-            // We should return a vector of results since exporting some images might
-            // fail due to some reason
-            // resize_and_rotate takes the width and height arguments to mean the max width and
-            // max height of the resized image. It finds the ratios of max width / original width
-            // and max height / original height and uses the smaller of the two ratios to resize
-            // If we send any one (max width or max height) as 0, it will resize the image based on
-            // ratio calculated as per the other option
-            // If we want to use the short edge and long edge options, we can first find the short
-            // or long edge, and send the other max value as 0
-            if export_settings.image_sizing.resize_enabled {
-                let resized_image =
-                    resize_image_with_export_settings(image_file, &export_settings.image_sizing);
-
-                if let Ok(resized_image) = resized_image {
-                    image_file = resized_image;
-                } else {
-                    return Err(format!("Error resizing image {image_path:?}"));
+        image_format
+            .extension()
+            .ok_or_else(|| not_encodable_error(image_format))?
+            .to_string()
+    };
+    let export_file_name = file_naming::export_file_name(
+        image_path,
+        sequence_index,
+        &export_extension,
+        &export_settings.file_renaming,
+    )
+    .map_err(ExportError::InvalidSettings)?;
+    let export_file_path = export_folder.join(export_file_name);
+    let export_file_path = match resolve_existing_export_file(
+        export_file_path.clone(),
+        export_settings.export_location.existing_file_action,
+        ask_existing_file_action,
+        export_path_claims,
+    ) {
+        ControlFlow::Continue(Some(export_file_path)) => export_file_path,
+        ControlFlow::Continue(None) => return Ok(ExportOutcome::Skipped(export_file_path)),
+        ControlFlow::Break(()) => return Ok(ExportOutcome::Cancelled),
+    };
+
+    if image_format == ExportImageFormat::Dng {
+        info!("Converting {image_path:?} to DNG");
+        let buffer = convert_raw_to_dng(image_path, &file_settings.dng)?;
+        if report_stage(ExportStage::Encoded).is_break() {
+            return Ok(ExportOutcome::Cancelled);
+        }
+        write_export_file(buffer, &export_file_path)?;
+        return Ok(ExportOutcome::Exported(export_file_path));
+    }
+
+    if image_format == ExportImageFormat::JpegXl
+        && file_settings.jpeg_xl.lossless_jpeg_transcode
+        && !export_settings.image_sizing.resize_enabled
+    {
+        // No pixel changes are needed, so a jpeg source can be repacked as is instead of being
+        // decoded and compressed a second time
+        if let Ok(source_data) = std::fs::read(image_path) {
+            let is_in_color_space = || {
+                color_management::is_in_color_space(
+                    color_management::read_icc_profile(image_path).as_deref(),
+                    file_settings.color_space,
+                )
+            };
+            if is_jpeg_data(&source_data) && is_in_color_space() {
+                info!("Transcoding jpeg {image_path:?} losslessly to jpeg xl");
+                let buffer = transcode_jpeg_source(
+                    image_path,
+                    &source_data,
+                    export_settings.metadata_policy,
+                    file_settings,
+                )?;
+                if report_stage(ExportStage::Encoded).is_break() {
+                    return Ok(ExportOutcome::Cancelled);
                 }
+                write_export_file(buffer, &export_file_path)?;
+                return Ok(ExportOutcome::Exported(export_file_path));
             }
+        }
+    }
 
-            let image_format = &export_settings.file_settings.image_format;
-            image_file = convert_image_for_format(image_file, image_format);
-            save_image_to_disk(image_file, &export_file_path, export_settings)
+    // Raw files are developed into a color space we know, every other source may have its own
+    // icc profile
+    let decoders::DecodedImage {
+        image: mut image_file,
+        icc_profile: source_icc_profile,
+    } = decoder.decode(image_path, export_settings)?;
+    if report_stage(ExportStage::Decoded).is_break() {
+        return Ok(ExportOutcome::Cancelled);
+    }
+    // The order of applying the settings is important
+    // Because converting image to a format and applying quality might need
+    // to be done along with saving the image, which means quality and output
+    // format should be applied at the end
+    // We should start with resizing the image
+    // We should return a vector of results since exporting some images might
+    // fail due to some reason
+    // resize_and_rotate takes the width and height arguments to mean the max width and
+    // max height of the resized image. It finds the ratios of max width / original width
+    // and max height / original height and uses the smaller of the two ratios to resize
+    // If we send any one (max width or max height) as 0, it will resize the image based on
+    // ratio calculated as per the other option
+    // If we want to use the short edge and long edge options, we can first find the short
+    // or long edge, and send the other max value as 0
+    let mut left_smaller = false;
+    if export_settings.image_sizing.resize_enabled {
+        let resized_image =
+            resize_image_with_export_settings(image_file, &export_settings.image_sizing);
+
+        let (resized_image, not_enlarged) = resized_image?;
+        image_file = resized_image;
+        left_smaller = not_enlarged;
+        if report_stage(ExportStage::Resized).is_break() {
+            return Ok(ExportOutcome::Cancelled);
         }
-        Err(e) => Err(format!("Error opening image {image_path:?} {e:?}")),
+    }
+
+    let color_space = file_settings.color_space;
+    image_file = color_management::convert_to_color_space(
+        image_file,
+        source_icc_profile.as_deref(),
+        color_space,
+    )
+    .map_err(ExportError::color_conversion)?;
+    image_file = convert_image_for_format(image_file, image_format, file_settings)?;
+
+    let source_metadata =
+        metadata::read_source_metadata(image_path, decoder.source_container(image_path));
+    let mut embedded_metadata =
+        metadata::export_metadata(&source_metadata, export_settings.metadata_policy, true);
+    // sRGB is the default for files without a profile, but tagging it explicitly keeps
+    // color managed apps from guessing
+    embedded_metadata.icc_profile = Some(
+        color_management::color_space_icc_profile(color_space)
+            .map_err(ExportError::color_conversion)?,
+    );
+    let buffer = encode_image(
+        image_file,
+        image_format,
+        export_settings,
+        &embedded_metadata,
+    )?;
+    if report_stage(ExportStage::Encoded).is_break() {
+        return Ok(ExportOutcome::Cancelled);
+    }
+    info!("Saving exported image to {export_file_path:?}");
+    write_export_file(buffer, &export_file_path)?;
+    if left_smaller {
+        Ok(ExportOutcome::ExportedSmallerThanTarget(export_file_path))
+    } else {
+        Ok(ExportOutcome::Exported(export_file_path))
     }
 }
 
 /// Loads a raw image from a path as image::DynamicImage
-/// We are loading the jpeg embedded inside the raw image until we figure out a
-/// good way to recreate the raw as a jpeg
-/// TODO: Handle dng files separately. Using get_image_from_python_script.
-pub fn load_raw_image_embedded_jpeg(
-    path: &Path,
-) -> Result<DynamicImage, Box<dyn std::error::Error + Send + Sync>> {
-    let raw_source = rawler::rawsource::RawSource::new(path)?;
-    let decoder = rawler::get_decoder(&raw_source)?;
+/// Used for previews of raw files `raw_develop` can't develop. For DNGs this is the preview
+/// image the DNG carries.
+pub fn load_raw_image_embedded_jpeg(path: &Path) -> Result<DynamicImage, ExportError> {
+    let decode_error = |e: BoxError| ExportError::decode(DecodeBackend::Rawler, e);
+    let raw_source = rawler::rawsource::RawSource::new(path).map_err(|e| decode_error(e.into()))?;
+    let decoder = rawler::get_decoder(&raw_source).map_err(|e| decode_error(e.into()))?;
     let params = rawler::decoders::RawDecodeParams::default();
     let metadata = decoder.raw_metadata(&raw_source, &params);
     // The full_image function simply returns the embedded jpeg inside the raw file
     // It does not decode the raw file and recreate the jpeg using raw data
     // https://github.com/dnglab/dnglab/blob/fc63ad95643e8e16bf8ba0831c9d7fa47a6ca2da/rawler/src/decoders/raf.rs#L437
-    let image_res = decoder.full_image(&raw_source, &params)?;
+    let image_res = decoder
+        .full_image(&raw_source, &params)
+        .map_err(|e| decode_error(e.into()))?;
 
     // For some reason rawler sets image orientation to Normal for all kinds of images
     // So we need to rotate image ourselves by reading the metadata
     match image_res {
-        Some(mut img) => {
-            if let Ok(metadata) = metadata {
-                match (metadata.exif.orientation.unwrap_or(0)).into() {
-                    0 => {}
-                    1 => {}
-                    2 => {}
-                    3 => {
-                        img = img.rotate180();
-                    }
-                    4 => {}
-                    5 => {}
-                    6 => {
-                        img = img.rotate90();
-                    }
-                    7 => {}
-                    8 => {
-                        img = img.rotate270();
-                    }
-                    _ => {}
-                }
-            }
-            Ok(img)
-        }
-        None => Err("Error getting embedded jpeg from raw".into()),
+        Some(img) => match metadata {
+            Ok(metadata) => Ok(rotate_to_orientation(
+                img,
+                metadata.exif.orientation.unwrap_or(0),
+            )),
+            Err(_) => Ok(img),
+        },
+        None => Err(decode_error("Error getting embedded jpeg from raw".into())),
+    }
+}
+
+/// Rotate an image as per its exif orientation. Mirrored orientations are left as they are.
+pub(crate) fn rotate_to_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        3 => img.rotate180(),
+        6 => img.rotate90(),
+        8 => img.rotate270(),
+        _ => img,
     }
 }
-/// For non raw images, simply reading the file from disk and encoding it as base64
-/// is enough. For raw images, we need to decode the raw image and then encode it as base64
+/// For images the webview can show, simply reading the file from disk and encoding it as base64
+/// is enough. For others like raw images, we need to decode the image and then encode it as base64
 /// TODO: What if the image has a orientation embedded in it?
 /// I guess one downside to this approach is that we cannot resize the image before sending
 /// it across. Sometimes we might want to resize image or make it's quality lower before sending
 /// to frontend. Only when the user tries to zoom in or something, we can send the full quality image
 pub fn load_image_as_base64(
     image_path: &str,
-) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    raw_develop: &RawDevelopSettings,
+) -> Result<String, ExportError> {
     let path = Path::new(image_path);
-    if !is_raw_image(path) {
-        let start = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        // Open the file
-        let mut file = std::fs::File::open(path)?;
-        let end = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        info!("Time to read image file {:?}", end - start);
-
-        let start = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        // Read the file contents into a buffer
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        let end = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        info!("Time to write image file to buffer {:?}", end - start);
-
-        // Encode the buffer into a Base64 string
-        // let res_base64 = base64::encode(&buffer, general_purpose::STANDARD);
-        let res_base64 = general_purpose::STANDARD.encode(buffer);
-        Ok(res_base64)
-    } else {
-        if Some("dng") == path.extension().and_then(OsStr::to_str) {
-            return Err("Unsupported format: dng".into());
+    let start = Instant::now();
+    // Formats the webview can't show, like raw files, get a decoded preview
+    let preview =
+        decoders::decoder_for(path).and_then(|decoder| decoder.preview(path, raw_develop));
+    let img = match preview {
+        Ok(Some(img)) => img,
+        Ok(None) => {
+            // Open the file
+            let mut file = std::fs::File::open(path)
+                .map_err(|e| ExportError::read(format!("Error opening image {path:?}"), e))?;
+            info!("Time to read image file {:?}", start.elapsed());
+
+            let start = Instant::now();
+            // Read the file contents into a buffer
+            let mut buffer = Vec::new();
+            file.read_to_end(&mut buffer)
+                .map_err(|e| ExportError::read(format!("Error reading image {path:?}"), e))?;
+            info!("Time to write image file to buffer {:?}", start.elapsed());
+
+            // Encode the buffer into a Base64 string
+            // let res_base64 = base64::encode(&buffer, general_purpose::STANDARD);
+            let res_base64 = general_purpose::STANDARD.encode(buffer);
+            return Ok(res_base64);
+        }
+        Err(e) => {
+            warn!("Error reading image {:?}", e);
+            return Err(e);
         }
-        let start = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        let img = load_raw_image_embedded_jpeg(path);
+    };
+    info!("Time to read image {:?}", start.elapsed());
+    let start = Instant::now();
+    let mut image_buffer = Cursor::new(Vec::new());
+    let image_format = ImageFormat::Jpeg;
+    info!("Image format {image_format:?}");
+    // TODO: I think we don't need to write ImageBuffer to another buffer
+    img.write_to(&mut image_buffer, image_format)
+        .map_err(|e| ExportError::encode("jpeg", e))?;
+    info!("Time to write image to buffer {:?}", start.elapsed());
+    let start = Instant::now();
+    let res_base64 = general_purpose::STANDARD.encode(image_buffer.get_ref());
+    info!("Time to base64 encode the image {:?}", start.elapsed());
+    Ok(res_base64)
+}
 
-        let img = match img {
-            Ok(img) => img,
-            Err(e) => {
-                warn!("Error reading image {:?}", e);
-                return Err(format!("Error reading image {:?}", e).into());
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{Field, In, Tag, Value};
+    use std::io::Cursor;
+
+    fn file_settings(image_format: &str) -> FileSettings {
+        serde_json::from_value(serde_json::json!({
+            "imageFormat": image_format,
+            "quality": 90,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn jpeg_transcode_keeps_source_orientation() {
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .encode(&[100; 8 * 4 * 3], 8, 4, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let orientation = Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![6]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&orientation);
+        let mut exif = Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+        let metadata = EmbeddedMetadata {
+            exif: Some(exif.into_inner()),
+            ..EmbeddedMetadata::default()
+        };
+        let jpeg = containers::embed_in_jpeg(&jpeg, &metadata).unwrap();
+
+        for policy in [MetadataPolicy::KeepAll, MetadataPolicy::StripAll] {
+            let path = std::env::temp_dir().join(format!(
+                "vikara-orientation-{}-{policy:?}.jpg",
+                std::process::id()
+            ));
+            std::fs::write(&path, &jpeg).unwrap();
+            let jpeg_xl = transcode_jpeg_source(&path, &jpeg, policy, &file_settings("jpeg_xl"));
+            std::fs::remove_file(&path).unwrap();
+
+            // The jpeg is stored for reconstruction along with its exif, which is where the
+            // orientation of the untouched pixels comes from
+            let decoder = jpegxl_rs::decoder_builder().build().unwrap();
+            let (_, data) = decoder.reconstruct(&jpeg_xl.unwrap()).unwrap();
+            let jpegxl_rs::decode::Data::Jpeg(reconstructed) = data else {
+                panic!("No jpeg reconstruction data for {policy:?}");
+            };
+            let exif = exif::Reader::new()
+                .read_from_container(&mut Cursor::new(reconstructed))
+                .unwrap();
+            let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+            assert_eq!(orientation.value.get_uint(0), Some(6), "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn rgb16_to_ycbcr_keeps_more_than_8_bits() {
+        assert_eq!(rgb16_to_10_bit_ycbcr([0; 3]), [0, 512, 512]);
+        assert_eq!(rgb16_to_10_bit_ycbcr([u16::MAX; 3]), [1023, 512, 512]);
+        // Less than one 8 bit step apart, but two 10 bit steps
+        let darker = rgb16_to_10_bit_ycbcr([32768; 3]);
+        let brighter = rgb16_to_10_bit_ycbcr([32768 + 128; 3]);
+        assert_eq!(brighter[0] - darker[0], 2);
+    }
+
+    #[test]
+    fn ten_bit_avif_is_encoded_from_16_bit_samples() {
+        let settings = file_settings("avif");
+        let gradient = image::ImageBuffer::from_fn(16, 8, |x, y| {
+            image::Rgba([(x * 4096) as u16, (y * 8192) as u16, 30000, u16::MAX])
+        });
+        let image = convert_image_for_format(
+            DynamicImage::ImageRgba16(gradient),
+            ExportImageFormat::Avif,
+            &settings,
+        )
+        .unwrap();
+        assert!(matches!(image, DynamicImage::ImageRgba16(_)));
+        let avif = encode_avif(&image, &settings, &EmbeddedMetadata::default()).unwrap();
+        assert_eq!(&avif[4..8], b"ftyp");
+    }
+
+    #[test]
+    fn formats_without_pixels_of_their_own_are_errors_not_panics() {
+        assert_eq!(ExportImageFormat::Original.extension(), None);
+        for image_format in [ExportImageFormat::Dng, ExportImageFormat::Original] {
+            let image = DynamicImage::new_rgb8(2, 2);
+            assert!(matches!(
+                convert_image_for_format(image, image_format, &file_settings("jpeg")),
+                Err(ExportError::UnsupportedFormat(_))
+            ));
+        }
+    }
+
+    fn image_sizing(resize_to_fit: ResizeToFitOption, enlarge: bool) -> ImageSizing {
+        ImageSizing {
+            resize_enabled: true,
+            resize_to_fit,
+            enlarge,
+            resize_width: 0.0,
+            resize_height: 0.0,
+            resize_size: 0.0,
+            resize_size_megapixels: 0.0,
+            resize_in: ResizeIn::Pixels,
+            resize_resolution: 300.0,
+            resize_resolution_in: ResizeInOption::PixelsPerInch,
+            resampling_filter: ResamplingFilter::default(),
+            linear_light: false,
+        }
+    }
+
+    #[test]
+    fn fit_size_keeps_the_aspect_ratio() {
+        assert_eq!(fit_size((4000, 3000), (1000, 1000)), (1000, 750));
+        assert_eq!(fit_size((3000, 4000), (1000, 1000)), (750, 1000));
+        // 0 leaves a dimension unrestricted
+        assert_eq!(fit_size((3000, 4000), (1000, 0)), (1000, 1333));
+        assert_eq!(fit_size((3000, 4000), (0, 1000)), (750, 1000));
+        assert_eq!(fit_size((3000, 4000), (0, 0)), (3000, 4000));
+        // Smaller images are scaled up to fit
+        assert_eq!(fit_size((400, 300), (1000, 1000)), (1000, 750));
+        // Very thin images keep at least one pixel
+        assert_eq!(fit_size((10000, 1), (100, 100)), (100, 1));
+    }
+
+    #[test]
+    fn restrict_size_only_enlarges_when_asked() {
+        assert_eq!(restrict_size((400, 300), (1000, 1000), false), (400, 300));
+        assert_eq!(restrict_size((400, 300), (1000, 1000), true), (1000, 750));
+        assert_eq!(
+            restrict_size((4000, 3000), (1000, 1000), false),
+            (1000, 750)
+        );
+    }
+
+    #[test]
+    fn dimensions_turn_the_box_to_the_image() {
+        let sizing = ImageSizing {
+            resize_width: 1500.0,
+            resize_height: 1000.0,
+            ..image_sizing(ResizeToFitOption::Dimensions, false)
+        };
+        assert_eq!(
+            max_size_for_settings((3000, 2000), &sizing).unwrap(),
+            (1500, 1000)
+        );
+        assert_eq!(
+            max_size_for_settings((2000, 3000), &sizing).unwrap(),
+            (1000, 1500)
+        );
+        // Unlike width and height, where a portrait image is fit into the landscape box
+        let sizing = ImageSizing {
+            resize_to_fit: ResizeToFitOption::WidthAndHeight,
+            ..sizing
+        };
+        let max_size = max_size_for_settings((2000, 3000), &sizing).unwrap();
+        assert_eq!(fit_size((2000, 3000), max_size), (667, 1000));
+    }
+
+    #[test]
+    fn edges_restrict_one_dimension() {
+        let sizing = ImageSizing {
+            resize_size: 1000.0,
+            ..image_sizing(ResizeToFitOption::LongEdge, false)
+        };
+        assert_eq!(
+            max_size_for_settings((4000, 3000), &sizing).unwrap(),
+            (1000, 0)
+        );
+        assert_eq!(
+            max_size_for_settings((3000, 4000), &sizing).unwrap(),
+            (0, 1000)
+        );
+        let sizing = ImageSizing {
+            resize_to_fit: ResizeToFitOption::ShortEdge,
+            ..sizing
+        };
+        assert_eq!(
+            max_size_for_settings((4000, 3000), &sizing).unwrap(),
+            (0, 1000)
+        );
+    }
+
+    #[test]
+    fn sizes_in_inches_use_the_resolution() {
+        let sizing = ImageSizing {
+            resize_size: 2.0,
+            resize_in: ResizeIn::Inches,
+            ..image_sizing(ResizeToFitOption::LongEdge, false)
+        };
+        assert_eq!(
+            max_size_for_settings((4000, 3000), &sizing).unwrap(),
+            (600, 0)
+        );
+    }
+
+    #[test]
+    fn megapixels_keep_the_aspect_ratio() {
+        let sizing = ImageSizing {
+            resize_size_megapixels: 3.0,
+            ..image_sizing(ResizeToFitOption::Megapixels, false)
         };
-        let end = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        info!("Time to read image {:?}", end - start);
-        let start = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        let mut image_buffer = Cursor::new(Vec::new());
-        let image_format = ImageFormat::Jpeg;
-        info!("Image format {image_format:?}");
-        // TODO: I think we don't need to write ImageBuffer to another buffer
-        img.write_to(&mut image_buffer, image_format)
-            .expect("Unable to write image to buffer");
-        let end = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        info!("Time to write image to buffer {:?}", end - start);
-        let start = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        let res_base64 = general_purpose::STANDARD.encode(image_buffer.get_ref());
-        let end = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        info!("Time to base64 encode the image {:?}", end - start);
-        Ok(res_base64)
+        let max_size = max_size_for_settings((4000, 3000), &sizing).unwrap();
+        assert_eq!(fit_size((4000, 3000), max_size), (2000, 1500));
+    }
+
+    #[test]
+    fn megapixels_of_images_with_more_pixels_than_a_u32_holds() {
+        let sizing = ImageSizing {
+            resize_size_megapixels: 24.0,
+            ..image_sizing(ResizeToFitOption::Megapixels, false)
+        };
+        let (max_width, max_height) = max_size_for_settings((80_000, 60_000), &sizing).unwrap();
+        let megapixels = f64::from(max_width) * f64::from(max_height) / 1_000_000.0;
+        assert!((23.9..=24.0).contains(&megapixels), "{megapixels}");
+        assert_eq!((max_width, max_height), (5656, 4242));
+    }
+
+    #[test]
+    fn sizes_which_arent_positive_are_rejected() {
+        for resize_width in [0.0, -100.0, f32::NAN, f32::INFINITY] {
+            let sizing = ImageSizing {
+                resize_width,
+                resize_height: 1000.0,
+                ..image_sizing(ResizeToFitOption::WidthAndHeight, false)
+            };
+            assert!(matches!(
+                max_size_for_settings((4000, 3000), &sizing),
+                Err(ExportError::InvalidSettings(_))
+            ));
+        }
+        let less_than_a_pixel = ImageSizing {
+            resize_width: 0.5,
+            resize_height: 0.5,
+            ..image_sizing(ResizeToFitOption::WidthAndHeight, false)
+        };
+        assert!(matches!(
+            max_size_for_settings((4000, 3000), &less_than_a_pixel),
+            Err(ExportError::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn small_images_are_left_smaller_unless_enlarging() {
+        let image = DynamicImage::new_rgb8(10, 10);
+        let sizing = ImageSizing {
+            resize_width: 20.0,
+            resize_height: 20.0,
+            ..image_sizing(ResizeToFitOption::WidthAndHeight, false)
+        };
+        let (resized, left_smaller) =
+            resize_image_with_export_settings(image.clone(), &sizing).unwrap();
+        assert_eq!(
+            (resized.width(), resized.height(), left_smaller),
+            (10, 10, true)
+        );
+
+        let sizing = ImageSizing {
+            enlarge: true,
+            ..sizing
+        };
+        let (resized, left_smaller) = resize_image_with_export_settings(image, &sizing).unwrap();
+        assert_eq!(
+            (resized.width(), resized.height(), left_smaller),
+            (20, 20, false)
+        );
+    }
+
+    #[test]
+    fn empty_and_huge_resizes_are_rejected() {
+        let filter = ResamplingFilter::default();
+        assert!(matches!(
+            resize_image(DynamicImage::new_rgb8(0, 10), 5, 5, filter, false),
+            Err(ExportError::Resize { .. })
+        ));
+        assert!(matches!(
+            resize_image(DynamicImage::new_rgb8(10, 10), 0, 5, filter, false),
+            Err(ExportError::InvalidSettings(_))
+        ));
+        // Checked before anything is allocated for the resized image
+        assert!(matches!(
+            resize_image(
+                DynamicImage::new_rgb8(10, 10),
+                40_000,
+                30_000,
+                filter,
+                false
+            ),
+            Err(ExportError::InvalidSettings(_))
+        ));
     }
 }
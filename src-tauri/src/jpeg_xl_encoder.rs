@@ -0,0 +1,212 @@
+use crate::export_error::BoxError;
+use jpegxl_sys::common::types::{JxlBool, JxlDataType, JxlEndianness, JxlPixelFormat};
+use jpegxl_sys::encoder::encode::{
+    JxlEncoder, JxlEncoderAddImageFrame, JxlEncoderCloseInput, JxlEncoderCreate, JxlEncoderDestroy,
+    JxlEncoderFrameSettingId, JxlEncoderFrameSettingsCreate, JxlEncoderFrameSettingsSetOption,
+    JxlEncoderInitBasicInfo, JxlEncoderProcessOutput, JxlEncoderSetBasicInfo,
+    JxlEncoderSetFrameDistance, JxlEncoderSetFrameLossless, JxlEncoderSetICCProfile,
+    JxlEncoderSetParallelRunner, JxlEncoderStatus,
+};
+use jpegxl_sys::metadata::codestream_header::JxlBasicInfo;
+use jpegxl_sys::threads::thread_parallel_runner::{
+    JxlThreadParallelRunner, JxlThreadParallelRunnerCreate,
+    JxlThreadParallelRunnerDefaultNumWorkerThreads, JxlThreadParallelRunnerDestroy,
+};
+use std::ffi::c_void;
+use std::mem::MaybeUninit;
+use std::ptr::{self, NonNull};
+
+fn check(status: JxlEncoderStatus, step: &str) -> Result<(), BoxError> {
+    if matches!(status, JxlEncoderStatus::Success) {
+        Ok(())
+    } else {
+        Err(format!("libjxl error {step}").into())
+    }
+}
+
+/// How a frame is compressed
+pub struct JxlFrameOptions {
+    /// Butteraugli distance, ignored for lossless frames
+    pub distance: f32,
+    pub lossless: bool,
+    /// Encoder effort from 1 (fastest) to 9 (slowest)
+    pub effort: u8,
+}
+
+/// A libjxl encoder running on its own thread pool
+struct Encoder {
+    encoder: NonNull<JxlEncoder>,
+    runner: *mut c_void,
+}
+impl Encoder {
+    fn new() -> Result<Self, BoxError> {
+        // SAFETY: a null memory manager makes libjxl use its default allocator. It returns null
+        // if it can't allocate.
+        let encoder = NonNull::new(unsafe { JxlEncoderCreate(ptr::null()) })
+            .ok_or("Error creating libjxl encoder")?;
+        // SAFETY: as above. A null runner is handled by encoding on the calling thread.
+        let runner = unsafe {
+            JxlThreadParallelRunnerCreate(
+                ptr::null(),
+                JxlThreadParallelRunnerDefaultNumWorkerThreads(),
+            )
+        };
+        let encoder = Encoder { encoder, runner };
+        if !runner.is_null() {
+            check(
+                // SAFETY: both pointers are live and are only destroyed together with `encoder`,
+                // which destroys the encoder before the runner it uses
+                unsafe {
+                    JxlEncoderSetParallelRunner(
+                        encoder.encoder.as_ptr(),
+                        JxlThreadParallelRunner,
+                        runner,
+                    )
+                },
+                "setting up threads",
+            )?;
+        }
+        Ok(encoder)
+    }
+
+    /// Collect the encoded file once all input has been added
+    fn output(&mut self) -> Result<Vec<u8>, BoxError> {
+        let mut output = vec![0u8; 64 * 1024];
+        let mut written = 0;
+        loop {
+            // SAFETY: `written` is never more than `output.len()`
+            let mut next_out = unsafe { output.as_mut_ptr().add(written) };
+            let mut avail_out = output.len() - written;
+            // SAFETY: libjxl writes at most `avail_out` bytes from `next_out`, which stay within
+            // `output`, and updates both to where it stopped
+            let status = unsafe {
+                JxlEncoderProcessOutput(self.encoder.as_ptr(), &mut next_out, &mut avail_out)
+            };
+            written = output.len() - avail_out;
+            match status {
+                JxlEncoderStatus::Success => break,
+                JxlEncoderStatus::NeedMoreOutput => output.resize(output.len() * 2, 0),
+                _ => return Err("libjxl error encoding the image".into()),
+            }
+        }
+        output.truncate(written);
+        Ok(output)
+    }
+}
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        // SAFETY: both were created in `new` and are destroyed only here, the encoder first
+        // since it uses the runner
+        unsafe {
+            JxlEncoderDestroy(self.encoder.as_ptr());
+            if !self.runner.is_null() {
+                JxlThreadParallelRunnerDestroy(self.runner);
+            }
+        }
+    }
+}
+
+/// Encode interleaved 8 bit rgb or rgba pixels, tagged with `icc_profile`. Lossy frames are
+/// stored in XYB and converted back to the profile's color space when decoded.
+pub fn encode_rgb8(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    icc_profile: &[u8],
+    options: &JxlFrameOptions,
+) -> Result<Vec<u8>, BoxError> {
+    let mut encoder = Encoder::new()?;
+    let jxl_encoder = encoder.encoder.as_ptr();
+
+    let mut basic_info = MaybeUninit::<JxlBasicInfo>::uninit();
+    // SAFETY: JxlEncoderInitBasicInfo fills in every field of the struct
+    let mut basic_info = unsafe {
+        JxlEncoderInitBasicInfo(basic_info.as_mut_ptr());
+        basic_info.assume_init()
+    };
+    basic_info.xsize = width;
+    basic_info.ysize = height;
+    basic_info.bits_per_sample = 8;
+    basic_info.exponent_bits_per_sample = 0;
+    basic_info.num_color_channels = 3;
+    if has_alpha {
+        basic_info.num_extra_channels = 1;
+        basic_info.alpha_bits = 8;
+    }
+    // Lossless frames have to keep the pixels in their own color space instead of XYB
+    basic_info.uses_original_profile = if options.lossless {
+        JxlBool::True
+    } else {
+        JxlBool::False
+    };
+    // SAFETY: for this and the other calls below, `jxl_encoder` is live as long as `encoder`,
+    // and libjxl copies whatever it is passed before returning
+    check(
+        unsafe { JxlEncoderSetBasicInfo(jxl_encoder, &basic_info) },
+        "setting the image info",
+    )?;
+    // SAFETY: see above
+    check(
+        unsafe { JxlEncoderSetICCProfile(jxl_encoder, icc_profile.as_ptr(), icc_profile.len()) },
+        "setting the icc profile",
+    )?;
+
+    // SAFETY: see above. The frame settings belong to the encoder and are freed along with it.
+    let frame_settings = unsafe { JxlEncoderFrameSettingsCreate(jxl_encoder, ptr::null()) };
+    if frame_settings.is_null() {
+        return Err("Error creating libjxl frame settings".into());
+    }
+    // SAFETY: `frame_settings` was checked to not be null, and lives as long as the encoder
+    check(
+        unsafe {
+            JxlEncoderFrameSettingsSetOption(
+                frame_settings,
+                JxlEncoderFrameSettingId::Effort,
+                i64::from(options.effort.clamp(1, 9)),
+            )
+        },
+        "setting the effort",
+    )?;
+    // libjxl wants a distance of 0 along with lossless
+    let distance = if options.lossless {
+        0.0
+    } else {
+        options.distance
+    };
+    // SAFETY: see above
+    check(
+        unsafe { JxlEncoderSetFrameDistance(frame_settings, distance) },
+        "setting the distance",
+    )?;
+    if options.lossless {
+        // SAFETY: see above
+        check(
+            unsafe { JxlEncoderSetFrameLossless(frame_settings, JxlBool::True) },
+            "setting lossless",
+        )?;
+    }
+
+    let pixel_format = JxlPixelFormat {
+        num_channels: if has_alpha { 4 } else { 3 },
+        data_type: JxlDataType::Uint8,
+        endianness: JxlEndianness::Native,
+        align: 0,
+    };
+    // SAFETY: `pixels` holds `pixels.len()` bytes, which libjxl checks against the size the
+    // pixel format and basic info call for, and copies before returning
+    check(
+        unsafe {
+            JxlEncoderAddImageFrame(
+                frame_settings,
+                &pixel_format,
+                pixels.as_ptr().cast::<c_void>(),
+                pixels.len(),
+            )
+        },
+        "adding the image",
+    )?;
+    // SAFETY: see above
+    unsafe { JxlEncoderCloseInput(jxl_encoder) };
+    encoder.output()
+}
@@ -1,16 +1,48 @@
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use std::process::Command;
+use tauri::ipc::Channel;
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
 fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
+mod batch_export;
+mod color_management;
+mod containers;
+mod decoders;
+mod dng_opcodes;
+mod export_conflicts;
+mod export_error;
+mod export_jobs;
+mod file_naming;
 mod image_helpers;
+mod jpeg_xl_encoder;
+mod libraw_processor;
+mod metadata;
+mod raw_develop;
+#[cfg(test)]
+mod test_fixtures;
 
-#[cfg(not(target_os = "linux"))]
-#[tauri::command]
-fn show_item_in_folder(path: String) -> Result<(), String> {
+/// Percent encodes a path into a file:// uri. Commas are encoded as well since dbus-send splits
+/// array arguments on them.
+#[cfg(target_os = "linux")]
+fn file_uri(path: &std::path::Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut uri = String::from("file://");
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    uri
+}
+
+fn show_in_file_manager(path: String) -> Result<(), String> {
     use std::path::PathBuf;
 
     #[cfg(target_os = "windows")]
@@ -36,56 +68,163 @@ fn show_item_in_folder(path: String) -> Result<(), String> {
                 .map_err(|e| e.to_string())?;
         }
     }
+
+    #[cfg(target_os = "linux")]
+    {
+        let path_buf = PathBuf::from(&path);
+        if path_buf.is_dir() {
+            Command::new("xdg-open")
+                .args([&path])
+                .spawn()
+                .map_err(|e| e.to_string())?;
+        } else {
+            // Ask the file manager to open the folder with the file selected. Not every file
+            // manager implements the interface, so fall back to just opening the folder.
+            let shown = Command::new("dbus-send")
+                .args([
+                    "--session",
+                    "--print-reply",
+                    // Don't wait long on file managers which never answer
+                    "--reply-timeout=2000",
+                    "--dest=org.freedesktop.FileManager1",
+                    "--type=method_call",
+                    "/org/freedesktop/FileManager1",
+                    "org.freedesktop.FileManager1.ShowItems",
+                    &format!("array:string:{}", file_uri(&path_buf)),
+                    "string:",
+                ])
+                .output()
+                .is_ok_and(|output| output.status.success());
+            if !shown {
+                let parent_folder = path_buf
+                    .parent()
+                    .ok_or_else(|| format!("Error finding the folder of {path}"))?;
+                Command::new("xdg-open")
+                    .arg(parent_folder)
+                    .spawn()
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+    }
     Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[tauri::command]
+async fn show_item_in_folder(path: String) -> Result<(), String> {
+    // Asking the file manager over dbus waits on its reply, so keep it off the async runtime
+    // threads
+    tauri::async_runtime::spawn_blocking(move || show_in_file_manager(path))
+        .await
+        .map_err(|e| format!("Error showing item in folder {e:?}"))?
+}
+
+#[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct ConvertError {
-    image_id: u32,
-    error_message: String,
+struct ConvertResult {
+    job_id: u32,
+    /// The job was cancelled before all images were exported
+    cancelled: bool,
+    /// One result for every image, in the order the images were passed in
+    results: Vec<batch_export::ImageExportResult>,
 }
 
 #[tauri::command]
 async fn convert_images(
+    app: tauri::AppHandle,
+    export_jobs: tauri::State<'_, export_jobs::ExportJobs>,
     image_paths: Vec<String>,
     export_settings: image_helpers::ExportSettings,
-) -> Result<Vec<ConvertError>, String> {
-    let mut export_errors = vec![];
-
-    // For each image path, read the image, make changes as per the export_settings and save the image to
-    // export_settings.export_location.folder_path + export_settings.export_location.subfolder
-    for image_path in image_paths.iter() {
-        let export_result = image_helpers::export_image(image_path, &export_settings);
-
-        if let Err(error_message) = export_result {
-            let export_error = ConvertError {
-                image_id: 0,
-                error_message,
-            };
-            export_errors.push(export_error);
-        }
+    on_progress: Channel<batch_export::ExportProgress>,
+) -> Result<ConvertResult, String> {
+    // Exporting is blocking work and can wait on the user to resolve file conflicts, so keep it
+    // off the async runtime threads
+    let (job_id, job) = export_jobs.start();
+    if let Err(e) = on_progress.send(batch_export::ExportProgress::JobStarted { job_id }) {
+        export_jobs.finish(job_id);
+        return Err(format!("Error starting export job {e:?}"));
     }
-    Ok(export_errors)
+    let handle = tauri::async_runtime::spawn_blocking(move || {
+        let file_conflict_prompt = export_conflicts::FileConflictPrompt::new(app, job.clone());
+
+        // Each image is read, changed as per the export_settings and saved to
+        // export_settings.export_location.folder_path + export_settings.export_location.subfolder
+        let results = batch_export::export_images(
+            &image_paths,
+            &export_settings,
+            &file_conflict_prompt,
+            &job,
+            &on_progress,
+        );
+        let cancelled = results
+            .iter()
+            .any(|result| result.status == batch_export::ImageExportStatus::Cancelled);
+        ConvertResult {
+            job_id,
+            cancelled,
+            results,
+        }
+    });
+
+    let convert_result = handle
+        .await
+        .map_err(|e| format!("Error exporting images {:?}", e));
+    export_jobs.finish(job_id);
+    convert_result
+}
+
+#[tauri::command]
+fn cancel_export(
+    export_jobs: tauri::State<export_jobs::ExportJobs>,
+    job_id: u32,
+) -> Result<(), String> {
+    export_jobs.cancel(job_id)
+}
+
+#[tauri::command]
+fn pause_export(
+    export_jobs: tauri::State<export_jobs::ExportJobs>,
+    job_id: u32,
+) -> Result<(), String> {
+    export_jobs.pause(job_id)
+}
+
+#[tauri::command]
+fn resume_export(
+    export_jobs: tauri::State<export_jobs::ExportJobs>,
+    job_id: u32,
+) -> Result<(), String> {
+    export_jobs.resume(job_id)
+}
+
+#[tauri::command]
+fn resolve_file_conflict(
+    pending_file_conflicts: tauri::State<export_conflicts::PendingFileConflicts>,
+    conflict_id: u32,
+    answer: export_conflicts::FileConflictAnswer,
+) -> Result<(), String> {
+    pending_file_conflicts.answer(conflict_id, answer)
 }
 
 #[tauri::command]
 // Returns the base64 encoding of image on the file system
-async fn load_image(image_path: String) -> Result<String, String> {
-    let handle =
-        tauri::async_runtime::spawn(
-            async move { image_helpers::load_image_as_base64(&image_path) },
-        );
+// Raw files are developed with `raw_develop`, the same settings used for exporting them
+async fn load_image(
+    image_path: String,
+    raw_develop: Option<image_helpers::RawDevelopSettings>,
+) -> Result<String, export_error::ExportError> {
+    let handle = tauri::async_runtime::spawn_blocking(move || {
+        image_helpers::load_image_as_base64(&image_path, &raw_develop.unwrap_or_default())
+    });
 
-    let handle_result = handle.await;
+    handle
+        .await
+        .map_err(|e| export_error::ExportError::Panicked(format!("Error loading image {e}")))?
+}
 
-    match handle_result {
-        Ok(res) => match res {
-            Ok(base64_image) => Ok(base64_image),
-            Err(e) => Err(format!("Error getting image {:?}", e)),
-        },
-        Err(e) => Err(format!("Error getting image {:?}", e)),
-    }
+#[tauri::command]
+// Formats we can read and write, so the frontend doesn't keep its own lists
+fn get_supported_formats() -> decoders::SupportedFormats {
+    decoders::supported_formats()
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -94,11 +233,18 @@ pub fn run() {
         .plugin(tauri_plugin_os::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_opener::init())
+        .manage(export_conflicts::PendingFileConflicts::default())
+        .manage(export_jobs::ExportJobs::default())
         .invoke_handler(tauri::generate_handler![
             greet,
             convert_images,
+            resolve_file_conflict,
+            cancel_export,
+            pause_export,
+            resume_export,
             show_item_in_folder,
-            load_image
+            load_image,
+            get_supported_formats
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
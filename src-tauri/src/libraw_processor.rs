@@ -0,0 +1,170 @@
+use crate::export_error::BoxError;
+use image::{DynamicImage, ImageBuffer};
+use libraw_sys as sys;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::ptr::NonNull;
+
+fn libraw_error(code: c_int) -> BoxError {
+    // SAFETY: libraw_strerror returns a pointer to a static, nul terminated string for any code
+    let message = unsafe { CStr::from_ptr(sys::libraw_strerror(code)) };
+    format!("libraw error {code}: {}", message.to_string_lossy()).into()
+}
+
+fn check(code: c_int) -> Result<(), BoxError> {
+    if code == 0 {
+        Ok(())
+    } else {
+        Err(libraw_error(code))
+    }
+}
+
+/// A raw file opened with libraw, ready to have its processing params set and be developed.
+/// libraw reads the file from the buffer it was opened with, which it borrows for `'a`.
+pub struct LibRaw<'a> {
+    data: NonNull<sys::libraw_data_t>,
+    buffer: PhantomData<&'a [u8]>,
+}
+impl<'a> LibRaw<'a> {
+    pub fn open_buffer(buffer: &'a [u8]) -> Result<LibRaw<'a>, BoxError> {
+        // SAFETY: libraw_init has no preconditions, and returns null if it can't allocate
+        let data =
+            NonNull::new(unsafe { sys::libraw_init(0) }).ok_or("Error initializing libraw")?;
+        let libraw = LibRaw {
+            data,
+            buffer: PhantomData,
+        };
+        // SAFETY: `data` was just initialized. libraw keeps reading from the buffer until it is
+        // closed, which happens when `libraw` is dropped, and `buffer` outlives it as the
+        // lifetime ties them together.
+        check(unsafe {
+            sys::libraw_open_buffer(data.as_ptr(), buffer.as_ptr().cast(), buffer.len())
+        })?;
+        Ok(libraw)
+    }
+
+    pub fn params_mut(&mut self) -> &mut sys::libraw_output_params_t {
+        // SAFETY: `data` points to the libraw_data_t allocated by libraw_init, which lives until
+        // drop. The `&mut self` borrow keeps any other reference to it from existing meanwhile.
+        unsafe { &mut self.data.as_mut().params }
+    }
+
+    /// Color data of the camera, available once the file has been opened
+    pub fn color(&self) -> &sys::libraw_colordata_t {
+        // SAFETY: as in `params_mut`, and libraw only writes to it from calls which take
+        // `&mut self`
+        unsafe { &self.data.as_ref().color }
+    }
+
+    /// Unpack and develop the raw data with the current params. The image is 8 or 16 bit as per
+    /// `params.output_bps`.
+    pub fn develop(&mut self) -> Result<DynamicImage, BoxError> {
+        // SAFETY: `data` is a libraw handle with a file opened by `open_buffer`, and the buffer
+        // it reads from is still borrowed. libraw checks the order of the processing calls itself
+        // and returns an error code if one is out of place.
+        check(unsafe { sys::libraw_unpack(self.data.as_ptr()) })?;
+        // SAFETY: as above
+        check(unsafe { sys::libraw_dcraw_process(self.data.as_ptr()) })?;
+
+        let mut error_code = 0;
+        // SAFETY: as above, and `error_code` is a valid place for libraw to write its error to
+        let processed =
+            unsafe { sys::libraw_dcraw_make_mem_image(self.data.as_ptr(), &mut error_code) };
+        let Some(processed) = NonNull::new(processed) else {
+            return Err(libraw_error(error_code));
+        };
+        // `data` is declared as a one byte array but the samples run past it, so they have to be
+        // read through the pointer to the whole allocation rather than a reference to the header
+        // SAFETY: libraw allocates the header together with `data_size` bytes of samples starting
+        // at `data`, and the allocation stays valid until libraw_dcraw_clear_mem below. The
+        // samples are copied out before that.
+        let image = unsafe {
+            let processed = processed.as_ptr();
+            let samples = std::slice::from_raw_parts(
+                std::ptr::addr_of!((*processed).data).cast::<u8>(),
+                (*processed).data_size as usize,
+            );
+            image_from_samples(
+                u32::from((*processed).width),
+                u32::from((*processed).height),
+                (*processed).colors,
+                (*processed).bits,
+                samples,
+            )
+        };
+        // SAFETY: `processed` came from libraw_dcraw_make_mem_image and is freed only here
+        unsafe { sys::libraw_dcraw_clear_mem(processed.as_ptr()) };
+        image
+    }
+}
+impl Drop for LibRaw<'_> {
+    fn drop(&mut self) {
+        // SAFETY: `data` came from libraw_init and is closed only here, after which it is never
+        // used again
+        unsafe { sys::libraw_close(self.data.as_ptr()) };
+    }
+}
+
+fn image_from_samples(
+    width: u32,
+    height: u32,
+    colors: u16,
+    bits: u16,
+    samples: &[u8],
+) -> Result<DynamicImage, BoxError> {
+    let image = match (colors, bits) {
+        (3, 8) => {
+            ImageBuffer::from_raw(width, height, samples.to_vec()).map(DynamicImage::ImageRgb8)
+        }
+        (1, 8) => {
+            ImageBuffer::from_raw(width, height, samples.to_vec()).map(DynamicImage::ImageLuma8)
+        }
+        // 16 bit samples are in native byte order
+        (3, 16) => ImageBuffer::from_raw(width, height, bytemuck::pod_collect_to_vec(samples))
+            .map(DynamicImage::ImageRgb16),
+        (1, 16) => ImageBuffer::from_raw(width, height, bytemuck::pod_collect_to_vec(samples))
+            .map(DynamicImage::ImageLuma16),
+        _ => {
+            return Err(
+                format!("Unsupported libraw output with {colors} colors at {bits} bits").into(),
+            )
+        }
+    };
+    image.ok_or_else(|| "Failed to create image buffer".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+
+    #[test]
+    fn develops_a_small_dng() {
+        let dng = test_fixtures::small_dng();
+        let mut libraw = LibRaw::open_buffer(&dng).unwrap();
+        assert!(libraw
+            .color()
+            .cam_mul
+            .iter()
+            .any(|&multiplier| multiplier > 0.0));
+        libraw.params_mut().output_bps = 16;
+        let image = libraw.develop().unwrap();
+        assert!(matches!(image, DynamicImage::ImageRgb16(_)));
+        assert_eq!((image.width(), image.height()), (32, 32));
+    }
+
+    #[test]
+    fn develops_8_bit_by_default() {
+        let dng = test_fixtures::small_dng();
+        let image = LibRaw::open_buffer(&dng).unwrap().develop().unwrap();
+        assert!(matches!(image, DynamicImage::ImageRgb8(_)));
+    }
+
+    #[test]
+    fn files_libraw_doesnt_know_are_errors() {
+        assert!(LibRaw::open_buffer(&[0; 1024]).is_err());
+        let png_header = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR";
+        assert!(LibRaw::open_buffer(png_header).is_err());
+    }
+}
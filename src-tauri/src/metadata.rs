@@ -0,0 +1,782 @@
+use crate::containers::{
+    self, EmbeddedMetadata, JPEG_APP1, JPEG_APP13, JPEG_PHOTOSHOP_PREFIX, JPEG_XMP_PREFIX,
+    TIFF_IPTC_TAG, TIFF_XMP_TAG,
+};
+use crate::image_helpers::MetadataPolicy;
+use exif::experimental::Writer as ExifWriter;
+use exif::{Context, Field, In, Tag, Value};
+use libheif_rs::HeifContext;
+use log::warn;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Descriptive tags of the first directory. The other tags there describe the layout of the
+/// source pixels, which doesn't match the exported file.
+const KEPT_TIFF_TAGS: [Tag; 8] = [
+    Tag::ImageDescription,
+    Tag::Make,
+    Tag::Model,
+    Tag::Orientation,
+    Tag::Software,
+    Tag::DateTime,
+    Tag::Artist,
+    Tag::Copyright,
+];
+/// Exif tags which no longer match the exported pixels after resizing and color conversion
+const DROPPED_EXIF_TAGS: [Tag; 3] = [Tag::PixelXDimension, Tag::PixelYDimension, Tag::ColorSpace];
+
+/// XMP properties (or property prefixes) describing where an image was taken
+const XMP_LOCATION_PROPERTIES: [&str; 8] = [
+    "exif:GPS",
+    "photoshop:City",
+    "photoshop:State",
+    "photoshop:Country",
+    "Iptc4xmpCore:Location",
+    "Iptc4xmpCore:CountryCode",
+    "Iptc4xmpExt:LocationCreated",
+    "Iptc4xmpExt:LocationShown",
+];
+/// XMP properties (or property prefixes) kept with `MetadataPolicy::CopyrightAndContactOnly`
+const XMP_COPYRIGHT_AND_CONTACT_PROPERTIES: [&str; 8] = [
+    "dc:rights",
+    "dc:creator",
+    "xmpRights:",
+    "Iptc4xmpCore:CreatorContactInfo",
+    "Iptc4xmpCore:Ci",
+    "photoshop:AuthorsPosition",
+    "photoshop:Credit",
+    "photoshop:Source",
+];
+
+/// IPTC-IIM (record, dataset) pairs describing where an image was taken
+const IPTC_LOCATION_DATASETS: [(u8, u8); 7] = [
+    (2, 26),
+    (2, 27),
+    (2, 90),
+    (2, 92),
+    (2, 95),
+    (2, 100),
+    (2, 101),
+];
+/// Character set, record version, by-line, by-line title, credit, source, copyright notice and
+/// contact
+const IPTC_COPYRIGHT_AND_CONTACT_DATASETS: [(u8, u8); 8] = [
+    (1, 90),
+    (2, 0),
+    (2, 80),
+    (2, 85),
+    (2, 110),
+    (2, 115),
+    (2, 116),
+    (2, 118),
+];
+
+/// Metadata of a source image, before the export `MetadataPolicy` is applied
+#[derive(Debug, Default)]
+pub struct SourceMetadata {
+    exif_fields: Vec<Field>,
+    xmp: Option<Vec<u8>>,
+    iptc: Option<Vec<u8>>,
+}
+
+fn ascii_field(tag: Tag, value: &str) -> Field {
+    Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+    }
+}
+
+fn rational_field(tag: Tag, value: &rawler::formats::tiff::Rational) -> Field {
+    Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Rational(vec![exif::Rational {
+            num: value.n,
+            denom: value.d,
+        }]),
+    }
+}
+
+/// Exif fields of raw formats which aren't TIFF based, from what rawler has parsed
+fn read_raw_exif_fields(
+    path: &Path,
+) -> Result<Vec<Field>, Box<dyn std::error::Error + Send + Sync>> {
+    let raw_source = rawler::rawsource::RawSource::new(path)?;
+    let decoder = rawler::get_decoder(&raw_source)?;
+    let params = rawler::decoders::RawDecodeParams::default();
+    let metadata = decoder.raw_metadata(&raw_source, &params)?;
+    let raw_exif = &metadata.exif;
+
+    let mut fields = vec![
+        ascii_field(Tag::Make, &metadata.make),
+        ascii_field(Tag::Model, &metadata.model),
+    ];
+    let ascii_values = [
+        (Tag::Artist, &raw_exif.artist),
+        (Tag::Copyright, &raw_exif.copyright),
+        (Tag::DateTimeOriginal, &raw_exif.date_time_original),
+        (Tag::LensModel, &raw_exif.lens_model),
+    ];
+    for (tag, value) in ascii_values {
+        if let Some(value) = value {
+            fields.push(ascii_field(tag, value));
+        }
+    }
+    let rational_values = [
+        (Tag::ExposureTime, &raw_exif.exposure_time),
+        (Tag::FNumber, &raw_exif.fnumber),
+        (Tag::FocalLength, &raw_exif.focal_length),
+    ];
+    for (tag, value) in rational_values {
+        if let Some(value) = value {
+            fields.push(rational_field(tag, value));
+        }
+    }
+    if let Some(iso) = raw_exif.iso_speed_ratings {
+        fields.push(Field {
+            tag: Tag::PhotographicSensitivity,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![iso]),
+        });
+    }
+    Ok(fields)
+}
+
+fn read_exif(path: &Path) -> Result<exif::Exif, exif::Error> {
+    let file = std::fs::File::open(path)?;
+    let mut reader = std::io::BufReader::new(file);
+    exif::Reader::new().read_from_container(&mut reader)
+}
+
+/// Raw bytes of a TIFF field holding an embedded block, like XMP or IPTC
+fn tiff_field_bytes(exif: &exif::Exif, tag_number: u16) -> Option<Vec<u8>> {
+    let field = exif.get_field(Tag(Context::Tiff, tag_number), In::PRIMARY)?;
+    match &field.value {
+        Value::Byte(bytes) | Value::Undefined(bytes, _) => Some(bytes.clone()),
+        // IPTC is often stored as LONGs, which we turn back into the original bytes
+        Value::Long(values) => Some(
+            values
+                .iter()
+                .flat_map(|value| {
+                    if exif.little_endian() {
+                        value.to_le_bytes()
+                    } else {
+                        value.to_be_bytes()
+                    }
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+fn read_jpeg_xmp_and_iptc(path: &Path, source_metadata: &mut SourceMetadata) {
+    let jpeg = match std::fs::read(path) {
+        Ok(jpeg) => jpeg,
+        Err(e) => {
+            warn!("Error reading {path:?} {e}");
+            return;
+        }
+    };
+    let segments = match containers::jpeg_segments(&jpeg) {
+        Ok(segments) => segments,
+        Err(e) => {
+            warn!("Error reading jpeg segments of {path:?} {e}");
+            return;
+        }
+    };
+    for (marker, payload) in segments {
+        if marker == JPEG_APP1 && payload.starts_with(JPEG_XMP_PREFIX) {
+            source_metadata.xmp = Some(payload[JPEG_XMP_PREFIX.len()..].to_vec());
+        } else if marker == JPEG_APP13 && payload.starts_with(JPEG_PHOTOSHOP_PREFIX) {
+            source_metadata.iptc =
+                containers::iptc_from_photoshop_resources(&payload[JPEG_PHOTOSHOP_PREFIX.len()..]);
+        }
+    }
+}
+
+fn read_heif_xmp(path: &Path) -> Option<Vec<u8>> {
+    let context = HeifContext::read_from_file(path.to_str()?).ok()?;
+    let handle = context.primary_image_handle().ok()?;
+    let mut metadata_ids = [0; 8];
+    let count = handle.metadata_block_ids(&mut metadata_ids, b"mime");
+    metadata_ids[..count]
+        .iter()
+        .find(|id| handle.metadata_content_type(**id) == Some("application/rdf+xml"))
+        .and_then(|id| handle.metadata(*id).ok())
+}
+
+/// What a source file was recognized as by its decoder, which decides where its metadata is
+/// read from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SourceContainer {
+    /// XMP and IPTC are in their own segments
+    Jpeg,
+    /// XMP is a metadata item
+    Heif,
+    /// Exif rawler can read when kamadak-exif can't
+    Raw,
+    /// XMP and IPTC are only read from tiff fields
+    Other,
+}
+
+/// Read the exif, xmp and iptc of a jpeg, heif, tiff or raw file. Missing or unreadable
+/// metadata is left empty.
+pub fn read_source_metadata(path: &Path, container: SourceContainer) -> SourceMetadata {
+    let mut source_metadata = SourceMetadata::default();
+
+    // kamadak-exif reads jpeg, heif, png, webp and TIFF based raw files
+    match read_exif(path) {
+        Ok(exif) => {
+            source_metadata.xmp = tiff_field_bytes(&exif, TIFF_XMP_TAG);
+            source_metadata.iptc = tiff_field_bytes(&exif, TIFF_IPTC_TAG);
+            source_metadata.exif_fields = exif.fields().cloned().collect();
+        }
+        Err(e) if container == SourceContainer::Raw => match read_raw_exif_fields(path) {
+            Ok(fields) => source_metadata.exif_fields = fields,
+            Err(raw_error) => {
+                warn!("Error reading exif from {path:?} {e} {raw_error:?}")
+            }
+        },
+        Err(exif::Error::NotFound(_)) => {}
+        Err(e) => warn!("Error reading exif from {path:?} {e}"),
+    }
+
+    match container {
+        SourceContainer::Jpeg => read_jpeg_xmp_and_iptc(path, &mut source_metadata),
+        SourceContainer::Heif => source_metadata.xmp = read_heif_xmp(path),
+        SourceContainer::Raw | SourceContainer::Other => {}
+    }
+    source_metadata
+}
+
+fn keep_exif_field(field: &Field, policy: MetadataPolicy, pixels_upright: bool) -> bool {
+    // Thumbnail directories and values we can't write again are always dropped
+    if field.ifd_num != In::PRIMARY || matches!(field.value, Value::Unknown(..)) {
+        return false;
+    }
+    // Pixels which weren't rotated need the orientation to be shown the right way up, whatever
+    // else the policy removes
+    if field.tag == Tag::Orientation && !pixels_upright {
+        return true;
+    }
+    match policy {
+        MetadataPolicy::StripAll => false,
+        MetadataPolicy::CopyrightAndContactOnly => {
+            field.tag == Tag::Artist || field.tag == Tag::Copyright
+        }
+        MetadataPolicy::KeepAll | MetadataPolicy::RemoveLocation => match field.tag.context() {
+            Context::Tiff => KEPT_TIFF_TAGS.contains(&field.tag),
+            Context::Exif => !DROPPED_EXIF_TAGS.contains(&field.tag),
+            Context::Gps => policy == MetadataPolicy::KeepAll,
+            _ => true,
+        },
+    }
+}
+
+fn write_exif(fields: &[Field]) -> Result<Option<Vec<u8>>, exif::Error> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let mut writer = ExifWriter::new();
+    for field in fields.iter() {
+        writer.push_field(field);
+    }
+    let mut buffer = Cursor::new(Vec::new());
+    // The tiff crate writes files in native byte order, and exif has to match it to be merged
+    // into them
+    writer.write(&mut buffer, cfg!(target_endian = "little"))?;
+    Ok(Some(buffer.into_inner()))
+}
+
+/// Returns the length of the XML tag at the start of `xml`, including the closing `>`
+fn xml_tag_length(xml: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in xml.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn xml_tag_name(tag: &str) -> &str {
+    let tag = tag.trim_start_matches('<').trim_start_matches('/');
+    let end = tag
+        .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .unwrap_or(tag.len());
+    &tag[..end]
+}
+
+fn is_special_xml_tag(tag: &str) -> bool {
+    tag.starts_with("</") || tag.starts_with("<?") || tag.starts_with("<!")
+}
+
+/// Skip past the end of the element `name`, whose start tag has already been consumed
+fn skip_xml_element<'a>(mut xml: &'a str, name: &str) -> &'a str {
+    let mut depth = 1;
+    while depth > 0 {
+        let Some(tag_start) = xml.find('<') else {
+            return "";
+        };
+        xml = &xml[tag_start..];
+        let Some(tag_length) = xml_tag_length(xml) else {
+            return "";
+        };
+        let tag = &xml[..tag_length];
+        if xml_tag_name(tag) == name {
+            if tag.starts_with("</") {
+                depth -= 1;
+            } else if !is_special_xml_tag(tag) && !tag.ends_with("/>") {
+                depth += 1;
+            }
+        }
+        xml = &xml[tag_length..];
+    }
+    xml
+}
+
+fn remove_xml_attributes(tag: &str, is_removed: &dyn Fn(&str) -> bool) -> String {
+    let name_end = tag.find(xml_tag_name(tag)).unwrap_or(0) + xml_tag_name(tag).len();
+    let mut result = tag[..name_end].to_string();
+    let mut rest = &tag[name_end..];
+    loop {
+        let attribute = rest.trim_start();
+        let whitespace = &rest[..rest.len() - attribute.len()];
+        let Some(equals) = attribute.find('=') else {
+            break;
+        };
+        let attribute_name = attribute[..equals].trim();
+        if attribute_name.is_empty() || attribute_name.contains(['<', '>', '/']) {
+            break;
+        }
+        let value = attribute[equals + 1..].trim_start();
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            break;
+        };
+        let Some(value_length) = value[1..].find(quote) else {
+            break;
+        };
+        let attribute_length = attribute.len() - value.len() + value_length + 2;
+        if !is_removed(attribute_name) {
+            result.push_str(whitespace);
+            result.push_str(&attribute[..attribute_length]);
+        }
+        rest = &attribute[attribute_length..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Remove properties from an XMP packet, whether they are written as attributes or elements
+fn remove_xmp_properties(xmp: &str, is_removed: &dyn Fn(&str) -> bool) -> String {
+    let mut result = String::with_capacity(xmp.len());
+    let mut rest = xmp;
+    while let Some(tag_start) = rest.find('<') {
+        result.push_str(&rest[..tag_start]);
+        rest = &rest[tag_start..];
+        let Some(tag_length) = xml_tag_length(rest) else {
+            break;
+        };
+        let tag = &rest[..tag_length];
+        rest = &rest[tag_length..];
+
+        if is_special_xml_tag(tag) {
+            result.push_str(tag);
+        } else if is_removed(xml_tag_name(tag)) {
+            if !tag.ends_with("/>") {
+                rest = skip_xml_element(rest, xml_tag_name(tag));
+            }
+        } else {
+            result.push_str(&remove_xml_attributes(tag, is_removed));
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Names which make up the structure of an XMP packet rather than being properties
+fn is_structural_xmp_name(name: &str) -> bool {
+    !name.contains(':')
+        || name.starts_with("rdf:")
+        || name.starts_with("x:")
+        || name.starts_with("xml:")
+        || name.starts_with("xmlns")
+}
+
+fn filter_xmp(xmp: &[u8], policy: MetadataPolicy, pixels_upright: bool) -> Option<Vec<u8>> {
+    let Ok(xmp) = std::str::from_utf8(xmp) else {
+        warn!("Dropping xmp metadata which isn't valid utf-8");
+        return None;
+    };
+    let matches_any = |name: &str, properties: &[&str]| {
+        properties.iter().any(|property| name.starts_with(property))
+    };
+
+    // Once the pixels have been rotated the orientation is dropped in every case
+    let is_removed = |name: &str| -> bool {
+        if name == "tiff:Orientation" {
+            return pixels_upright;
+        }
+        match policy {
+            MetadataPolicy::KeepAll => false,
+            MetadataPolicy::RemoveLocation => matches_any(name, &XMP_LOCATION_PROPERTIES),
+            MetadataPolicy::CopyrightAndContactOnly => {
+                !is_structural_xmp_name(name)
+                    && !matches_any(name, &XMP_COPYRIGHT_AND_CONTACT_PROPERTIES)
+            }
+            MetadataPolicy::StripAll => true,
+        }
+    };
+    Some(remove_xmp_properties(xmp, &is_removed).into_bytes())
+}
+
+fn filter_iptc(iptc: &[u8], policy: MetadataPolicy) -> Option<Vec<u8>> {
+    let mut filtered = vec![];
+    let mut has_content = false;
+    let mut offset = 0;
+    while offset + 5 <= iptc.len() && iptc[offset] == 0x1C {
+        let dataset = (iptc[offset + 1], iptc[offset + 2]);
+        let size = u16::from_be_bytes([iptc[offset + 3], iptc[offset + 4]]) as usize;
+        if size & 0x8000 != 0 {
+            warn!("Dropping iptc metadata with extended datasets");
+            return None;
+        }
+        let end = offset + 5 + size;
+        if end > iptc.len() {
+            warn!("Dropping truncated iptc metadata");
+            return None;
+        }
+
+        let keep = match policy {
+            MetadataPolicy::KeepAll => true,
+            MetadataPolicy::RemoveLocation => !IPTC_LOCATION_DATASETS.contains(&dataset),
+            MetadataPolicy::CopyrightAndContactOnly => {
+                IPTC_COPYRIGHT_AND_CONTACT_DATASETS.contains(&dataset)
+            }
+            MetadataPolicy::StripAll => false,
+        };
+        if keep {
+            filtered.extend_from_slice(&iptc[offset..end]);
+            has_content |= dataset.0 == 2 && dataset.1 != 0;
+        }
+        offset = end;
+    }
+
+    // Only a character set or record version is not worth writing
+    if has_content {
+        Some(filtered)
+    } else {
+        None
+    }
+}
+
+/// Apply the metadata policy to the source metadata. `pixels_upright` is whether the exported
+/// pixels have been rotated as per the source orientation. The orientation is then reset, and
+/// otherwise kept so the untouched pixels still show the right way up. The returned metadata
+/// has no icc profile.
+pub fn export_metadata(
+    source_metadata: &SourceMetadata,
+    policy: MetadataPolicy,
+    pixels_upright: bool,
+) -> EmbeddedMetadata {
+    if policy == MetadataPolicy::StripAll && pixels_upright {
+        return EmbeddedMetadata::default();
+    }
+
+    let exif_fields: Vec<Field> = source_metadata
+        .exif_fields
+        .iter()
+        .filter(|field| keep_exif_field(field, policy, pixels_upright))
+        .map(|field| {
+            if field.tag == Tag::Orientation && pixels_upright {
+                Field {
+                    value: Value::Short(vec![1]),
+                    ..field.clone()
+                }
+            } else {
+                field.clone()
+            }
+        })
+        .collect();
+    let exif = write_exif(&exif_fields).unwrap_or_else(|e| {
+        warn!("Error writing exif metadata {e}");
+        None
+    });
+    if policy == MetadataPolicy::StripAll {
+        // Only the orientation is left
+        return EmbeddedMetadata {
+            exif,
+            ..EmbeddedMetadata::default()
+        };
+    }
+
+    EmbeddedMetadata {
+        icc_profile: None,
+        exif,
+        xmp: source_metadata
+            .xmp
+            .as_deref()
+            .and_then(|xmp| filter_xmp(xmp, policy, pixels_upright)),
+        iptc: source_metadata
+            .iptc
+            .as_deref()
+            .and_then(|iptc| filter_iptc(iptc, policy)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XMP: &str = concat!(
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+        r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+        r#"<rdf:Description rdf:about="" exif:GPSLatitude="52,31.2N" photoshop:City="Berlin" "#,
+        r#"tiff:Orientation="6" xmp:Rating="4">"#,
+        r#"<exif:GPSLongitude>13,24.6E</exif:GPSLongitude>"#,
+        r#"<dc:rights><rdf:Alt><rdf:li xml:lang="x-default">Jane Doe</rdf:li></rdf:Alt></dc:rights>"#,
+        r#"<dc:subject><rdf:Bag><rdf:li>travel</rdf:li></rdf:Bag></dc:subject>"#,
+        r#"</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+    );
+
+    fn iptc_dataset(record: u8, dataset: u8, value: &str) -> Vec<u8> {
+        let mut data = vec![0x1C, record, dataset];
+        data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        data.extend_from_slice(value.as_bytes());
+        data
+    }
+
+    fn source_metadata() -> SourceMetadata {
+        let rational = |num| exif::Rational { num, denom: 1 };
+        let iptc = [
+            iptc_dataset(2, 0, "\u{0}\u{4}"),
+            iptc_dataset(2, 5, "Title"),
+            iptc_dataset(2, 90, "Berlin"),
+            iptc_dataset(2, 116, "Jane Doe"),
+        ]
+        .concat();
+        SourceMetadata {
+            exif_fields: vec![
+                ascii_field(Tag::Make, "Canon"),
+                ascii_field(Tag::Model, "Canon EOS R5"),
+                ascii_field(Tag::Artist, "Jane Doe"),
+                ascii_field(Tag::Copyright, "Jane Doe"),
+                Field {
+                    tag: Tag::Orientation,
+                    ifd_num: In::PRIMARY,
+                    value: Value::Short(vec![6]),
+                },
+                Field {
+                    tag: Tag::ImageWidth,
+                    ifd_num: In::PRIMARY,
+                    value: Value::Long(vec![6000]),
+                },
+                ascii_field(Tag::DateTimeOriginal, "2024:05:01 10:20:30"),
+                Field {
+                    tag: Tag::PixelXDimension,
+                    ifd_num: In::PRIMARY,
+                    value: Value::Long(vec![6000]),
+                },
+                ascii_field(Tag::GPSLatitudeRef, "N"),
+                Field {
+                    tag: Tag::GPSLatitude,
+                    ifd_num: In::PRIMARY,
+                    value: Value::Rational(vec![rational(52), rational(31), rational(12)]),
+                },
+                Field {
+                    ifd_num: In::THUMBNAIL,
+                    ..ascii_field(Tag::Make, "Thumbnail maker")
+                },
+            ],
+            xmp: Some(XMP.as_bytes().to_vec()),
+            iptc: Some(iptc),
+        }
+    }
+
+    /// Embed the exported metadata into a jpeg and read the exif back from it
+    fn exif_round_trip(metadata: &EmbeddedMetadata) -> Option<exif::Exif> {
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .encode(&[128; 4 * 4 * 3], 4, 4, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let jpeg = containers::embed_in_jpeg(&jpeg, metadata).unwrap();
+        image::load_from_memory(&jpeg).unwrap();
+        exif::Reader::new()
+            .read_from_container(&mut Cursor::new(jpeg))
+            .ok()
+    }
+
+    #[test]
+    fn jpeg_xmp_is_read_by_content_not_extension() {
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .encode(&[128; 4 * 4 * 3], 4, 4, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let metadata = EmbeddedMetadata {
+            xmp: Some(XMP.as_bytes().to_vec()),
+            ..EmbeddedMetadata::default()
+        };
+        let jpeg = containers::embed_in_jpeg(&jpeg, &metadata).unwrap();
+        let path = std::env::temp_dir().join(format!("vikara-xmp-{}.png", std::process::id()));
+        std::fs::write(&path, &jpeg).unwrap();
+        let from_jpeg = read_source_metadata(&path, SourceContainer::Jpeg);
+        let from_other = read_source_metadata(&path, SourceContainer::Other);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(from_jpeg.xmp.as_deref(), Some(XMP.as_bytes()));
+        assert_eq!(from_other.xmp, None);
+    }
+
+    fn tags(exif: &exif::Exif) -> Vec<Tag> {
+        exif.fields().map(|field| field.tag).collect()
+    }
+
+    fn xmp_string(metadata: &EmbeddedMetadata) -> String {
+        String::from_utf8(metadata.xmp.clone().unwrap()).unwrap()
+    }
+
+    fn iptc_datasets(metadata: &EmbeddedMetadata) -> Vec<(u8, u8)> {
+        let iptc = metadata.iptc.as_deref().unwrap();
+        let mut datasets = vec![];
+        let mut offset = 0;
+        while offset + 5 <= iptc.len() {
+            datasets.push((iptc[offset + 1], iptc[offset + 2]));
+            offset += 5 + u16::from_be_bytes([iptc[offset + 3], iptc[offset + 4]]) as usize;
+        }
+        datasets
+    }
+
+    #[test]
+    fn keep_all_keeps_descriptive_tags_and_resets_orientation() {
+        let metadata = export_metadata(&source_metadata(), MetadataPolicy::KeepAll, true);
+        let exif = exif_round_trip(&metadata).unwrap();
+        let tags = tags(&exif);
+        for tag in [
+            Tag::Make,
+            Tag::Model,
+            Tag::Artist,
+            Tag::DateTimeOriginal,
+            Tag::GPSLatitudeRef,
+            Tag::GPSLatitude,
+        ] {
+            assert!(tags.contains(&tag), "{tag} is missing");
+        }
+        // The layout of the source pixels and the thumbnail don't carry over
+        assert!(!tags.contains(&Tag::ImageWidth));
+        assert!(!tags.contains(&Tag::PixelXDimension));
+        assert!(exif.get_field(Tag::Make, In::THUMBNAIL).is_none());
+        let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+        assert_eq!(orientation.value.get_uint(0), Some(1));
+
+        let xmp = xmp_string(&metadata);
+        assert!(xmp.contains("exif:GPSLatitude"));
+        assert!(xmp.contains("photoshop:City"));
+        assert!(!xmp.contains("tiff:Orientation"));
+        assert_eq!(
+            iptc_datasets(&metadata),
+            vec![(2, 0), (2, 5), (2, 90), (2, 116)]
+        );
+    }
+
+    #[test]
+    fn remove_location_drops_gps() {
+        let metadata = export_metadata(&source_metadata(), MetadataPolicy::RemoveLocation, true);
+        let exif = exif_round_trip(&metadata).unwrap();
+        let tags = tags(&exif);
+        assert!(tags.contains(&Tag::Make));
+        assert!(tags.contains(&Tag::DateTimeOriginal));
+        assert!(!tags.contains(&Tag::GPSInfoIFDPointer));
+        assert!(tags.iter().all(|tag| tag.context() != Context::Gps));
+
+        let xmp = xmp_string(&metadata);
+        assert!(!xmp.contains("exif:GPS"));
+        assert!(!xmp.contains("photoshop:City"));
+        assert!(xmp.contains("xmp:Rating=\"4\""));
+        assert!(xmp.contains("<dc:subject>"));
+        assert_eq!(iptc_datasets(&metadata), vec![(2, 0), (2, 5), (2, 116)]);
+    }
+
+    #[test]
+    fn copyright_and_contact_only_keeps_just_those() {
+        let metadata = export_metadata(
+            &source_metadata(),
+            MetadataPolicy::CopyrightAndContactOnly,
+            true,
+        );
+        let exif = exif_round_trip(&metadata).unwrap();
+        let tags = tags(&exif);
+        assert!(tags.contains(&Tag::Artist));
+        assert!(tags.contains(&Tag::Copyright));
+        for tag in [
+            Tag::Make,
+            Tag::Model,
+            Tag::DateTimeOriginal,
+            Tag::GPSLatitude,
+        ] {
+            assert!(!tags.contains(&tag), "{tag} is kept");
+        }
+
+        let xmp = xmp_string(&metadata);
+        assert!(xmp.contains("<dc:rights>"));
+        assert!(xmp.contains("Jane Doe"));
+        assert!(xmp.contains("<rdf:Description"));
+        assert!(!xmp.contains("dc:subject"));
+        assert!(!xmp.contains("xmp:Rating"));
+        assert!(!xmp.contains("exif:GPS"));
+        assert_eq!(iptc_datasets(&metadata), vec![(2, 0), (2, 116)]);
+    }
+
+    #[test]
+    fn strip_all_embeds_nothing() {
+        let metadata = export_metadata(&source_metadata(), MetadataPolicy::StripAll, true);
+        assert!(metadata.is_empty());
+        assert!(exif_round_trip(&metadata).is_none());
+    }
+
+    #[test]
+    fn iptc_with_only_a_record_version_is_dropped() {
+        let iptc = [
+            iptc_dataset(2, 0, "\u{0}\u{4}"),
+            iptc_dataset(2, 90, "Berlin"),
+        ]
+        .concat();
+        assert_eq!(filter_iptc(&iptc, MetadataPolicy::RemoveLocation), None);
+        // Truncated data isn't written at all
+        assert_eq!(
+            filter_iptc(&iptc[..iptc.len() - 1], MetadataPolicy::KeepAll),
+            None
+        );
+    }
+
+    #[test]
+    fn xmp_elements_and_attributes_are_removed() {
+        let filtered = filter_xmp(XMP.as_bytes(), MetadataPolicy::RemoveLocation, true).unwrap();
+        let filtered = String::from_utf8(filtered).unwrap();
+        assert!(filtered.starts_with("<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">"));
+        assert!(filtered.contains("<rdf:Description rdf:about=\"\" xmp:Rating=\"4\">"));
+        assert!(filtered.ends_with("</rdf:Description></rdf:RDF></x:xmpmeta>"));
+    }
+
+    #[test]
+    fn orientation_is_kept_for_pixels_which_werent_rotated() {
+        for policy in [
+            MetadataPolicy::KeepAll,
+            MetadataPolicy::CopyrightAndContactOnly,
+            MetadataPolicy::StripAll,
+        ] {
+            let metadata = export_metadata(&source_metadata(), policy, false);
+            let exif = exif_round_trip(&metadata).unwrap();
+            let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+            assert_eq!(orientation.value.get_uint(0), Some(6), "{policy:?}");
+        }
+        let metadata = export_metadata(&source_metadata(), MetadataPolicy::KeepAll, false);
+        assert!(xmp_string(&metadata).contains("tiff:Orientation=\"6\""));
+        let metadata = export_metadata(&source_metadata(), MetadataPolicy::StripAll, false);
+        assert!(metadata.xmp.is_none() && metadata.iptc.is_none());
+    }
+}
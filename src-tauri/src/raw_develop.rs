@@ -0,0 +1,714 @@
+use crate::dng_opcodes;
+use crate::export_error::{BoxError, DecodeBackend, ExportError};
+use crate::image_helpers::{
+    apply_transfer_lut, linear_to_srgb_lut, rotate_to_orientation, white_point_chromaticity,
+};
+use image::{DynamicImage, ImageBuffer};
+use log::{info, warn};
+use rawler::decoders::RawDecodeParams;
+use rawler::imgop::xyz::Illuminant;
+use rawler::rawimage::{RawImage, RawImageData, RawPhotometricInterpretation};
+use rawler::rawsource::RawSource;
+use std::path::Path;
+use std::time::Instant;
+
+/// Rows of the image demosaiced together by AHD, which keeps its working copies small
+const AHD_BAND_ROWS: usize = 64;
+/// AHD looks up to this many pixels away from the one it interpolates. Pixels closer than this
+/// to the edge are interpolated bilinearly instead.
+const AHD_BORDER: usize = 3;
+
+/// Linear sRGB to CIE XYZ, D65 white point
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.0721750],
+    [0.0193339, 0.1191920, 0.9503041],
+];
+const D65_WHITE: [f32; 3] = [0.95047, 1.0, 1.08883];
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum DemosaicAlgorithm {
+    /// Fast, but softer and prone to color fringes along edges
+    Bilinear,
+    /// Adaptive homogeneity-directed interpolation. Only used for bayer sensors, other sensor
+    /// layouts fall back to bilinear.
+    #[default]
+    Ahd,
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum HighlightMode {
+    /// Clip all channels at the level of the first channel to saturate, so blown highlights
+    /// come out neutral white
+    #[default]
+    Clip,
+    /// Fade partly clipped areas to neutral, which avoids hard edges and color casts where
+    /// only some channels are blown
+    Blend,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum WhiteBalance {
+    /// The white balance the camera recorded
+    #[default]
+    AsShot,
+    /// Balance the averages of the unclipped colors, assuming the scene averages to gray
+    Auto,
+    /// The white point of a black body at `temperature` kelvin, shifted green (negative) or
+    /// magenta (positive) by `tint`
+    Custom { temperature: u32, tint: f32 },
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RawDevelopOptions {
+    pub white_balance: WhiteBalance,
+    pub demosaic: DemosaicAlgorithm,
+    pub highlights: HighlightMode,
+    /// Exposure change in stops
+    pub exposure_compensation: f32,
+    /// Scale the brightness so the brightest percent of the pixels comes out white
+    pub auto_brightness: bool,
+}
+
+/// Color filter pattern of the sensor. Colors are 0 for red, 1 for green and 2 for blue.
+struct Cfa {
+    width: usize,
+    height: usize,
+    colors: Vec<usize>,
+}
+impl Cfa {
+    fn color_at(&self, row: usize, col: usize) -> usize {
+        self.colors[(row % self.height) * self.width + col % self.width]
+    }
+
+    fn is_bayer(&self) -> bool {
+        let mut counts = [0; 3];
+        for &color in &self.colors {
+            counts[color] += 1;
+        }
+        self.width == 2 && self.height == 2 && counts == [1, 2, 1]
+    }
+}
+
+enum SensorLayout {
+    /// One color per pixel, as laid out by the color filter
+    Mosaic(Cfa),
+    /// Already three colors per pixel, like linear DNGs
+    Rgb,
+}
+
+/// Sensor data scaled to 0..1 between the black and white level
+struct SensorData {
+    width: usize,
+    height: usize,
+    samples: Vec<f32>,
+    layout: SensorLayout,
+    /// The level at which the first channel saturates, which moves once white balance is applied
+    clip_level: f32,
+}
+
+/// Multipliers that bring the camera's white balance coefficients to a green of 1. Cameras
+/// which don't record white balance leave the colors as they are.
+fn white_balance_multipliers(wb_coeffs: [f32; 4]) -> [f32; 3] {
+    let green = wb_coeffs[1];
+    if !(green.is_finite() && green > 0.0) {
+        return [1.0; 3];
+    }
+    let multiplier = |coeff: f32| {
+        if coeff.is_finite() && coeff > 0.0 {
+            coeff / green
+        } else {
+            1.0
+        }
+    };
+    [multiplier(wb_coeffs[0]), 1.0, multiplier(wb_coeffs[2])]
+}
+
+impl SensorData {
+    fn from_raw_image(raw_image: &RawImage) -> Result<Self, BoxError> {
+        let layout = match (&raw_image.photometric, raw_image.cpp) {
+            (RawPhotometricInterpretation::Cfa(config), 1) => {
+                let cfa = &config.cfa;
+                let mut colors = Vec::with_capacity(cfa.width * cfa.height);
+                for row in 0..cfa.height {
+                    for col in 0..cfa.width {
+                        // Sensors with a second kind of green report it as a fourth color
+                        colors.push(match cfa.color_at(row, col) {
+                            3 => 1,
+                            color => color,
+                        });
+                    }
+                }
+                if colors.iter().any(|&color| color > 2) {
+                    return Err(format!("Unsupported color filter {}", cfa.name).into());
+                }
+                SensorLayout::Mosaic(Cfa {
+                    width: cfa.width,
+                    height: cfa.height,
+                    colors,
+                })
+            }
+            (RawPhotometricInterpretation::LinearRaw, 3) => SensorLayout::Rgb,
+            (photometric, cpp) => {
+                return Err(format!(
+                    "Unsupported sensor layout {photometric:?} with {cpp} samples per pixel"
+                )
+                .into())
+            }
+        };
+
+        let black_levels = raw_image.blacklevel.as_bayer_array();
+        let white_level = raw_image
+            .whitelevel
+            .0
+            .first()
+            .copied()
+            .unwrap_or(u16::MAX as u32) as f32;
+
+        let width = raw_image.width;
+        let height = raw_image.height;
+        let scale = |index: usize, value: f32| {
+            let black = match &layout {
+                SensorLayout::Mosaic(_) => {
+                    let (row, col) = (index / width, index % width);
+                    black_levels[(row % 2) * 2 + col % 2]
+                }
+                SensorLayout::Rgb => black_levels[0],
+            };
+            ((value - black) / (white_level - black)).max(0.0)
+        };
+        let samples: Vec<f32> = match &raw_image.data {
+            RawImageData::Integer(data) => data
+                .iter()
+                .enumerate()
+                .map(|(index, &value)| scale(index, value as f32))
+                .collect(),
+            RawImageData::Float(data) => data
+                .iter()
+                .enumerate()
+                .map(|(index, &value)| scale(index, value))
+                .collect(),
+        };
+
+        Ok(SensorData {
+            width,
+            height,
+            samples,
+            layout,
+            clip_level: 1.0,
+        })
+    }
+
+    fn sample(&self, row: usize, col: usize) -> f32 {
+        self.samples[row * self.width + col]
+    }
+
+    fn color_of(&self, index: usize) -> usize {
+        match &self.layout {
+            SensorLayout::Mosaic(cfa) => cfa.color_at(index / self.width, index % self.width),
+            SensorLayout::Rgb => index % 3,
+        }
+    }
+
+    /// Multipliers which make the averages of the colors equal. Clipped samples are left out,
+    /// since they no longer tell how bright their color was.
+    fn gray_world_multipliers(&self) -> [f32; 3] {
+        let mut sums = [0.0f64; 3];
+        let mut counts = [0usize; 3];
+        for (index, &value) in self.samples.iter().enumerate() {
+            if value < 1.0 {
+                let color = self.color_of(index);
+                sums[color] += value as f64;
+                counts[color] += 1;
+            }
+        }
+        let averages: [f64; 3] =
+            std::array::from_fn(|color| sums[color] / counts[color].max(1) as f64);
+        if averages.iter().any(|&average| average <= 0.0) {
+            return [1.0; 3];
+        }
+        [
+            (averages[1] / averages[0]) as f32,
+            1.0,
+            (averages[1] / averages[2]) as f32,
+        ]
+    }
+
+    fn apply_white_balance(&mut self, multipliers: [f32; 3]) {
+        for index in 0..self.samples.len() {
+            let color = self.color_of(index);
+            self.samples[index] *= multipliers[color];
+        }
+        self.clip_level = multipliers.iter().copied().fold(f32::INFINITY, f32::min);
+    }
+}
+
+/// Offsets to the closest pixels of `color` around a position of the pattern
+fn neighbor_offsets(cfa: &Cfa, row: usize, col: usize, color: usize) -> Vec<(isize, isize)> {
+    for radius in 1..=2isize {
+        let mut offsets = Vec::new();
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if (dy, dx) == (0, 0) {
+                    continue;
+                }
+                let pattern_row = (row as isize + dy).rem_euclid(cfa.height as isize) as usize;
+                let pattern_col = (col as isize + dx).rem_euclid(cfa.width as isize) as usize;
+                if cfa.color_at(pattern_row, pattern_col) == color {
+                    offsets.push((dy, dx));
+                }
+            }
+        }
+        if !offsets.is_empty() {
+            return offsets;
+        }
+    }
+    Vec::new()
+}
+
+/// Fills in the missing colors of each pixel with the average of the closest pixels of that
+/// color. Works with any color filter pattern.
+fn demosaic_bilinear(sensor: &SensorData, cfa: &Cfa) -> Vec<[f32; 3]> {
+    let (width, height) = (sensor.width, sensor.height);
+    let mut pattern_offsets = Vec::with_capacity(cfa.width * cfa.height);
+    for row in 0..cfa.height {
+        for col in 0..cfa.width {
+            pattern_offsets.push([0, 1, 2].map(|color| neighbor_offsets(cfa, row, col, color)));
+        }
+    }
+
+    let mut rgb = vec![[0.0; 3]; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let own_color = cfa.color_at(y, x);
+            let offsets = &pattern_offsets[(y % cfa.height) * cfa.width + x % cfa.width];
+            let pixel = &mut rgb[y * width + x];
+            for color in 0..3 {
+                if color == own_color {
+                    pixel[color] = sensor.sample(y, x);
+                    continue;
+                }
+                let mut sum = 0.0;
+                let mut count = 0;
+                for &(dy, dx) in &offsets[color] {
+                    let (ny, nx) = (y as isize + dy, x as isize + dx);
+                    if ny >= 0 && nx >= 0 && (ny as usize) < height && (nx as usize) < width {
+                        sum += sensor.sample(ny as usize, nx as usize);
+                        count += 1;
+                    }
+                }
+                if count > 0 {
+                    pixel[color] = sum / count as f32;
+                }
+            }
+        }
+    }
+    rgb
+}
+
+fn lab_f(t: f32) -> f32 {
+    if t > 0.008856 {
+        t.cbrt()
+    } else {
+        7.787 * t + 16.0 / 116.0
+    }
+}
+
+/// CIELab of a white balanced camera color. The camera color is treated as sRGB here, which
+/// is close enough to judge how similar neighboring pixels are.
+fn to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let [fx, fy, fz] = [0, 1, 2].map(|i| {
+        let xyz: f32 = (0..3).map(|j| SRGB_TO_XYZ[i][j] * rgb[j]).sum();
+        lab_f(xyz / D65_WHITE[i])
+    });
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Green interpolated along one direction, with the gradient of the pixel's own color as a
+/// correction. Clamped to its two green neighbors so edges don't overshoot.
+fn directional_green(sensor: &SensorData, y: usize, x: usize, horizontal: bool) -> Option<f32> {
+    let (dy, dx) = if horizontal { (0, 1) } else { (1, 0) };
+    if y < 2 * dy || x < 2 * dx || y + 2 * dy >= sensor.height || x + 2 * dx >= sensor.width {
+        return None;
+    }
+    let before = sensor.sample(y - dy, x - dx);
+    let after = sensor.sample(y + dy, x + dx);
+    let correction = (2.0 * sensor.sample(y, x)
+        - sensor.sample(y - 2 * dy, x - 2 * dx)
+        - sensor.sample(y + 2 * dy, x + 2 * dx))
+        / 4.0;
+    Some(((before + after) / 2.0 + correction).clamp(before.min(after), before.max(after)))
+}
+
+/// Adaptive homogeneity-directed demosaicing for bayer sensors. Every pixel is interpolated
+/// once horizontally and once vertically, and the direction whose neighborhood is the most
+/// uniform in CIELab wins. The image is worked through in bands of rows, with the pixels along
+/// the edges left as interpolated by `demosaic_bilinear`.
+fn demosaic_ahd(sensor: &SensorData, cfa: &Cfa, rgb: &mut [[f32; 3]]) {
+    let (width, height) = (sensor.width, sensor.height);
+    if width < 4 * AHD_BORDER || height < 4 * AHD_BORDER {
+        return;
+    }
+    const NEIGHBORS: [(isize, isize); 4] = [(0, -1), (0, 1), (-1, 0), (1, 0)];
+
+    let mut band_start = AHD_BORDER;
+    while band_start < height - AHD_BORDER {
+        let band_end = (band_start + AHD_BAND_ROWS).min(height - AHD_BORDER);
+        let top = band_start - AHD_BORDER;
+        let bottom = band_end + AHD_BORDER;
+        let band_len = (bottom - top) * width;
+        let at = |y: usize, x: usize| (y - top) * width + x;
+
+        // Green in both directions for every row of the band
+        let mut greens = [vec![0.0f32; band_len], vec![0.0f32; band_len]];
+        for y in top..bottom {
+            for x in 0..width {
+                for (direction, green) in greens.iter_mut().enumerate() {
+                    green[at(y, x)] = if cfa.color_at(y, x) == 1 {
+                        sensor.sample(y, x)
+                    } else {
+                        directional_green(sensor, y, x, direction == 0)
+                            .unwrap_or(rgb[y * width + x][1])
+                    };
+                }
+            }
+        }
+
+        // Red and blue from the color difference to the green of the same direction
+        let mut candidates = [vec![[0.0f32; 3]; band_len], vec![[0.0f32; 3]; band_len]];
+        let mut labs = [vec![[0.0f32; 3]; band_len], vec![[0.0f32; 3]; band_len]];
+        for direction in 0..2 {
+            let green = &greens[direction];
+            let difference = |y: usize, x: usize| sensor.sample(y, x) - green[at(y, x)];
+            for y in top + 1..bottom - 1 {
+                for x in 1..width - 1 {
+                    let g = green[at(y, x)];
+                    let own_color = cfa.color_at(y, x);
+                    let mut pixel = [0.0; 3];
+                    pixel[1] = g;
+                    if own_color == 1 {
+                        let horizontal_color = cfa.color_at(y, x + 1);
+                        let vertical_color = cfa.color_at(y + 1, x);
+                        pixel[horizontal_color] =
+                            g + (difference(y, x - 1) + difference(y, x + 1)) / 2.0;
+                        pixel[vertical_color] =
+                            g + (difference(y - 1, x) + difference(y + 1, x)) / 2.0;
+                    } else {
+                        pixel[own_color] = sensor.sample(y, x);
+                        let diagonal_sum = difference(y - 1, x - 1)
+                            + difference(y - 1, x + 1)
+                            + difference(y + 1, x - 1)
+                            + difference(y + 1, x + 1);
+                        pixel[2 - own_color] = g + diagonal_sum / 4.0;
+                    }
+                    candidates[direction][at(y, x)] = pixel;
+                    labs[direction][at(y, x)] = to_lab(pixel);
+                }
+            }
+        }
+
+        // How many of its neighbors each candidate pixel is similar to
+        let mut homogeneity = [vec![0u8; band_len], vec![0u8; band_len]];
+        for y in top + 2..bottom - 2 {
+            for x in 2..width - 2 {
+                let mut l_diffs = [[0.0f32; 4]; 2];
+                let mut ab_diffs = [[0.0f32; 4]; 2];
+                for direction in 0..2 {
+                    let center = labs[direction][at(y, x)];
+                    for (i, &(dy, dx)) in NEIGHBORS.iter().enumerate() {
+                        let neighbor = labs[direction]
+                            [at((y as isize + dy) as usize, (x as isize + dx) as usize)];
+                        l_diffs[direction][i] = (center[0] - neighbor[0]).abs();
+                        ab_diffs[direction][i] =
+                            (center[1] - neighbor[1]).powi(2) + (center[2] - neighbor[2]).powi(2);
+                    }
+                }
+                let l_epsilon = l_diffs[0][0]
+                    .max(l_diffs[0][1])
+                    .min(l_diffs[1][2].max(l_diffs[1][3]));
+                let ab_epsilon = ab_diffs[0][0]
+                    .max(ab_diffs[0][1])
+                    .min(ab_diffs[1][2].max(ab_diffs[1][3]));
+                for direction in 0..2 {
+                    homogeneity[direction][at(y, x)] = (0..4)
+                        .filter(|&i| {
+                            l_diffs[direction][i] <= l_epsilon
+                                && ab_diffs[direction][i] <= ab_epsilon
+                        })
+                        .count() as u8;
+                }
+            }
+        }
+
+        for y in band_start..band_end {
+            for x in AHD_BORDER..width - AHD_BORDER {
+                let mut scores = [0u32; 2];
+                for (direction, score) in scores.iter_mut().enumerate() {
+                    for ny in y - 1..=y + 1 {
+                        for nx in x - 1..=x + 1 {
+                            *score += u32::from(homogeneity[direction][at(ny, nx)]);
+                        }
+                    }
+                }
+                let horizontal = candidates[0][at(y, x)];
+                let vertical = candidates[1][at(y, x)];
+                rgb[y * width + x] = match scores[0].cmp(&scores[1]) {
+                    std::cmp::Ordering::Greater => horizontal,
+                    std::cmp::Ordering::Less => vertical,
+                    std::cmp::Ordering::Equal => {
+                        [0, 1, 2].map(|c| (horizontal[c] + vertical[c]) / 2.0)
+                    }
+                };
+            }
+        }
+        band_start = band_end;
+    }
+}
+
+fn handle_highlights(rgb: &mut [[f32; 3]], clip_level: f32, mode: HighlightMode) {
+    for pixel in rgb.iter_mut() {
+        let brightest = pixel[0].max(pixel[1]).max(pixel[2]);
+        if brightest <= clip_level {
+            pixel.iter_mut().for_each(|value| *value /= clip_level);
+            continue;
+        }
+        let clipped = pixel.map(|value| value.min(clip_level));
+        let blended = match mode {
+            HighlightMode::Clip => clipped,
+            HighlightMode::Blend => {
+                // The further past the clip level, the closer to neutral
+                let amount = ((brightest - clip_level) / clip_level).min(1.0);
+                clipped.map(|value| value + (clip_level - value) * amount)
+            }
+        };
+        *pixel = blended.map(|value| value / clip_level);
+    }
+}
+
+/// Scale linear colors so the brightest percent of the pixels reaches white, like libraw's auto
+/// brightness
+fn auto_brighten(rgb: &mut [[f32; 3]]) {
+    let mut brightest: Vec<f32> = rgb
+        .iter()
+        .map(|pixel| pixel[0].max(pixel[1]).max(pixel[2]))
+        .collect();
+    if brightest.is_empty() {
+        return;
+    }
+    let index = (brightest.len() - 1) * 99 / 100;
+    let (_, &mut white, _) = brightest.select_nth_unstable_by(index, f32::total_cmp);
+    if !(white.is_finite() && white > 0.0) {
+        return;
+    }
+    rgb.iter_mut()
+        .for_each(|pixel| pixel.iter_mut().for_each(|value| *value /= white));
+}
+
+pub(crate) fn invert_matrix(m: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let determinant = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    Some([
+        [
+            cofactor(1, 2, 1, 2) / determinant,
+            -cofactor(0, 2, 1, 2) / determinant,
+            cofactor(0, 1, 1, 2) / determinant,
+        ],
+        [
+            -cofactor(1, 2, 0, 2) / determinant,
+            cofactor(0, 2, 0, 2) / determinant,
+            -cofactor(0, 1, 0, 2) / determinant,
+        ],
+        [
+            cofactor(1, 2, 0, 1) / determinant,
+            -cofactor(0, 2, 0, 1) / determinant,
+            cofactor(0, 1, 0, 1) / determinant,
+        ],
+    ])
+}
+
+/// The camera's XYZ to camera matrix as 9 values in row order, preferably the one for daylight
+fn xyz_to_camera_matrix(raw_image: &RawImage) -> Option<&[f32]> {
+    raw_image
+        .color_matrix
+        .get(&Illuminant::D65)
+        .or_else(|| raw_image.color_matrix.values().next())
+        .map(|matrix| matrix.as_slice())
+        .filter(|matrix| matrix.len() >= 9)
+}
+
+/// Multipliers which make the white point of a custom temperature and tint neutral, found by
+/// taking the white point to the camera's colors through its matrix. Returns `None` if the
+/// camera has no usable matrix.
+fn custom_white_balance_multipliers(
+    raw_image: &RawImage,
+    temperature: u32,
+    tint: f32,
+) -> Option<[f32; 3]> {
+    let xyz_to_camera = xyz_to_camera_matrix(raw_image)?;
+    let (x, y) = white_point_chromaticity(temperature, tint);
+    let xyz = [x / y, 1.0, (1.0 - x - y) / y];
+    let camera_white: [f32; 3] =
+        std::array::from_fn(|c| (0..3).map(|k| xyz_to_camera[c * 3 + k] * xyz[k]).sum());
+    let multipliers = camera_white.map(|white| camera_white[1] / white);
+    multipliers
+        .iter()
+        .all(|multiplier| multiplier.is_finite() && *multiplier > 0.0)
+        .then_some(multipliers)
+}
+
+fn white_balance(
+    raw_image: &RawImage,
+    sensor: &SensorData,
+    white_balance: WhiteBalance,
+) -> [f32; 3] {
+    match white_balance {
+        WhiteBalance::AsShot => white_balance_multipliers(raw_image.wb_coeffs),
+        WhiteBalance::Auto => sensor.gray_world_multipliers(),
+        WhiteBalance::Custom { temperature, tint } => {
+            custom_white_balance_multipliers(raw_image, temperature, tint).unwrap_or_else(|| {
+                warn!("No camera matrix for custom white balance, using the camera white balance");
+                white_balance_multipliers(raw_image.wb_coeffs)
+            })
+        }
+    }
+}
+
+/// Matrix from white balanced camera colors to linear sRGB, built from the camera's XYZ to
+/// camera matrix. Rows are normalized so a neutral camera color stays neutral. Cameras without
+/// a matrix keep their colors as they are.
+fn camera_to_srgb_matrix(raw_image: &RawImage) -> [[f32; 3]; 3] {
+    const IDENTITY: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    let Some(xyz_to_camera) = xyz_to_camera_matrix(raw_image) else {
+        return IDENTITY;
+    };
+
+    let mut srgb_to_camera = [[0.0f32; 3]; 3];
+    for (i, row) in srgb_to_camera.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3)
+                .map(|k| xyz_to_camera[i * 3 + k] * SRGB_TO_XYZ[k][j])
+                .sum();
+        }
+        let row_sum: f32 = row.iter().sum();
+        if row_sum.abs() < f32::EPSILON {
+            return IDENTITY;
+        }
+        row.iter_mut().for_each(|value| *value /= row_sum);
+    }
+    invert_matrix(srgb_to_camera).unwrap_or(IDENTITY)
+}
+
+/// Corrections stored in a DNG. rawler hands over the sensor data as stored in the file, so
+/// they are applied here. A DNG whose opcodes can't be read is still developed, just without
+/// them.
+fn read_dng_opcodes(path: &Path) -> dng_opcodes::DngOpcodes {
+    dng_opcodes::read_opcodes(path).unwrap_or_else(|e| {
+        warn!("Error reading DNG opcodes {e:?}");
+        dng_opcodes::DngOpcodes::default()
+    })
+}
+
+/// Develops a raw file from its sensor data: scales the black and white levels, applies the
+/// white balance, demosaics, applies the lens corrections of DNGs, handles clipped
+/// highlights and converts the camera colors to sRGB. Returns a 16 bit sRGB image.
+pub fn develop_raw_image(
+    path: &Path,
+    options: &RawDevelopOptions,
+) -> Result<DynamicImage, ExportError> {
+    let decode_error = |e: BoxError| ExportError::decode(DecodeBackend::Rawler, e);
+    let start = Instant::now();
+    let raw_source = RawSource::new(path).map_err(|e| decode_error(e.into()))?;
+    let decoder = rawler::get_decoder(&raw_source).map_err(|e| decode_error(e.into()))?;
+    let params = RawDecodeParams::default();
+    let raw_image = decoder
+        .raw_image(&raw_source, &params, false)
+        .map_err(|e| decode_error(e.into()))?;
+    // rawler doesn't fill in the orientation of the raw image, but it does read it from exif
+    let orientation = decoder
+        .raw_metadata(&raw_source, &params)
+        .ok()
+        .and_then(|metadata| metadata.exif.orientation)
+        .unwrap_or(0);
+    info!("Time to decode raw sensor data {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let mut sensor = SensorData::from_raw_image(&raw_image).map_err(decode_error)?;
+    let opcodes = read_dng_opcodes(path);
+    // Opcode coordinates are relative to the active area of the sensor
+    let active_area = match raw_image.active_area {
+        Some(area) => (area.p.x, area.p.y, area.d.w, area.d.h),
+        None => (0, 0, sensor.width, sensor.height),
+    };
+    let samples_per_pixel = match sensor.layout {
+        SensorLayout::Mosaic(_) => 1,
+        SensorLayout::Rgb => 3,
+    };
+    for gain_map in &opcodes.gain_maps {
+        gain_map.apply(
+            &mut sensor.samples,
+            sensor.width,
+            samples_per_pixel,
+            active_area,
+        );
+    }
+    let multipliers = white_balance(&raw_image, &sensor, options.white_balance);
+    sensor.apply_white_balance(multipliers);
+    let mut rgb = match &sensor.layout {
+        SensorLayout::Mosaic(cfa) => {
+            let mut rgb = demosaic_bilinear(&sensor, cfa);
+            if options.demosaic == DemosaicAlgorithm::Ahd && cfa.is_bayer() {
+                demosaic_ahd(&sensor, cfa, &mut rgb);
+            }
+            rgb
+        }
+        SensorLayout::Rgb => sensor
+            .samples
+            .chunks_exact(3)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect(),
+    };
+    info!("Time to demosaic raw image {:?}", start.elapsed());
+    for opcode in &opcodes.image_opcodes {
+        opcode.apply(&mut rgb, sensor.width, active_area);
+    }
+
+    if options.exposure_compensation != 0.0 {
+        let exposure = 2f32.powf(options.exposure_compensation);
+        rgb.iter_mut()
+            .for_each(|pixel| pixel.iter_mut().for_each(|value| *value *= exposure));
+    }
+    handle_highlights(&mut rgb, sensor.clip_level, options.highlights);
+    let to_srgb = camera_to_srgb_matrix(&raw_image);
+    for pixel in rgb.iter_mut() {
+        let camera = *pixel;
+        *pixel = to_srgb.map(|row| row[0] * camera[0] + row[1] * camera[1] + row[2] * camera[2]);
+    }
+    if options.auto_brightness {
+        auto_brighten(&mut rgb);
+    }
+    let mut samples: Vec<u16> = rgb
+        .iter()
+        .flatten()
+        .map(|value| (value.clamp(0.0, 1.0) * 65535.0).round() as u16)
+        .collect();
+    apply_transfer_lut(&mut samples, 3, linear_to_srgb_lut());
+
+    let image_buffer = ImageBuffer::from_raw(sensor.width as u32, sensor.height as u32, samples)
+        .ok_or_else(|| decode_error("Error creating image from raw data".into()))?;
+    let mut image = DynamicImage::ImageRgb16(image_buffer);
+    if let Some(crop) = raw_image.crop_area {
+        image = image.crop_imm(
+            crop.p.x as u32,
+            crop.p.y as u32,
+            crop.d.w as u32,
+            crop.d.h as u32,
+        );
+    }
+    Ok(rotate_to_orientation(image, orientation))
+}
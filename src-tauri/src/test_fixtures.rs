@@ -0,0 +1,210 @@
+//! Files built in memory for tests, so the repo doesn't need binary fixtures
+
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_UNDEFINED: u16 = 7;
+const TYPE_SRATIONAL: u16 = 10;
+
+pub const TAG_MAKE: u16 = 271;
+
+/// Little endian tiff with a single directory holding one strip of image data
+#[derive(Default)]
+pub struct TiffBuilder {
+    /// Tag, type, value count and the value's bytes
+    entries: Vec<(u16, u16, u32, Vec<u8>)>,
+}
+impl TiffBuilder {
+    fn entry(mut self, tag: u16, field_type: u16, count: usize, value: Vec<u8>) -> Self {
+        self.entries.retain(|entry| entry.0 != tag);
+        self.entries.push((tag, field_type, count as u32, value));
+        self
+    }
+
+    pub fn bytes(self, tag: u16, values: &[u8]) -> Self {
+        self.entry(tag, TYPE_BYTE, values.len(), values.to_vec())
+    }
+
+    pub fn undefined(self, tag: u16, values: &[u8]) -> Self {
+        self.entry(tag, TYPE_UNDEFINED, values.len(), values.to_vec())
+    }
+
+    pub fn ascii(self, tag: u16, value: &str) -> Self {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.entry(tag, TYPE_ASCII, bytes.len(), bytes)
+    }
+
+    pub fn shorts(self, tag: u16, values: &[u16]) -> Self {
+        let bytes = values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        self.entry(tag, TYPE_SHORT, values.len(), bytes)
+    }
+
+    pub fn longs(self, tag: u16, values: &[u32]) -> Self {
+        let bytes = values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        self.entry(tag, TYPE_LONG, values.len(), bytes)
+    }
+
+    pub fn rationals(self, tag: u16, values: &[(u32, u32)]) -> Self {
+        let bytes = values
+            .iter()
+            .flat_map(|(numerator, denominator)| {
+                [numerator.to_le_bytes(), denominator.to_le_bytes()]
+            })
+            .flatten()
+            .collect();
+        self.entry(tag, TYPE_RATIONAL, values.len(), bytes)
+    }
+
+    pub fn srationals(self, tag: u16, values: &[(i32, i32)]) -> Self {
+        let bytes = values
+            .iter()
+            .flat_map(|(numerator, denominator)| {
+                [numerator.to_le_bytes(), denominator.to_le_bytes()]
+            })
+            .flatten()
+            .collect();
+        self.entry(tag, TYPE_SRATIONAL, values.len(), bytes)
+    }
+
+    /// Lay out the directory, then the values which don't fit in their entries, then the strip
+    pub fn build(self, strip: &[u8]) -> Vec<u8> {
+        let mut entries = self
+            .longs(273, &[0])
+            .longs(279, &[strip.len() as u32])
+            .entries;
+        entries.sort_by_key(|entry| entry.0);
+
+        let directory_len = 2 + entries.len() * 12 + 4;
+        let mut values = Vec::new();
+        let mut value_offsets = Vec::new();
+        for (_, _, _, value) in &entries {
+            if value.len() > 4 {
+                value_offsets.push(Some(8 + directory_len + values.len()));
+                values.extend_from_slice(value);
+                if values.len() % 2 == 1 {
+                    values.push(0);
+                }
+            } else {
+                value_offsets.push(None);
+            }
+        }
+        let strip_offset = (8 + directory_len + values.len()) as u32;
+
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for ((tag, field_type, count, value), value_offset) in entries.iter().zip(value_offsets) {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&field_type.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            let mut inline = match value_offset {
+                Some(offset) => (offset as u32).to_le_bytes().to_vec(),
+                None if *tag == 273 => strip_offset.to_le_bytes().to_vec(),
+                None => value.clone(),
+            };
+            inline.resize(4, 0);
+            tiff.extend_from_slice(&inline);
+        }
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&values);
+        tiff.extend_from_slice(strip);
+        tiff
+    }
+}
+
+/// A plain 8 bit rgb tiff, as a camera or an editor would save it
+pub fn rgb_tiff(make: &str, width: u32, height: u32) -> Vec<u8> {
+    TiffBuilder::default()
+        .longs(256, &[width])
+        .longs(257, &[height])
+        .shorts(258, &[8, 8, 8])
+        .shorts(259, &[1])
+        .shorts(262, &[2])
+        .ascii(TAG_MAKE, make)
+        .shorts(277, &[3])
+        .longs(278, &[height])
+        .build(&vec![128; (width * height * 3) as usize])
+}
+
+/// The directory of a 16 bit bayer DNG, uncompressed with an RGGB pattern, a daylight color
+/// matrix and a gradient so demosaicing has something to work on. More tags, like opcode
+/// lists, can be added before building it with `bayer_dng_strip`.
+pub fn bayer_dng(width: u32, height: u32) -> TiffBuilder {
+    TiffBuilder::default()
+        .longs(254, &[0])
+        .longs(256, &[width])
+        .longs(257, &[height])
+        .shorts(258, &[16])
+        .shorts(259, &[1])
+        // Color filter array
+        .shorts(262, &[32803])
+        .ascii(TAG_MAKE, "Canon")
+        .ascii(272, "Canon EOS 5D Mark II")
+        .shorts(274, &[1])
+        .shorts(277, &[1])
+        .longs(278, &[height])
+        .shorts(284, &[1])
+        .shorts(33421, &[2, 2])
+        .bytes(33422, &[0, 1, 1, 2])
+        // DNG version 1.4
+        .bytes(50706, &[1, 4, 0, 0])
+        .bytes(50707, &[1, 1, 0, 0])
+        .ascii(50708, "Canon EOS 5D Mark II")
+        .longs(50714, &[256])
+        .longs(50717, &[16383])
+        .srationals(
+            50721,
+            &[
+                (4716, 10000),
+                (603, 10000),
+                (-830, 10000),
+                (-7798, 10000),
+                (15474, 10000),
+                (2480, 10000),
+                (-1496, 10000),
+                (1937, 10000),
+                (6651, 10000),
+            ],
+        )
+        .rationals(50728, &[(1, 2), (1, 1), (2, 3)])
+        // D65
+        .shorts(50778, &[21])
+}
+
+/// Sensor data for `bayer_dng`, brighter to the right and the bottom
+pub fn bayer_dng_strip(width: u32, height: u32) -> Vec<u8> {
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| 256 + (x + y) * 16000 / (width + height)))
+        .flat_map(|value| (value as u16).to_le_bytes())
+        .collect()
+}
+
+/// A complete bayer DNG without opcodes
+pub fn small_dng() -> Vec<u8> {
+    bayer_dng(32, 32).build(&bayer_dng_strip(32, 32))
+}
+
+/// Test data written to a temporary file, which is removed when dropped
+pub struct TempFile(pub std::path::PathBuf);
+impl TempFile {
+    /// `name` has to be unique among the tests, which run in parallel
+    pub fn new(name: &str, data: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!("vikara-{}-{name}", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        TempFile(path)
+    }
+}
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
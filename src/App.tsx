@@ -13,29 +13,141 @@ import {
   NumberInput,
   Space,
   Slider,
+  TextInput,
+  Modal,
+  ColorInput,
 } from "@mantine/core";
 import {
   resizeResolutionInOptions,
   resizeInOptions,
   resizeToFitOptions,
-  imageFormatOptions,
+  avifBitDepthOptions,
+  tiffBitDepthOptions,
+  tiffCompressionOptions,
+  heicChromaOptions,
+  heicBitDepthOptions,
+  dngCompressionOptions,
+  dngPreviewOptions,
+  colorSpaceOptions,
+  renameToOptions,
+  fileExtensionsCaseOptions,
+  existingFileActionOptions,
+  metadataPolicyOptions,
+  resamplingFilterOptions,
+  rawWhiteBalanceOptions,
+  rawHighlightModeOptions,
+  rawDemosaicAlgorithmOptions,
   INITIAL_VALUES,
 } from "./export_form_input_config";
 import { zodResolver } from "mantine-form-zod-resolver";
-import { useState } from "react";
+import { useEffect, useState } from "react";
 import { open } from "@tauri-apps/plugin-dialog";
-import { invoke } from "@tauri-apps/api/core";
+import { Channel, invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import { exportFormSchema, ExportSettings } from "./export_form_schema";
 import { notifications } from "@mantine/notifications";
-import { SUPPORTED_FILE_EXTENSIONS } from "./constants";
 
 function showItemInFolder(path: string) {
   return invoke("show_item_in_folder", { path });
 }
 
+type ExportErrorCode =
+  | "read_failed"
+  | "decode_failed"
+  | "unsupported_format"
+  | "resize_failed"
+  | "color_conversion_failed"
+  | "encode_failed"
+  | "write_failed"
+  | "permission_denied"
+  | "invalid_settings"
+  | "internal";
+
+type ExportError = {
+  code: ExportErrorCode;
+  message: string;
+  backend: "image" | "libraw" | "rawler" | "libheif" | null;
+  causes: string[];
+};
+
+type ImageExportResult = {
+  inputPath: string;
+  outputPath: string | null;
+  status: "ok" | "skipped" | "failed" | "cancelled";
+  leftSmallerThanTarget: boolean;
+  errorCode: ExportErrorCode | null;
+  message: string | null;
+  error: ExportError | null;
+  durationMs: number;
+  inputBytes: number | null;
+  outputBytes: number | null;
+};
+
+type ConvertResult = {
+  jobId: number;
+  cancelled: boolean;
+  results: ImageExportResult[];
+};
+
+type ExportProgress =
+  | { event: "jobStarted"; jobId: number }
+  | { event: "started"; imageIndex: number; imagePath: string }
+  | { event: "decoded" | "resized" | "encoded"; imageIndex: number }
+  | {
+      event: "written" | "skipped";
+      imageIndex: number;
+      exportPath: string;
+    }
+  | { event: "cancelled"; imageIndex: number }
+  | {
+      event: "failed";
+      imageIndex: number;
+      errorCode: ExportErrorCode;
+      errorMessage: string;
+    };
+
+type FileConflict = {
+  conflictId: number;
+  imagePath: string;
+  exportFilePath: string;
+};
+
+type ExistingFileAction = "choose_new_name" | "overwrite_without_warning" | "skip";
+
+type SupportedFormats = {
+  readable: { name: string; extensions: string[]; raw: boolean }[];
+  writable: {
+    format: ExportSettings["fileSettings"]["imageFormat"];
+    name: string;
+    extension: string | null;
+    options: string[];
+    rawSourcesOnly: boolean;
+  }[];
+};
+
 function App() {
   const [imagePath, setImagePath] = useState<string | null>(null);
   const [imageSrc, setImageSrc] = useState<string | null>(null);
+  const [supportedFormats, setSupportedFormats] =
+    useState<SupportedFormats | null>(null);
+  useEffect(() => {
+    invoke<SupportedFormats>("get_supported_formats").then(
+      setSupportedFormats,
+    );
+  }, []);
+  const imageFormatOptions = (supportedFormats?.writable ?? []).map(
+    (format) => ({
+      label: format.rawSourcesOnly
+        ? `${format.name} (raw files only)`
+        : format.name,
+      value: format.format,
+    }),
+  );
+  // Formats we can fall back to when "Original" is selected and the source format can't be
+  // written. Raw only formats like DNG are left out
+  const originalFallbackFormatOptions = (supportedFormats?.writable ?? [])
+    .filter((format) => format.format !== "original" && !format.rawSourcesOnly)
+    .map((format) => ({ label: format.name, value: format.format }));
   const form = useForm({
     mode: "controlled",
     initialValues: INITIAL_VALUES,
@@ -52,19 +164,115 @@ function App() {
       resizeResolution,
       resizeResolutionIn,
     },
-    fileSettings: { imageFormat },
+    fileSettings: { imageFormat, webp, tiff, jpegXl, heic },
+    fileRenaming: { enableRenaming, renameTo },
+    rawDevelop,
   } = form.values;
 
+  const [fileConflict, setFileConflict] = useState<FileConflict | null>(null);
+  const [applyToAllConflicts, setApplyToAllConflicts] = useState(false);
+  useEffect(() => {
+    const unlisten = listen<FileConflict>("export-file-conflict", (event) => {
+      setFileConflict(event.payload);
+    });
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, []);
+  async function resolveFileConflict(action: ExistingFileAction) {
+    if (fileConflict) {
+      setFileConflict(null);
+      await invoke("resolve_file_conflict", {
+        conflictId: fileConflict.conflictId,
+        answer: { action, applyToAll: applyToAllConflicts },
+      });
+      setApplyToAllConflicts(false);
+    }
+  }
+
   const [converting, setConverting] = useState(false);
+  const [exportedCount, setExportedCount] = useState(0);
+  const [exportJobId, setExportJobId] = useState<number | null>(null);
+  const [exportPaused, setExportPaused] = useState(false);
+  async function cancelExport() {
+    if (exportJobId !== null) {
+      await invoke("cancel_export", { jobId: exportJobId });
+    }
+  }
+  async function togglePauseExport() {
+    if (exportJobId !== null) {
+      await invoke(exportPaused ? "resume_export" : "pause_export", {
+        jobId: exportJobId,
+      });
+      setExportPaused(!exportPaused);
+    }
+  }
   async function handleSubmit(values: ExportSettings) {
     try {
       if (imagePath) {
         setConverting(true);
+        setExportedCount(0);
+        const onProgress = new Channel<ExportProgress>();
+        onProgress.onmessage = (progress) => {
+          if (progress.event === "jobStarted") {
+            setExportJobId(progress.jobId);
+          } else if (
+            progress.event === "written" ||
+            progress.event === "skipped" ||
+            progress.event === "failed"
+          ) {
+            setExportedCount((count) => count + 1);
+          }
+        };
         await new Promise((res) => setTimeout(res, 5000));
-        await invoke("convert_images", {
+        const convertResult = await invoke<ConvertResult>("convert_images", {
           imagePaths: [imagePath],
           exportSettings: values,
+          onProgress,
         });
+        if (convertResult.cancelled) {
+          notifications.show({
+            title: "Export cancelled",
+            message: "The export was cancelled before all images were exported",
+            autoClose: false,
+          });
+          return;
+        }
+        const failedResults = convertResult.results.filter(
+          (result) => result.status === "failed",
+        );
+        if (failedResults.length > 0) {
+          notifications.show({
+            title: "Image not exported",
+            message: failedResults
+              .map((result) => `${result.inputPath}: ${result.message}`)
+              .join("\n"),
+            color: "red",
+            autoClose: false,
+          });
+          return;
+        }
+        const skippedResults = convertResult.results.filter(
+          (result) => result.status === "skipped",
+        );
+        if (skippedResults.length > 0) {
+          notifications.show({
+            title: "Image not exported",
+            message: skippedResults.map((result) => result.message).join("\n"),
+            autoClose: false,
+          });
+          return;
+        }
+        const notEnlargedResults = convertResult.results.filter(
+          (result) => result.leftSmallerThanTarget,
+        );
+        if (notEnlargedResults.length > 0) {
+          notifications.show({
+            title: "Image not enlarged",
+            message: `${notEnlargedResults.map((result) => result.outputPath).join(", ")} was left smaller than the requested size`,
+            autoClose: false,
+          });
+        }
         notifications.show({
           message: (
             <Flex align="center">
@@ -106,37 +314,63 @@ function App() {
       });
     } finally {
       setConverting(false);
+      setExportJobId(null);
+      setExportPaused(false);
     }
   }
 
   const [imageLoading, setImageLoading] = useState(false);
-  async function openFileDialog() {
-    const imagePath = await open({
-      multiple: false,
-      directory: false,
-      filters: [
-        {
-          name: "file_extensions",
-          extensions: SUPPORTED_FILE_EXTENSIONS,
-        },
-      ],
-    });
+  async function loadImage(imagePath: string) {
     try {
       setImageLoading(true);
-      const imageBase64 = await invoke("load_image", { imagePath });
+      const imageBase64 = await invoke("load_image", {
+        imagePath,
+        rawDevelop,
+      });
       setImagePath(imagePath);
       setImageSrc(`data:image/jpeg;base64, ${imageBase64}`);
     } catch (e) {
       console.error("Error loading image:", e);
+      const loadError = e as ExportError;
       notifications.show({
         title: "Error loading image",
-        message: "We encountered an error while loading the image",
+        message:
+          loadError.code === "unsupported_format"
+            ? loadError.message
+            : "We encountered an error while loading the image",
         autoClose: false,
       });
     } finally {
       setImageLoading(false);
     }
   }
+  async function openFileDialog() {
+    const imagePath = await open({
+      multiple: false,
+      directory: false,
+      filters: [
+        {
+          name: "file_extensions",
+          extensions: (supportedFormats?.readable ?? []).flatMap(
+            (format) => format.extensions,
+          ),
+        },
+      ],
+    });
+    if (imagePath) {
+      await loadImage(imagePath);
+    }
+  }
+  // Raw previews are developed with the raw development settings, so they are redone when
+  // those change
+  const rawDevelopKey = JSON.stringify(rawDevelop);
+  useEffect(() => {
+    if (!imagePath) {
+      return;
+    }
+    const timeout = setTimeout(() => loadImage(imagePath), 500);
+    return () => clearTimeout(timeout);
+  }, [rawDevelopKey]);
   function handleClearClick() {
     setImagePath(null);
     setImageSrc(null);
@@ -266,6 +500,43 @@ function App() {
   }
 
   return (
+    <>
+      <Modal
+        opened={fileConflict !== null}
+        onClose={() => resolveFileConflict("skip")}
+        title="File already exists"
+      >
+        <Text size="sm">
+          {fileConflict?.exportFilePath} already exists. What would you like to
+          do?
+        </Text>
+        <Space h="md" />
+        <Checkbox
+          label="Do this for all remaining files"
+          checked={applyToAllConflicts}
+          onChange={(event) =>
+            setApplyToAllConflicts(event.currentTarget.checked)
+          }
+        />
+        <Space h="md" />
+        <Flex gap="sm" justify="end">
+          <Button variant="default" onClick={() => resolveFileConflict("skip")}>
+            Skip
+          </Button>
+          <Button
+            variant="light"
+            onClick={() => resolveFileConflict("choose_new_name")}
+          >
+            Use unique name
+          </Button>
+          <Button
+            color="red"
+            onClick={() => resolveFileConflict("overwrite_without_warning")}
+          >
+            Overwrite
+          </Button>
+        </Flex>
+      </Modal>
     <Flex
       component="main"
       direction="column"
@@ -328,6 +599,26 @@ function App() {
             <>
               <Divider orientation="horizontal" />
               <Flex align="center" justify="end" gap="md" p="md">
+                {converting ? (
+                  <>
+                    <Text size="sm">{exportedCount} of 1 exported</Text>
+                    <Button
+                      variant="default"
+                      onClick={togglePauseExport}
+                      disabled={exportJobId === null}
+                    >
+                      {exportPaused ? "Resume" : "Pause"}
+                    </Button>
+                    <Button
+                      variant="default"
+                      color="red"
+                      onClick={cancelExport}
+                      disabled={exportJobId === null}
+                    >
+                      Cancel
+                    </Button>
+                  </>
+                ) : null}
                 <Button
                   variant="light"
                   onClick={handleClearClick}
@@ -366,7 +657,164 @@ function App() {
                 allowDeselect={false}
               />
               <Space h="md" />
-              {imageFormat === "jpeg" || imageFormat === "avif" ? (
+              {imageFormat === "original" ? (
+                <>
+                  <Select
+                    label="Format for RAW, HEIC and other unwritable formats"
+                    data={originalFallbackFormatOptions}
+                    {...form.getInputProps("fileSettings.originalFallbackFormat")}
+                    allowDeselect={false}
+                  />
+                  <Space h="md" />
+                </>
+              ) : null}
+              {imageFormat === "webp" ? (
+                <>
+                  <Checkbox
+                    label="Lossless"
+                    {...form.getInputProps("fileSettings.webp.lossless", {
+                      type: "checkbox",
+                    })}
+                    key={form.key("fileSettings.webp.lossless")}
+                  />
+                  <Space h="md" />
+                </>
+              ) : null}
+              {imageFormat === "avif" ? (
+                <>
+                  <NumberInput
+                    label="Encoder speed"
+                    min={1}
+                    max={10}
+                    {...form.getInputProps("fileSettings.avif.speed")}
+                  />
+                  <Select
+                    label="Bit depth"
+                    data={avifBitDepthOptions}
+                    {...form.getInputProps("fileSettings.avif.bitDepth")}
+                    allowDeselect={false}
+                  />
+                  <Space h="md" />
+                </>
+              ) : null}
+              {imageFormat === "tiff" ? (
+                <>
+                  <Select
+                    label="Compression"
+                    data={tiffCompressionOptions}
+                    {...form.getInputProps("fileSettings.tiff.compression")}
+                    allowDeselect={false}
+                  />
+                  <Select
+                    label="Bit depth"
+                    data={tiffBitDepthOptions}
+                    {...form.getInputProps("fileSettings.tiff.bitDepth")}
+                    allowDeselect={false}
+                  />
+                  <Space h="xs" />
+                  <Checkbox
+                    label="Include transparency"
+                    {...form.getInputProps("fileSettings.tiff.alpha", {
+                      type: "checkbox",
+                    })}
+                    key={form.key("fileSettings.tiff.alpha")}
+                  />
+                  <Space h="md" />
+                </>
+              ) : null}
+              {imageFormat === "jpeg" ||
+              (imageFormat === "tiff" && !tiff.alpha) ? (
+                <>
+                  <ColorInput
+                    label="Background for transparent areas"
+                    format="hex"
+                    {...form.getInputProps("fileSettings.matteColor")}
+                  />
+                  <Space h="md" />
+                </>
+              ) : null}
+              {imageFormat === "jpeg_xl" ? (
+                <>
+                  <NumberInput
+                    label="Effort"
+                    min={1}
+                    max={9}
+                    {...form.getInputProps("fileSettings.jpegXl.effort")}
+                  />
+                  <Space h="xs" />
+                  <Checkbox
+                    label="Lossless"
+                    {...form.getInputProps("fileSettings.jpegXl.lossless", {
+                      type: "checkbox",
+                    })}
+                    key={form.key("fileSettings.jpegXl.lossless")}
+                  />
+                  <Space h="xs" />
+                  <Checkbox
+                    label="Repack JPEG sources losslessly"
+                    {...form.getInputProps(
+                      "fileSettings.jpegXl.losslessJpegTranscode",
+                      { type: "checkbox" },
+                    )}
+                    key={form.key("fileSettings.jpegXl.losslessJpegTranscode")}
+                  />
+                  <Space h="md" />
+                </>
+              ) : null}
+              {imageFormat === "heic" ? (
+                <>
+                  <Checkbox
+                    label="Lossless"
+                    {...form.getInputProps("fileSettings.heic.lossless", {
+                      type: "checkbox",
+                    })}
+                    key={form.key("fileSettings.heic.lossless")}
+                  />
+                  <Select
+                    label="Chroma subsampling"
+                    data={heicChromaOptions}
+                    {...form.getInputProps("fileSettings.heic.chroma")}
+                    disabled={heic.lossless}
+                    allowDeselect={false}
+                  />
+                  <Select
+                    label="Bit depth"
+                    data={heicBitDepthOptions}
+                    {...form.getInputProps("fileSettings.heic.bitDepth")}
+                    allowDeselect={false}
+                  />
+                  <Space h="md" />
+                </>
+              ) : null}
+              {imageFormat === "dng" ? (
+                <>
+                  <Select
+                    label="Compression"
+                    data={dngCompressionOptions}
+                    {...form.getInputProps("fileSettings.dng.compression")}
+                    allowDeselect={false}
+                  />
+                  <Select
+                    label="Embedded preview"
+                    data={dngPreviewOptions}
+                    {...form.getInputProps("fileSettings.dng.preview")}
+                    allowDeselect={false}
+                  />
+                  <Checkbox
+                    label="Embed original raw file"
+                    {...form.getInputProps("fileSettings.dng.embedOriginal", {
+                      type: "checkbox",
+                    })}
+                    key={form.key("fileSettings.dng.embedOriginal")}
+                  />
+                  <Space h="md" />
+                </>
+              ) : null}
+              {imageFormat === "jpeg" ||
+              imageFormat === "avif" ||
+              (imageFormat === "heic" && !heic.lossless) ||
+              (imageFormat === "jpeg_xl" && !jpegXl.lossless) ||
+              (imageFormat === "webp" && !webp.lossless) ? (
                 <Flex gap={4}>
                   <Text size="sm">Quality</Text>
                   <Space w={4} />
@@ -378,6 +826,71 @@ function App() {
                   />
                 </Flex>
               ) : null}
+              <Space h="md" />
+              <Select
+                label="Color Space"
+                data={colorSpaceOptions}
+                {...form.getInputProps("fileSettings.colorSpace")}
+                allowDeselect={false}
+              />
+            </Fieldset>
+            <Fieldset legend="Export Location">
+              <Select
+                label="Existing Files"
+                data={existingFileActionOptions}
+                {...form.getInputProps("exportLocation.existingFileAction")}
+                allowDeselect={false}
+              />
+            </Fieldset>
+            <Fieldset
+              legend="File Naming"
+              style={{ display: "flex", flexDirection: "column", gap: 16 }}
+            >
+              <Checkbox
+                label="Rename to"
+                {...form.getInputProps("fileRenaming.enableRenaming", {
+                  type: "checkbox",
+                })}
+                key={form.key("fileRenaming.enableRenaming")}
+              />
+              <Select
+                data={renameToOptions}
+                {...form.getInputProps("fileRenaming.renameTo")}
+                disabled={!enableRenaming}
+                allowDeselect={false}
+              />
+              {renameTo === "custom_name" ||
+              renameTo === "custom_name_sequence" ? (
+                <TextInput
+                  label="Custom Text"
+                  {...form.getInputProps("fileRenaming.customName")}
+                  disabled={!enableRenaming}
+                />
+              ) : null}
+              {renameTo === "template" ? (
+                <TextInput
+                  label="Template"
+                  description="Tokens: {name}, {seq}, {seq:04}, {date}, {date:%Y-%m-%d}, {camera}, {custom}"
+                  {...form.getInputProps("fileRenaming.template")}
+                  disabled={!enableRenaming}
+                />
+              ) : null}
+              {renameTo === "filename_sequence" ||
+              renameTo === "custom_name_sequence" ||
+              renameTo === "template" ? (
+                <NumberInput
+                  label="Start Number"
+                  min={0}
+                  {...form.getInputProps("fileRenaming.startNumber")}
+                  disabled={!enableRenaming}
+                />
+              ) : null}
+              <Select
+                label="Extensions"
+                data={fileExtensionsCaseOptions}
+                {...form.getInputProps("fileRenaming.fileExtensionsCase")}
+                allowDeselect={false}
+              />
             </Fieldset>
             <Fieldset
               legend="Image Sizing"
@@ -402,7 +915,16 @@ function App() {
                 disabled={!resizeEnabled}
                 allowDeselect={false}
               />
-              {resizeToFit === "width_and_height" ? (
+              <Checkbox
+                label="Enlarge smaller images"
+                {...form.getInputProps("imageSizing.enlarge", {
+                  type: "checkbox",
+                })}
+                disabled={!resizeEnabled}
+                key={form.key("imageSizing.enlarge")}
+              />
+              {resizeToFit === "width_and_height" ||
+              resizeToFit === "dimensions" ? (
                 <>
                   <NumberInput
                     label="Width"
@@ -464,11 +986,133 @@ function App() {
                 disabled={!resizeEnabled}
                 allowDeselect={false}
               />
+              <Select
+                label="Resampling"
+                data={resamplingFilterOptions}
+                {...form.getInputProps("imageSizing.resamplingFilter")}
+                disabled={!resizeEnabled}
+                allowDeselect={false}
+              />
+              <Checkbox
+                label="Resize in linear light"
+                {...form.getInputProps("imageSizing.linearLight", {
+                  type: "checkbox",
+                })}
+                disabled={!resizeEnabled}
+                key={form.key("imageSizing.linearLight")}
+              />
+            </Fieldset>
+            <Fieldset
+              legend="Raw Development"
+              style={{ display: "flex", flexDirection: "column", gap: 16 }}
+            >
+              <Select
+                label="White Balance"
+                data={rawWhiteBalanceOptions}
+                {...form.getInputProps("rawDevelop.whiteBalance")}
+                allowDeselect={false}
+              />
+              {rawDevelop.whiteBalance === "custom" ? (
+                <Flex gap="sm">
+                  <NumberInput
+                    label="Temperature (K)"
+                    min={2000}
+                    max={25000}
+                    step={100}
+                    allowDecimal={false}
+                    {...form.getInputProps("rawDevelop.temperature")}
+                    style={{ flex: 1 }}
+                  />
+                  <NumberInput
+                    label="Tint"
+                    min={-150}
+                    max={150}
+                    {...form.getInputProps("rawDevelop.tint")}
+                    style={{ flex: 1 }}
+                  />
+                </Flex>
+              ) : null}
+              <NumberInput
+                label="Exposure (stops)"
+                min={-2}
+                max={3}
+                step={0.1}
+                decimalScale={2}
+                {...form.getInputProps("rawDevelop.exposureCompensation")}
+              />
+              <Checkbox
+                label="Auto brightness"
+                {...form.getInputProps("rawDevelop.autoBrightness", {
+                  type: "checkbox",
+                })}
+                key={form.key("rawDevelop.autoBrightness")}
+              />
+              <Select
+                label="Highlights"
+                data={rawHighlightModeOptions}
+                {...form.getInputProps("rawDevelop.highlightMode")}
+                allowDeselect={false}
+              />
+              <Select
+                label="Demosaic"
+                data={rawDemosaicAlgorithmOptions}
+                {...form.getInputProps("rawDevelop.demosaicAlgorithm")}
+                allowDeselect={false}
+              />
+              <NumberInput
+                label="Noise reduction"
+                description="0 turns it off, 100 to 1000 is typical"
+                min={0}
+                max={2000}
+                allowDecimal={false}
+                {...form.getInputProps("rawDevelop.noiseThreshold")}
+              />
+              <Select
+                label="Working Color Space"
+                data={colorSpaceOptions}
+                {...form.getInputProps("rawDevelop.outputColorSpace")}
+                allowDeselect={false}
+              />
+              <Checkbox
+                label="Develop in 16 bit"
+                {...form.getInputProps("rawDevelop.output16bit", {
+                  type: "checkbox",
+                })}
+                key={form.key("rawDevelop.output16bit")}
+              />
+            </Fieldset>
+            <Fieldset legend="Metadata">
+              <Select
+                label="Include"
+                data={metadataPolicyOptions}
+                {...form.getInputProps("metadataPolicy")}
+                allowDeselect={false}
+              />
+            </Fieldset>
+            <Fieldset
+              legend="Performance"
+              style={{ display: "flex", flexDirection: "column", gap: 16 }}
+            >
+              <NumberInput
+                label="Parallel exports"
+                description="0 uses one per CPU core"
+                min={0}
+                allowDecimal={false}
+                {...form.getInputProps("batch.maxParallelExports")}
+              />
+              <NumberInput
+                label="Memory budget (MB)"
+                description="0 means no limit"
+                min={0}
+                allowDecimal={false}
+                {...form.getInputProps("batch.memoryBudgetMb")}
+              />
             </Fieldset>
           </form>
         </Flex>
       </Flex>
     </Flex>
+    </>
   );
 }
 
@@ -1,35 +0,0 @@
-export const SUPPORTED_FILE_EXTENSIONS = [
-  "jpeg",
-  "jpg",
-  "png",
-  "tiff",
-  "webp",
-  "bmp",
-  "ico",
-  "gif",
-  "avif",
-  "heic",
-  "raf",
-  "cr2",
-  "mrw",
-  "arw",
-  "srf",
-  "sr2",
-  "mef",
-  "orf",
-  "srw",
-  "erf",
-  "kdc",
-  "dcs",
-  "rw2",
-  "dcr",
-  "pef",
-  "crw",
-  "raw",
-  "iiq",
-  "3rf",
-  "nrw",
-  "nef",
-  "mos",
-  "ari",
-];
@@ -30,6 +30,10 @@ export const resizeToFitOptions = [
     label: "Width and Height",
     value: "width_and_height",
   },
+  {
+    label: "Dimensions",
+    value: "dimensions",
+  },
   {
     label: "Long edge",
     value: "long_edge",
@@ -43,28 +47,318 @@ export const resizeToFitOptions = [
     value: "megapixels",
   },
 ];
-export const imageFormatOptions = [
+export const avifBitDepthOptions = [
+  {
+    label: "8 bit",
+    value: "eight",
+  },
+  {
+    label: "10 bit",
+    value: "ten",
+  },
+];
+export const tiffBitDepthOptions = [
+  {
+    label: "8 bits/component",
+    value: "eight",
+  },
+  {
+    label: "16 bits/component",
+    value: "sixteen",
+  },
+];
+export const tiffCompressionOptions = [
+  {
+    label: "None",
+    value: "none",
+  },
+  {
+    label: "LZW",
+    value: "lzw",
+  },
+  {
+    label: "Deflate",
+    value: "deflate",
+  },
+];
+export const heicChromaOptions = [
+  {
+    label: "4:2:0",
+    value: "420",
+  },
+  {
+    label: "4:2:2",
+    value: "422",
+  },
+  {
+    label: "4:4:4",
+    value: "444",
+  },
+];
+export const heicBitDepthOptions = [
+  {
+    label: "8 bit",
+    value: "eight",
+  },
+  {
+    label: "10 bit",
+    value: "ten",
+  },
+];
+export const dngCompressionOptions = [
+  {
+    label: "Lossless",
+    value: "lossless",
+  },
+  {
+    label: "Uncompressed",
+    value: "uncompressed",
+  },
+];
+export const dngPreviewOptions = [
+  {
+    label: "None",
+    value: "none",
+  },
+  {
+    label: "Thumbnail only",
+    value: "thumbnail",
+  },
+  {
+    label: "Thumbnail and preview",
+    value: "full",
+  },
+];
+export const colorSpaceOptions = [
+  {
+    label: "sRGB",
+    value: "srgb",
+  },
+  {
+    label: "Display P3",
+    value: "display_p3",
+  },
+  {
+    label: "Adobe RGB (1998)",
+    value: "adobe_rgb",
+  },
+  {
+    label: "ProPhoto RGB",
+    value: "prophoto_rgb",
+  },
+  {
+    label: "Rec. 2020",
+    value: "rec2020",
+  },
+];
+export const renameToOptions = [
+  {
+    label: "Filename",
+    value: "original_name",
+  },
+  {
+    label: "Filename - Sequence",
+    value: "filename_sequence",
+  },
+  {
+    label: "Date - Filename",
+    value: "date_filename",
+  },
+  {
+    label: "Custom Name",
+    value: "custom_name",
+  },
+  {
+    label: "Custom Name - Sequence",
+    value: "custom_name_sequence",
+  },
+  {
+    label: "Template",
+    value: "template",
+  },
+];
+export const fileExtensionsCaseOptions = [
+  {
+    label: "lowercase",
+    value: "lower",
+  },
+  {
+    label: "UPPERCASE",
+    value: "upper",
+  },
+];
+export const existingFileActionOptions = [
+  {
+    label: "Ask what to do",
+    value: "ask_what_to_do",
+  },
   {
-    label: "JPEG",
-    value: "jpeg",
+    label: "Choose a new name for the exported file",
+    value: "choose_new_name",
   },
   {
-    label: "PNG",
-    value: "png",
+    label: "Overwrite WITHOUT WARNING",
+    value: "overwrite_without_warning",
   },
   {
-    label: "Original",
-    value: "original",
+    label: "Skip",
+    value: "skip",
+  },
+];
+export const resamplingFilterOptions = [
+  {
+    label: "Nearest (pixel art)",
+    value: "nearest",
+  },
+  {
+    label: "Bilinear",
+    value: "bilinear",
+  },
+  {
+    label: "Catmull-Rom",
+    value: "catmull_rom",
+  },
+  {
+    label: "Mitchell",
+    value: "mitchell",
+  },
+  {
+    label: "Lanczos3 (photos)",
+    value: "lanczos3",
+  },
+  {
+    label: "Supersampling (fast)",
+    value: "supersampling",
+  },
+];
+export const metadataPolicyOptions = [
+  {
+    label: "All Metadata",
+    value: "keep_all",
+  },
+  {
+    label: "All Except Location Info",
+    value: "remove_location",
+  },
+  {
+    label: "Copyright & Contact Info Only",
+    value: "copyright_and_contact_only",
+  },
+  {
+    label: "None",
+    value: "strip_all",
+  },
+];
+export const rawWhiteBalanceOptions = [
+  {
+    label: "As Shot",
+    value: "as_shot",
+  },
+  {
+    label: "Auto",
+    value: "auto",
+  },
+  {
+    label: "Custom",
+    value: "custom",
+  },
+];
+export const rawHighlightModeOptions = [
+  {
+    label: "Clip",
+    value: "clip",
+  },
+  {
+    label: "Unclip",
+    value: "unclip",
+  },
+  {
+    label: "Blend",
+    value: "blend",
+  },
+  {
+    label: "Rebuild",
+    value: "rebuild",
+  },
+];
+export const rawDemosaicAlgorithmOptions = [
+  {
+    label: "Linear (fast)",
+    value: "linear",
+  },
+  {
+    label: "VNG",
+    value: "vng",
+  },
+  {
+    label: "PPG",
+    value: "ppg",
+  },
+  {
+    label: "AHD",
+    value: "ahd",
+  },
+  {
+    label: "DCB",
+    value: "dcb",
+  },
+  {
+    label: "DHT",
+    value: "dht",
+  },
+  {
+    label: "AAHD",
+    value: "aahd",
   },
 ];
 
 export const INITIAL_VALUES = {
   exportLocation: {
     folderPath: "",
+    existingFileAction: "ask_what_to_do",
   },
   fileSettings: {
     imageFormat: "jpeg",
     quality: 70,
+    colorSpace: "srgb",
+    originalFallbackFormat: "jpeg",
+    matteColor: "#ffffff",
+    webp: {
+      lossless: false,
+    },
+    avif: {
+      speed: 6,
+      bitDepth: "ten",
+    },
+    tiff: {
+      bitDepth: "eight",
+      compression: "lzw",
+      alpha: false,
+    },
+    jpegXl: {
+      distance: null,
+      effort: 7,
+      lossless: false,
+      losslessJpegTranscode: true,
+    },
+    heic: {
+      lossless: false,
+      chroma: "420",
+      bitDepth: "eight",
+    },
+    dng: {
+      compression: "lossless",
+      embedOriginal: false,
+      preview: "full",
+    },
+  },
+  fileRenaming: {
+    enableRenaming: false,
+    renameTo: "original_name",
+    customName: "",
+    startNumber: 1,
+    fileExtensionsCase: "lower",
+    template: "{name}_{seq:04}_{date:%Y%m%d}_{camera}",
   },
   imageSizing: {
     resizeEnabled: false,
@@ -77,5 +371,24 @@ export const INITIAL_VALUES = {
     resizeIn: "pixels",
     resizeResolution: 240,
     resizeResolutionIn: "pixels_per_inch",
+    resamplingFilter: "lanczos3",
+    linearLight: false,
+  },
+  metadataPolicy: "keep_all",
+  batch: {
+    maxParallelExports: 0,
+    memoryBudgetMb: 2048,
+  },
+  rawDevelop: {
+    whiteBalance: "as_shot",
+    temperature: 5500,
+    tint: 0,
+    exposureCompensation: 0,
+    autoBrightness: false,
+    highlightMode: "clip",
+    demosaicAlgorithm: "ahd",
+    output16bit: true,
+    noiseThreshold: 0,
+    outputColorSpace: "srgb",
   },
 } as z.infer<typeof exportFormSchema>;
@@ -1,21 +1,83 @@
 import { z } from "zod";
 
+const imageFormat = z.enum([
+  "jpeg",
+  "jpeg_xl",
+  "avif",
+  "psd",
+  "tiff",
+  "png",
+  "dng",
+  "webp",
+  "heic",
+  "original",
+]);
+
 export const exportFormSchema = z.object({
   exportLocation: z.object({
     folderPath: z.string().min(1),
+    existingFileAction: z.enum([
+      "ask_what_to_do",
+      "choose_new_name",
+      "overwrite_without_warning",
+      "skip",
+    ]),
   }),
   fileSettings: z.object({
-    imageFormat: z.enum([
-      "jpeg",
-      "jpeg_xl",
-      "avif",
-      "psd",
-      "tiff",
-      "png",
-      "dng",
-      "original",
-    ]),
+    imageFormat,
+    originalFallbackFormat: imageFormat.exclude(["original"]),
+    matteColor: z.string().regex(/^#[0-9a-fA-F]{6}$/),
     quality: z.number().min(0).max(100),
+    colorSpace: z.enum([
+      "srgb",
+      "display_p3",
+      "adobe_rgb",
+      "prophoto_rgb",
+      "rec2020",
+    ]),
+    webp: z.object({
+      lossless: z.boolean(),
+    }),
+    avif: z.object({
+      speed: z.number().min(1).max(10),
+      bitDepth: z.enum(["eight", "ten"]),
+    }),
+    tiff: z.object({
+      bitDepth: z.enum(["eight", "sixteen"]),
+      compression: z.enum(["none", "lzw", "deflate"]),
+      alpha: z.boolean(),
+    }),
+    jpegXl: z.object({
+      distance: z.number().min(0).max(25).nullable(),
+      effort: z.number().min(1).max(9),
+      lossless: z.boolean(),
+      losslessJpegTranscode: z.boolean(),
+    }),
+    heic: z.object({
+      lossless: z.boolean(),
+      chroma: z.enum(["420", "422", "444"]),
+      bitDepth: z.enum(["eight", "ten"]),
+    }),
+    dng: z.object({
+      compression: z.enum(["lossless", "uncompressed"]),
+      embedOriginal: z.boolean(),
+      preview: z.enum(["none", "thumbnail", "full"]),
+    }),
+  }),
+  fileRenaming: z.object({
+    enableRenaming: z.boolean(),
+    renameTo: z.enum([
+      "original_name",
+      "filename_sequence",
+      "date_filename",
+      "custom_name",
+      "custom_name_sequence",
+      "template",
+    ]),
+    customName: z.string(),
+    startNumber: z.number().min(0).nullable(),
+    fileExtensionsCase: z.enum(["lower", "upper"]),
+    template: z.string(),
   }),
   imageSizing: z.object({
     resizeEnabled: z.boolean(),
@@ -35,6 +97,51 @@ export const exportFormSchema = z.object({
     resizeIn: z.enum(["pixels", "inches", "cms"]),
     resizeResolution: z.number().min(1),
     resizeResolutionIn: z.enum(["pixels_per_inch", "pixels_per_cm"]),
+    resamplingFilter: z.enum([
+      "nearest",
+      "bilinear",
+      "catmull_rom",
+      "mitchell",
+      "lanczos3",
+      "supersampling",
+    ]),
+    linearLight: z.boolean(),
+  }),
+  metadataPolicy: z.enum([
+    "keep_all",
+    "remove_location",
+    "copyright_and_contact_only",
+    "strip_all",
+  ]),
+  batch: z.object({
+    maxParallelExports: z.number().int().min(0),
+    memoryBudgetMb: z.number().int().min(0),
+  }),
+  rawDevelop: z.object({
+    whiteBalance: z.enum(["as_shot", "auto", "custom"]),
+    temperature: z.number().int().min(2000).max(25000),
+    tint: z.number().min(-150).max(150),
+    exposureCompensation: z.number().min(-2).max(3),
+    autoBrightness: z.boolean(),
+    highlightMode: z.enum(["clip", "unclip", "blend", "rebuild"]),
+    demosaicAlgorithm: z.enum([
+      "linear",
+      "vng",
+      "ppg",
+      "ahd",
+      "dcb",
+      "dht",
+      "aahd",
+    ]),
+    output16bit: z.boolean(),
+    noiseThreshold: z.number().min(0).max(2000),
+    outputColorSpace: z.enum([
+      "srgb",
+      "display_p3",
+      "adobe_rgb",
+      "prophoto_rgb",
+      "rec2020",
+    ]),
   }),
 });
 